@@ -0,0 +1,61 @@
+//! Compares the single and sharded counters of [`RateLimit`] under
+//! contention: `cargo bench --features rate_limit --bench rate_limit`.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use jenga::{rate_limit::RateLimit, Service};
+
+const REQUESTS_PER_THREAD: usize = 200_000;
+
+struct Noop;
+
+impl Service<()> for Noop {
+    type Response = ();
+    type Error = Infallible;
+
+    async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+        Ok(())
+    }
+}
+
+fn run(service: Arc<RateLimit<1024, (), Noop>>, threads: usize) -> Duration {
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|_| {
+            let service = service.clone();
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async {
+                    for _ in 0..REQUESTS_PER_THREAD {
+                        let _ = service.request(()).await;
+                    }
+                });
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let total = (threads * REQUESTS_PER_THREAD) as f64;
+
+    let single = run(Arc::new(RateLimit::new(Noop)), threads);
+    let sharded = run(Arc::new(RateLimit::sharded(Noop, threads)), threads);
+
+    println!("{threads} threads, {REQUESTS_PER_THREAD} requests each");
+    println!("single:  {:>10.0} req/s", total / single.as_secs_f64());
+    println!("sharded: {:>10.0} req/s", total / sharded.as_secs_f64());
+}
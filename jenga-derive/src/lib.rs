@@ -0,0 +1,290 @@
+//! Derive macros for [jenga](https://docs.rs/jenga), re-exported by its
+//! `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parenthesized, parse_macro_input, punctuated::Punctuated, Data, DeriveInput, Error, Fields,
+    Ident, Token, Type,
+};
+
+/// Turns an enum into a flat error for whole middleware stacks.
+///
+/// Variants are tagged with the failure they stand for:
+/// - `#[jenga(timeout)]`: unit variant for timed out requests,
+/// - `#[jenga(rate_limited)]`: unit variant for rate limited requests,
+/// - `#[jenga(closed)]`: unit variant for closed channels and workers,
+/// - `#[jenga(duplicate)]`: unit variant for duplicate requests,
+/// - `#[jenga(service)]`: single-field variant for an error of the
+///   innermost service (or a restart generator, or a pool).
+///
+/// `#[jenga(from(StackError, ...))]` on the enum generates a `From` impl
+/// for each given nested error type, so `?` flattens it. It doesn't compile
+/// if the stack can fail in a way the enum has no variant for.
+#[proc_macro_derive(FlatError, attributes(jenga))]
+pub fn derive_flat_error(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input,
+            "FlatError can only be derived for enums",
+        ));
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut impls = Vec::new();
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("jenga"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("from") {
+                return Err(meta.error("expected `from(...)`"));
+            }
+            let content;
+            parenthesized!(content in meta.input);
+            for ty in Punctuated::<Type, Token![,]>::parse_terminated(&content)? {
+                impls.push(quote! {
+                    impl #impl_generics ::core::convert::From<#ty> for #name #ty_generics #where_clause {
+                        fn from(error: #ty) -> Self {
+                            ::jenga::error::IntoFlat::into_flat(error)
+                        }
+                    }
+                });
+            }
+            Ok(())
+        })?;
+    }
+
+    for variant in &data.variants {
+        let ident = &variant.ident;
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("jenga"))
+        {
+            attr.parse_nested_meta(|meta| {
+                let unit = |hook: TokenStream2, method: TokenStream2| {
+                    if !matches!(variant.fields, Fields::Unit) {
+                        return Err(meta.error("expected a unit variant"));
+                    }
+                    Ok(quote! {
+                        impl #impl_generics ::jenga::error::#hook for #name #ty_generics #where_clause {
+                            fn #method() -> Self {
+                                #name::#ident
+                            }
+                        }
+                    })
+                };
+
+                if meta.path.is_ident("timeout") {
+                    impls.push(unit(quote!(FromTimeout), quote!(timed_out))?);
+                } else if meta.path.is_ident("rate_limited") {
+                    impls.push(unit(quote!(FromRateLimited), quote!(rate_limited))?);
+                } else if meta.path.is_ident("closed") {
+                    impls.push(unit(quote!(FromClosed), quote!(closed))?);
+                } else if meta.path.is_ident("duplicate") {
+                    impls.push(unit(quote!(FromDuplicate), quote!(duplicate))?);
+                } else if meta.path.is_ident("service") {
+                    let field = match &variant.fields {
+                        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0],
+                        _ => return Err(meta.error("expected a variant with a single unnamed field")),
+                    };
+                    let ty = &field.ty;
+                    impls.push(quote! {
+                        impl #impl_generics ::jenga::error::IntoFlat<#name #ty_generics> for #ty #where_clause {
+                            fn into_flat(self) -> #name #ty_generics {
+                                #name::#ident(self)
+                            }
+                        }
+                    });
+                } else {
+                    return Err(meta.error(
+                        "expected one of `timeout`, `rate_limited`, `closed`, `duplicate` or `service`",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(quote!(#(#impls)*))
+}
+
+/// Turns an enum of requests into a service dispatching each variant to its
+/// own inner service.
+///
+/// For `enum ApiRequest { GetUser(UserId), ListOrders(OrderQuery), Ping }`
+/// (`Request` is stripped from the name, if there), it generates:
+/// - `ApiService<S0, S1, S2>`, with a public field per variant (`get_user`,
+///   `list_orders`, `ping`) holding its service, taking the variant's field
+///   (or `()` for unit variants) as request,
+/// - `ApiResponse<T0, T1, T2>`, with the response of each service,
+/// - `ApiError<E0, E1, E2>`, with the error of each service, flattened and
+///   classified like the error it holds.
+///
+/// Variants have at most one unnamed field, and the enum can't be generic.
+#[proc_macro_derive(Dispatch)]
+pub fn derive_dispatch(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_dispatch(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// `GetUser` -> `get_user`
+fn snake_case(ident: &Ident) -> Ident {
+    let mut snake = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    format_ident!("{}", snake)
+}
+
+fn expand_dispatch(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input,
+            "Dispatch can only be derived for enums",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "Dispatch can't be derived for generic enums",
+        ));
+    }
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let base = name.to_string();
+    let base = base.strip_suffix("Request").unwrap_or(&base);
+    let service = format_ident!("{}Service", base);
+    let response = format_ident!("{}Response", base);
+    let error = format_ident!("{}Error", base);
+
+    let variants: Vec<_> = data.variants.iter().map(|v| &v.ident).collect();
+    let fields: Vec<_> = variants.iter().map(|v| snake_case(v)).collect();
+    let services: Vec<_> = (0..variants.len())
+        .map(|i| format_ident!("S{}", i))
+        .collect();
+    let responses: Vec<_> = (0..variants.len())
+        .map(|i| format_ident!("T{}", i))
+        .collect();
+    let errors: Vec<_> = (0..variants.len())
+        .map(|i| format_ident!("E{}", i))
+        .collect();
+
+    let mut requests = Vec::new();
+    let mut arms = Vec::new();
+    for (variant, field) in data.variants.iter().zip(&fields) {
+        let ident = &variant.ident;
+        let (request, pattern, msg) = match &variant.fields {
+            Fields::Unit => (quote!(()), quote!(#name::#ident), quote!(())),
+            Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+                let ty = &unnamed.unnamed[0].ty;
+                (quote!(#ty), quote!(#name::#ident(msg)), quote!(msg))
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    variant,
+                    "expected a unit variant or a variant with a single unnamed field",
+                ))
+            }
+        };
+        requests.push(request);
+        arms.push(quote! {
+            #pattern => ::jenga::Service::request(&self.#field, #msg)
+                .await
+                .map(#response::#ident)
+                .map_err(#error::#ident)
+        });
+    }
+
+    let doc_service = format!("Dispatches each [`{name}`] variant to its own service.");
+    let doc_response = format!("The response to a [`{name}`], from the service of its variant.");
+    let doc_error = format!("The error of the service a [`{name}`] was dispatched to.");
+
+    Ok(quote! {
+        #[doc = #doc_service]
+        #[derive(Debug, Clone)]
+        #vis struct #service<#(#services),*> {
+            #(pub #fields: #services,)*
+        }
+
+        #[doc = #doc_response]
+        #[derive(Debug, Clone, PartialEq)]
+        #vis enum #response<#(#responses),*> {
+            #(#variants(#responses),)*
+        }
+
+        #[doc = #doc_error]
+        #[derive(Debug, Clone, PartialEq)]
+        #vis enum #error<#(#errors),*> {
+            #(#variants(#errors),)*
+        }
+
+        impl<#(#errors: ::core::fmt::Display),*> ::core::fmt::Display for #error<#(#errors),*> {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                match self {
+                    #(#error::#variants(e) => ::core::fmt::Display::fmt(e, f),)*
+                }
+            }
+        }
+
+        impl<#(#errors: ::core::error::Error),*> ::core::error::Error for #error<#(#errors),*> {
+            fn source(&self) -> ::core::option::Option<&(dyn ::core::error::Error + 'static)> {
+                match self {
+                    #(#error::#variants(e) => ::core::error::Error::source(e),)*
+                }
+            }
+        }
+
+        impl<F, #(#errors: ::jenga::error::IntoFlat<F>),*> ::jenga::error::IntoFlat<F> for #error<#(#errors),*> {
+            fn into_flat(self) -> F {
+                match self {
+                    #(#error::#variants(e) => ::jenga::error::IntoFlat::into_flat(e),)*
+                }
+            }
+        }
+
+        impl<#(#errors: ::jenga::classify::Classify),*> ::jenga::classify::Classify for #error<#(#errors),*> {
+            fn classify(&self) -> ::jenga::classify::Class {
+                match self {
+                    #(#error::#variants(e) => ::jenga::classify::Classify::classify(e),)*
+                }
+            }
+        }
+
+        impl<#(#services),*> ::jenga::Service<#name> for #service<#(#services),*>
+        where
+            #(#services: ::jenga::Service<#requests>,)*
+        {
+            type Response = #response<#(#services::Response),*>;
+            type Error = #error<#(#services::Error),*>;
+
+            async fn request(&self, msg: #name) -> ::core::result::Result<Self::Response, Self::Error> {
+                match msg {
+                    #(#arms,)*
+                }
+            }
+        }
+    })
+}
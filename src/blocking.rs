@@ -0,0 +1,160 @@
+//! Blocking facade to call a service from synchronous code.
+//!
+//! [`BlockingService`] drives the requests of the wrapped service to completion
+//! on a Tokio runtime [`Handle`]. Like [`Handle::block_on`], it must not be
+//! called from within an asynchronous context, and on a current-thread runtime
+//! the timers are only driven while the runtime itself is being blocked on.
+
+use core::error::Error;
+use std::{marker::PhantomData, time::Duration};
+
+use thiserror::Error;
+use tokio::{runtime::Handle, time::timeout};
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromTimeout, IntoFlat},
+    Service,
+};
+
+/// Synchronous wrapper around a service, running each request
+/// on the given runtime handle.
+pub struct BlockingService<R, T: Service<R>> {
+    inner: T,
+    handle: Handle,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum BlockingError<E: Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("request timed out")]
+    TimeoutError,
+}
+
+impl<E: Error + IntoFlat<F>, F: FromTimeout> IntoFlat<F> for BlockingError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            BlockingError::ServiceError(e) => e.into_flat(),
+            BlockingError::TimeoutError => F::timed_out(),
+        }
+    }
+}
+
+impl<E: Error + Classify> Classify for BlockingError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            BlockingError::ServiceError(e) => e.classify(),
+            BlockingError::TimeoutError => Class::Transient,
+        }
+    }
+}
+
+impl<R, T: Service<R>> BlockingService<R, T> {
+    pub fn new(service: T, handle: Handle) -> Self {
+        BlockingService {
+            inner: service,
+            handle,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Blocks the current thread until the request finishes.
+    pub fn request(&self, msg: R) -> Result<T::Response, BlockingError<T::Error>> {
+        self.handle
+            .block_on(self.inner.request(msg))
+            .map_err(BlockingError::ServiceError)
+    }
+
+    /// Blocks the current thread until the request finishes, or until
+    /// `timeout_duration` has passed.
+    ///
+    /// The runtime behind the handle must have its time driver enabled.
+    pub fn request_timeout(
+        &self,
+        msg: R,
+        timeout_duration: Duration,
+    ) -> Result<T::Response, BlockingError<T::Error>> {
+        // The timer has to be created from within the runtime context
+        let res = self
+            .handle
+            .block_on(async { timeout(timeout_duration, self.inner.request(msg)).await });
+
+        match res {
+            Ok(res) => res.map_err(BlockingError::ServiceError),
+            Err(_) => Err(BlockingError::TimeoutError),
+        }
+    }
+
+    pub fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for BlockingService<R, T> {
+    fn name(&self) -> &'static str {
+        "blocking"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for BlockingService<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{runtime::Builder, time::sleep};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestBlockingService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    impl Service<u64> for TestBlockingService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(msg)).await;
+            if msg == 14 {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg * 2)
+            }
+        }
+    }
+
+    #[test]
+    fn blocking_test() {
+        let runtime = Builder::new_multi_thread().enable_time().build().unwrap();
+        let service = BlockingService::new(TestBlockingService {}, runtime.handle().clone());
+
+        assert_eq!(service.request(10), Ok(20));
+        assert_eq!(
+            service.request(14),
+            Err(BlockingError::ServiceError(FakeError::Error))
+        );
+        assert_eq!(
+            service.request_timeout(10, Duration::from_millis(15)),
+            Ok(20)
+        );
+        assert_eq!(
+            service.request_timeout(20, Duration::from_millis(15)),
+            Err(BlockingError::TimeoutError)
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Services with their type erased, to store stacks of different types in
+//! the same place.
+//!
+//! Every middleware changes the type of the stack, so two stacks built
+//! differently can't go in the same `Vec` or struct field. [`BoxService`]
+//! hides the stack behind a trait object, only keeping the types of its
+//! requests, responses and errors, at the cost of an allocation per request
+//! for the future. [`BoxCloneService`] can be cloned as well, its clones
+//! sharing the same stack.
+//!
+//! Like the stacks they hold, boxed services and their futures are not
+//! `Send`.
+
+use core::error::Error;
+use std::{future::Future, pin::Pin, rc::Rc};
+
+use crate::{
+    describe::{Describe, FindLayer},
+    Service,
+};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// Object-safe version of [`Service`], boxing the futures.
+trait DynService<R, Resp, E> {
+    fn request_boxed<'a>(&'a self, msg: R) -> BoxFuture<'a, Result<Resp, E>>
+    where
+        R: 'a;
+}
+
+impl<R, S: Service<R>> DynService<R, S::Response, S::Error> for S {
+    fn request_boxed<'a>(&'a self, msg: R) -> BoxFuture<'a, Result<S::Response, S::Error>>
+    where
+        R: 'a,
+    {
+        Box::pin(self.request(msg))
+    }
+}
+
+/// A service of any type, see the [module docs](self).
+pub struct BoxService<R, Resp, E> {
+    inner: Box<dyn DynService<R, Resp, E>>,
+}
+
+impl<R, Resp, E: Error + 'static> BoxService<R, Resp, E> {
+    pub fn new<S>(service: S) -> Self
+    where
+        S: Service<R, Response = Resp, Error = E> + 'static,
+    {
+        BoxService {
+            inner: Box::new(service),
+        }
+    }
+}
+
+impl<R, Resp, E: Error + 'static> Service<R> for BoxService<R, Resp, E> {
+    type Response = Resp;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.request_boxed(msg).await
+    }
+}
+
+/// The layers inside are hidden.
+impl<R, Resp, E> Describe for BoxService<R, Resp, E> {
+    fn name(&self) -> &'static str {
+        "boxed"
+    }
+}
+
+impl<R: 'static, Resp: 'static, E: 'static> FindLayer for BoxService<R, Resp, E> {}
+
+/// A [`BoxService`] that can be cloned, the clones sharing the same stack.
+pub struct BoxCloneService<R, Resp, E> {
+    inner: Rc<dyn DynService<R, Resp, E>>,
+}
+
+impl<R, Resp, E: Error + 'static> BoxCloneService<R, Resp, E> {
+    pub fn new<S>(service: S) -> Self
+    where
+        S: Service<R, Response = Resp, Error = E> + 'static,
+    {
+        BoxCloneService {
+            inner: Rc::new(service),
+        }
+    }
+}
+
+impl<R, Resp, E> Clone for BoxCloneService<R, Resp, E> {
+    fn clone(&self) -> Self {
+        BoxCloneService {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<R, Resp, E: Error + 'static> Service<R> for BoxCloneService<R, Resp, E> {
+    type Response = Resp;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.request_boxed(msg).await
+    }
+}
+
+/// The layers inside are hidden.
+impl<R, Resp, E> Describe for BoxCloneService<R, Resp, E> {
+    fn name(&self) -> &'static str {
+        "boxed"
+    }
+}
+
+impl<R: 'static, Resp: 'static, E: 'static> FindLayer for BoxCloneService<R, Resp, E> {}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+    use crate::combinators::ServiceExt;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("odd request")]
+        Odd,
+    }
+
+    /// Fails on odd requests
+    #[derive(Debug)]
+    pub struct TestEvenService {}
+
+    impl Service<u64> for TestEvenService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            if msg.is_multiple_of(2) {
+                Ok(msg)
+            } else {
+                Err(FakeError::Odd)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn box_service_test() {
+        // Stacks of different types in the same Vec
+        let services: Vec<BoxService<u64, u64, FakeError>> = vec![
+            BoxService::new(TestEvenService {}),
+            BoxService::new(TestEvenService {}.map_response(|resp| resp * 10)),
+            BoxService::new(TestEvenService {}.recover(|_| Ok(0))),
+        ];
+
+        let mut responses = Vec::new();
+        for service in &services {
+            responses.push(service.request(2).await);
+            responses.push(service.request(3).await);
+        }
+        assert_eq!(
+            responses,
+            [
+                Ok(2),
+                Err(FakeError::Odd),
+                Ok(20),
+                Err(FakeError::Odd),
+                Ok(2),
+                Ok(0)
+            ]
+        );
+        assert_eq!(services[1].describe().layers[0].name, "boxed");
+    }
+
+    #[tokio::test]
+    async fn box_clone_service_test() {
+        let service = BoxCloneService::new(TestEvenService {}.map_response(|resp| resp + 1));
+        let clone = service.clone();
+        assert_eq!(service.request(4).await, Ok(5));
+        assert_eq!(clone.request(5).await, Err(FakeError::Odd));
+    }
+}
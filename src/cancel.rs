@@ -0,0 +1,312 @@
+//! Cancellation of requests whose caller went away.
+//!
+//! Dropping a request's future already stops it everywhere in the stack, but
+//! the caller doesn't always hold the future, e.g. a request handed over to
+//! a channel worker, or a client disconnecting from a server. A
+//! [`CancelToken`] in the request's [metadata](crate::meta::Meta::cancel)
+//! stands for the caller instead, and [`Cancellable`] drops the rest of the
+//! stack as soon as it's cancelled:
+//! - `Retry` makes no more attempts, even without a [`Cancellable`] above it,
+//! - `Timeout` stops waiting on the inner service,
+//! - the permits of `RateLimit` and `ConcurrencyLimit` are released right
+//!   away, like for any dropped request.
+
+use core::error::Error;
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    task::{Poll, Waker},
+};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromTimeout, IntoFlat},
+    meta, Middleware, Service,
+};
+
+/// Cancels the requests it was given to. Its clones cancel the same
+/// requests.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    wakers: Mutex<Wakers>,
+}
+
+#[derive(Debug, Default)]
+struct Wakers {
+    next_id: u64,
+    /// [`CancelToken::cancelled`] futures not dropped yet.
+    waiting: HashMap<u64, Waker>,
+}
+
+/// Tokens are equal if they're clones of each other.
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::Release);
+        for (_, waker) in self
+            .inner
+            .wakers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .waiting
+            .drain()
+        {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let id = {
+            let mut wakers = self.inner.wakers.lock().unwrap();
+            wakers.next_id += 1;
+            wakers.next_id
+        };
+        let _waiting = Waiting {
+            inner: &self.inner,
+            id,
+        };
+        poll_fn(|cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+
+            let mut wakers = self.inner.wakers.lock().unwrap();
+            // Checked again, in case it was cancelled before the lock was taken
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            wakers.waiting.insert(id, cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Unregisters a [`CancelToken::cancelled`] future that was dropped, for a
+/// token shared by many requests not to keep all their wakers.
+struct Waiting<'a> {
+    inner: &'a Inner,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.inner
+            .wakers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .waiting
+            .remove(&self.id);
+    }
+}
+
+/// Whether the current request was cancelled.
+#[allow(dead_code)]
+pub(crate) fn is_cancelled() -> bool {
+    meta::current()
+        .and_then(|meta| meta.cancel.clone())
+        .is_some_and(|token| token.is_cancelled())
+}
+
+/// Drops the requests of the stack below once their token is cancelled, see
+/// the [module docs](self).
+pub struct Cancellable<R, T: Service<R>> {
+    inner: T,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CancelError<E: Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("request cancelled")]
+    Cancelled,
+}
+
+impl<E: Error> CancelError<E> {
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, CancelError::Cancelled)
+    }
+}
+
+/// Like a shed request, the caller stopped waiting for it.
+impl<E: Error + IntoFlat<F>, F: FromTimeout> IntoFlat<F> for CancelError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            CancelError::ServiceError(e) => e.into_flat(),
+            CancelError::Cancelled => F::timed_out(),
+        }
+    }
+}
+
+impl<E: Error + Classify> Classify for CancelError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            CancelError::ServiceError(e) => e.classify(),
+            CancelError::Cancelled => Class::Cancelled,
+        }
+    }
+}
+
+impl<R, T: Service<R>> Cancellable<R, T> {
+    pub fn new(service: T) -> Self {
+        Cancellable {
+            inner: service,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for Cancellable<R, T> {
+    type Response = T::Response;
+    type Error = CancelError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let Some(token) = meta::current().and_then(|meta| meta.cancel.clone()) else {
+            return self
+                .inner
+                .request(msg)
+                .await
+                .map_err(CancelError::ServiceError);
+        };
+        if token.is_cancelled() {
+            return Err(CancelError::Cancelled);
+        }
+
+        let mut future = pin!(self.inner.request(msg));
+        let mut cancelled = pin!(token.cancelled());
+        poll_fn(|cx| {
+            if let Poll::Ready(res) = future.as_mut().poll(cx) {
+                return Poll::Ready(res.map_err(CancelError::ServiceError));
+            }
+            cancelled
+                .as_mut()
+                .poll(cx)
+                .map(|()| Err(CancelError::Cancelled))
+        })
+        .await
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for Cancellable<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for Cancellable<R, T> {
+    fn name(&self) -> &'static str {
+        "cancellable"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Cancellable<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::meta::{Meta, MetaService, WithMeta};
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    #[derive(Debug)]
+    pub struct TestSlowService {}
+
+    impl Service<u64> for TestSlowService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_secs(1)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancellable_test() {
+        let service = MetaService::new(Cancellable::new(TestSlowService {}));
+        let token = CancelToken::new();
+        let request = || WithMeta::new(1, Meta::default().with_cancel_token(token.clone()));
+
+        let cancel = async {
+            sleep(Duration::from_millis(10)).await;
+            token.cancel();
+        };
+        let (res, ()) = tokio::join!(service.request(request()), cancel);
+        assert_eq!(res, Err(CancelError::Cancelled));
+
+        // Not even started once cancelled
+        assert_eq!(
+            service.request(request()).await,
+            Err(CancelError::Cancelled)
+        );
+
+        // Requests without a token can't be cancelled
+        assert_eq!(
+            service.request(WithMeta::new(2, Meta::default())).await,
+            Ok(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_token_wakers_test() {
+        let service = MetaService::new(Cancellable::new(TestSlowService {}));
+        let token = CancelToken::new();
+        let request = |msg| WithMeta::new(msg, Meta::default().with_cancel_token(token.clone()));
+        let waiting = || token.inner.wakers.lock().unwrap().waiting.len();
+
+        // A token shared by every request, never cancelled
+        for i in 0..100 {
+            assert_eq!(service.request(request(i)).await, Ok(i));
+        }
+        let (a, b) = tokio::join!(service.request(request(1)), async {
+            sleep(Duration::from_millis(10)).await;
+            waiting()
+        });
+        assert_eq!((a, b), (Ok(1), 1));
+        assert_eq!(waiting(), 0);
+    }
+}
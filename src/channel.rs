@@ -0,0 +1,365 @@
+//! A service that forwards its requests over a channel.
+//!
+//! [`ChannelService`] doesn't process anything itself. Each request is sent,
+//! along with a oneshot [`Sender`](oneshot::Sender) for the result, to a
+//! [`ChannelWorker`] that owns the actual service and can live in another
+//! task, or another thread entirely.
+//!
+//! This is also how to share a service that needs exclusive access to itself
+//! for every call, like a framed connection: implement [`ServiceMut`] for it,
+//! and the worker calls it one request at a time through [`ChannelWorker::run_mut`].
+//! Any number of cheap [`ChannelService`] clones then buffer their requests
+//! for it, and `jenga::spawn` can give the worker a thread of its own with
+//! [`Exclusive`].
+
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::Arc,
+};
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer},
+    error::{FromClosed, IntoFlat},
+    load::{Load, LoadMetric},
+    meta::{self, Meta},
+    Service,
+};
+
+/// A request, the [metadata](crate::meta) it was sent with, and the channel
+/// its result must be sent back on.
+pub type Envelope<R, Resp, E> = (R, Option<Arc<Meta>>, oneshot::Sender<Result<Resp, E>>);
+
+/// Client side of the channel. Cloning it is cheap, and all the
+/// clones send their requests to the same worker.
+pub struct ChannelService<R, Resp, E> {
+    sender: mpsc::Sender<Envelope<R, Resp, E>>,
+}
+
+/// Worker side of the channel, that calls the actual service.
+pub struct ChannelWorker<R, Resp, E> {
+    receiver: mpsc::Receiver<Envelope<R, Resp, E>>,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ChannelError<E: Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("channel worker is closed")]
+    Closed,
+}
+
+impl<E: Error + IntoFlat<F>, F: FromClosed> IntoFlat<F> for ChannelError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            ChannelError::ServiceError(e) => e.into_flat(),
+            ChannelError::Closed => F::closed(),
+        }
+    }
+}
+
+impl<E: Error + Classify> Classify for ChannelError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            ChannelError::ServiceError(e) => e.classify(),
+            ChannelError::Closed => Class::Permanent,
+        }
+    }
+}
+
+impl<R, Resp, E> ChannelService<R, Resp, E> {
+    /// Creates a channel-backed service and its worker. `buffer` is how many
+    /// requests can be waiting for the worker before callers have to wait,
+    /// at least one.
+    pub fn new(buffer: usize) -> (Self, ChannelWorker<R, Resp, E>) {
+        let (sender, receiver) = mpsc::channel(buffer.max(1));
+        (ChannelService { sender }, ChannelWorker { receiver })
+    }
+}
+
+impl<R, Resp, E: Error> ChannelService<R, Resp, E> {
+    /// Waits until there's room in the channel for another request. The room
+    /// isn't kept, a request sent later may still have to wait.
+    pub async fn ready(&self) -> Result<(), ChannelError<E>> {
+        match self.sender.reserve().await {
+            Ok(_permit) => Ok(()),
+            Err(_) => Err(ChannelError::Closed),
+        }
+    }
+}
+
+impl<R, Resp, E> Clone for ChannelService<R, Resp, E> {
+    fn clone(&self) -> Self {
+        ChannelService {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<R, Resp, E: Error + 'static> Service<R> for ChannelService<R, Resp, E> {
+    type Response = Resp;
+    type Error = ChannelError<E>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.sender
+            .send((msg, meta::current(), sender))
+            .await
+            .map_err(|_| ChannelError::Closed)?;
+
+        match receiver.await {
+            Ok(res) => res.map_err(ChannelError::ServiceError),
+            Err(_) => Err(ChannelError::Closed),
+        }
+    }
+}
+
+/// A service needing exclusive access to itself to process a request.
+#[allow(async_fn_in_trait)]
+pub trait ServiceMut<Request> {
+    type Response;
+    type Error: core::error::Error + 'static;
+    async fn request(&mut self, msg: Request) -> Result<Self::Response, Self::Error>;
+}
+
+/// Makes a [`Service`] out of a [`ServiceMut`], processing one request at a
+/// time while the others wait for their turn.
+pub struct Exclusive<S> {
+    inner: Mutex<S>,
+}
+
+impl<S> Exclusive<S> {
+    pub fn new(service: S) -> Self {
+        Exclusive {
+            inner: Mutex::new(service),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+impl<R, S: ServiceMut<R>> Service<R> for Exclusive<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.lock().await.request(msg).await
+    }
+}
+
+impl<S> Describe for Exclusive<S> {
+    fn name(&self) -> &'static str {
+        "exclusive"
+    }
+}
+
+impl<S: 'static> FindLayer for Exclusive<S> {}
+
+impl<R, Resp, E> ChannelWorker<R, Resp, E> {
+    /// The queue of requests, for workers processing more than one channel.
+    #[cfg(feature = "fan_in")]
+    pub(crate) fn into_receiver(self) -> mpsc::Receiver<Envelope<R, Resp, E>> {
+        self.receiver
+    }
+
+    /// Processes incoming requests one at a time with `service`, until
+    /// every [`ChannelService`] of this channel has been dropped.
+    pub async fn run<S: Service<R, Response = Resp, Error = E>>(mut self, service: S) {
+        while let Some((msg, meta, sender)) = self.receiver.recv().await {
+            // The caller may have given up on the response, which is fine
+            let _ = sender.send(meta::scope(meta, service.request(msg)).await);
+        }
+    }
+
+    /// Like [`ChannelWorker::run`], with a service needing exclusive access
+    /// to itself. Requests are processed one at a time either way.
+    pub async fn run_mut<S: ServiceMut<R, Response = Resp, Error = E>>(self, service: S) {
+        self.run(Exclusive::new(service)).await
+    }
+
+    /// Like [`ChannelWorker::run`], but also stops accepting requests once
+    /// `signal` resolves. Requests already queued at that point are still
+    /// processed before returning.
+    pub async fn run_until<S, F>(mut self, service: S, signal: F)
+    where
+        S: Service<R, Response = Resp, Error = E>,
+        F: Future<Output = ()>,
+    {
+        let mut signal = pin!(signal);
+        let mut closed = false;
+
+        loop {
+            let next = poll_fn(|cx| {
+                if !closed && signal.as_mut().poll(cx).is_ready() {
+                    closed = true;
+                    self.receiver.close();
+                }
+                self.receiver.poll_recv(cx)
+            })
+            .await;
+
+            match next {
+                Some((msg, meta, sender)) => {
+                    let _ = sender.send(meta::scope(meta, service.request(msg)).await);
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+/// Only the requests waiting in the channel are known, not what the worker
+/// is processing.
+impl<R, Resp, E> Load for ChannelService<R, Resp, E> {
+    fn load(&self) -> LoadMetric {
+        LoadMetric {
+            in_flight: 0,
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            capacity: None,
+        }
+    }
+
+    /// Ready while the worker is running and the channel isn't full.
+    fn is_ready(&self) -> bool {
+        !self.sender.is_closed() && self.sender.capacity() > 0
+    }
+}
+
+/// The worker's service isn't reachable from here, so this is a leaf.
+impl<R, Resp, E> Describe for ChannelService<R, Resp, E> {
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("closed", self.sender.is_closed().to_string())])
+    }
+}
+
+impl<R: 'static, Resp: 'static, E: 'static> FindLayer for ChannelService<R, Resp, E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::{MetaService, WithMeta};
+
+    #[derive(Debug)]
+    pub struct TestChannelService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    impl Service<u64> for TestChannelService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            if msg == 14 {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg * 2)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_test() {
+        let (service, worker) = ChannelService::new(4);
+        let worker = tokio::spawn(worker.run(TestChannelService {}));
+
+        assert_eq!(service.request(10).await, Ok(20));
+        assert_eq!(service.clone().request(20).await, Ok(40));
+        assert_eq!(
+            service.request(14).await,
+            Err(ChannelError::ServiceError(FakeError::Error))
+        );
+
+        // The worker stops once all the clients are gone
+        drop(service);
+        worker.await.unwrap();
+
+        let (service, worker) = ChannelService::<u64, u64, FakeError>::new(4);
+        drop(worker);
+        assert_eq!(service.request(10).await, Err(ChannelError::Closed));
+
+        // A zero buffer still holds one request
+        let (service, worker) = ChannelService::new(0);
+        let worker = tokio::spawn(worker.run(TestChannelService {}));
+        assert_eq!(service.request(10).await, Ok(20));
+        drop(service);
+        worker.await.unwrap();
+    }
+
+    /// Numbers its responses, like frames on a connection
+    #[derive(Debug, Default)]
+    pub struct TestFramedService {
+        next_frame: u64,
+    }
+
+    impl ServiceMut<u64> for TestFramedService {
+        type Response = (u64, u64);
+        type Error = FakeError;
+
+        async fn request(&mut self, msg: u64) -> Result<Self::Response, Self::Error> {
+            self.next_frame += 1;
+            tokio::task::yield_now().await;
+            Ok((self.next_frame, msg))
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_mut_test() {
+        let (service, worker) = ChannelService::new(4);
+        tokio::spawn(worker.run_mut(TestFramedService::default()));
+
+        let clone = service.clone();
+        let (a, b) = tokio::join!(service.request(10), clone.request(20));
+        let mut frames = [a.unwrap(), b.unwrap()].map(|(frame, _)| frame);
+        frames.sort();
+        assert_eq!(frames, [1, 2]);
+
+        // Usable without a channel too
+        let exclusive = Exclusive::new(TestFramedService::default());
+        let (a, b) = tokio::join!(exclusive.request(1), exclusive.request(2));
+        assert_eq!((a, b), (Ok((1, 1)), Ok((2, 2))));
+        assert_eq!(exclusive.into_inner().next_frame, 2);
+    }
+
+    #[derive(Debug)]
+    pub struct TestTenantService {}
+
+    impl Service<()> for TestTenantService {
+        type Response = Option<Arc<str>>;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(meta::current().and_then(|meta| meta.tenant.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_meta_test() {
+        let (service, worker) = ChannelService::new(4);
+        tokio::spawn(worker.run(TestTenantService {}));
+
+        // The worker runs in another task, the metadata went along with the request
+        let service = MetaService::new(service);
+        let request = WithMeta::new((), Meta::default().with_tenant("acme"));
+        assert_eq!(
+            service.request(request).await.unwrap().as_deref(),
+            Some("acme")
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Classification of errors, shared by the middlewares that react to them.
+//!
+//! Implement [`Classify`] once for an error type, and every middleware that
+//! can tell failures apart uses it: [`Retry::classified`](crate::retry::Retry)
+//! only retries what can succeed on another attempt, and
+//! [`Restart::classified`](crate::restart::Restart) only replaces its service
+//! when the service itself is at fault.
+
+/// Kind of failure an error stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    /// Might succeed if tried again, e.g. a timeout or a dropped connection.
+    Transient,
+    /// Will fail again, e.g. an invalid request or a closed service.
+    Permanent,
+    /// Rejected because of load, trying again right away makes it worse.
+    Throttled,
+    /// Abandoned by the caller.
+    Cancelled,
+}
+
+impl Class {
+    /// Whether another attempt can succeed.
+    #[inline]
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Class::Transient | Class::Throttled)
+    }
+
+    /// Whether the service is at fault, rather than the request or the load.
+    #[inline]
+    pub fn is_service_failure(self) -> bool {
+        matches!(self, Class::Transient | Class::Permanent)
+    }
+
+    /// Lowercase name of the class, e.g. for logs and metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Class::Transient => "transient",
+            Class::Permanent => "permanent",
+            Class::Throttled => "throttled",
+            Class::Cancelled => "cancelled",
+        }
+    }
+}
+
+pub trait Classify {
+    fn classify(&self) -> Class;
+}
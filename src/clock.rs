@@ -0,0 +1,240 @@
+//! Source of time for the time-dependent middlewares.
+//!
+//! By default, `Timeout` and `Retry` use the system timers directly. They can
+//! be given any other [`Clock`] instead, like a [`ManualClock`] that only moves
+//! forward when told to, which makes their behavior fully deterministic in tests.
+//!
+//! The deadlines of requests are always measured with the system clock, so
+//! that every layer agrees on the time they have left.
+
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Poll, Waker},
+    time::{Duration, Instant},
+};
+
+pub trait Clock: Send + Sync {
+    /// Current time according to this clock.
+    fn now(&self) -> Instant;
+
+    /// Resolves once `duration` has passed according to this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+/// The system clock, with the timers of the async runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(crate::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves forward when [`ManualClock::advance`] is called.
+#[derive(Debug)]
+pub struct ManualClock {
+    /// `None` on wasm, where there's no clock to start from.
+    start: Option<Instant>,
+    state: Mutex<ManualState>,
+}
+
+#[derive(Debug, Default)]
+struct ManualState {
+    elapsed: Duration,
+    next_id: u64,
+    /// Sleeps not due nor dropped yet.
+    sleepers: HashMap<u64, Waker>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: (!cfg!(target_arch = "wasm32")).then(Instant::now),
+            state: Mutex::new(ManualState::default()),
+        }
+    }
+
+    /// How much time passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /// Moves the clock forward, waking up the sleepers that are due.
+    pub fn advance(&self, duration: Duration) {
+        let sleepers = {
+            let mut state = self.state.lock().unwrap();
+            state.elapsed += duration;
+            std::mem::take(&mut state.sleepers)
+        };
+
+        // Sleepers that aren't due yet register themselves again when polled
+        for (_, sleeper) in sleepers {
+            sleeper.wake();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    /// # Panics
+    ///
+    /// On wasm, where there's no `Instant` to start from. The middlewares
+    /// don't call it there, and [`ManualClock::elapsed`] still works.
+    fn now(&self) -> Instant {
+        let start = self.start.expect("no clock to start from on wasm");
+        start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let (deadline, id) = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            (state.elapsed.saturating_add(duration), state.next_id)
+        };
+
+        Box::pin(async move {
+            let _sleeping = Sleeping { clock: self, id };
+            poll_fn(|cx| {
+                let mut state = self.state.lock().unwrap();
+                if state.elapsed >= deadline {
+                    Poll::Ready(())
+                } else {
+                    state.sleepers.insert(id, cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+            .await
+        })
+    }
+}
+
+/// Unregisters a [`ManualClock::sleep`] that was done or dropped.
+struct Sleeping<'a> {
+    clock: &'a ManualClock,
+    id: u64,
+}
+
+impl Drop for Sleeping<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .clock
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        state.sleepers.remove(&self.id);
+    }
+}
+
+/// What a middleware measures time with. The system timers are
+/// used directly, to avoid boxing their futures.
+#[derive(Clone)]
+pub(crate) enum TimeSource {
+    System,
+    Clock(Arc<dyn Clock>),
+}
+
+impl TimeSource {
+    /// What deadlines are compared against. The system clock is Tokio's,
+    /// which can be paused in tests. There's none without Tokio's time
+    /// driver, so deadlines are ignored on wasm.
+    pub(crate) fn now(&self) -> Option<Instant> {
+        match self {
+            #[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+            TimeSource::System => Some(tokio::time::Instant::now().into_std()),
+            #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+            TimeSource::System => None,
+            // `ManualClock` has no `Instant` to give either
+            TimeSource::Clock(_) if cfg!(target_arch = "wasm32") => None,
+            TimeSource::Clock(clock) => Some(clock.now()),
+        }
+    }
+
+    #[cfg(feature = "retry_wait")]
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        match self {
+            TimeSource::System => crate::time::sleep(duration).await,
+            TimeSource::Clock(clock) => clock.sleep(duration).await,
+        }
+    }
+
+    #[cfg(feature = "timeout")]
+    pub(crate) async fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, crate::time::Elapsed> {
+        match self {
+            TimeSource::System => crate::time::timeout(duration, future)
+                .await
+                .map_err(|_| crate::time::Elapsed),
+            TimeSource::Clock(clock) => crate::time::race(future, clock.sleep(duration)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::join;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn manual_clock_test() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+
+        let advance = async {
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+                clock.advance(Duration::from_millis(1));
+            }
+        };
+
+        let sleep = async {
+            clock.sleep(Duration::from_millis(5)).await;
+            clock.elapsed()
+        };
+
+        let (slept, ()) = join!(sleep, advance);
+        assert_eq!(slept, Duration::from_millis(5));
+        assert_eq!(clock.now() - start, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn manual_clock_sleepers_test() {
+        let clock = ManualClock::new();
+        let sleepers = || clock.state.lock().unwrap().sleepers.len();
+        let waker = Waker::noop();
+        let mut cx = std::task::Context::from_waker(waker);
+
+        // Polled again and again, or dropped before it's due
+        let mut sleep = clock.sleep(Duration::from_millis(5));
+        for _ in 0..10 {
+            assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(sleepers(), 1);
+        drop(sleep);
+        assert_eq!(sleepers(), 0);
+
+        let mut sleep = clock.sleep(Duration::from_millis(5));
+        assert!(sleep.as_mut().poll(&mut cx).is_pending());
+        clock.advance(Duration::from_millis(5));
+        assert!(sleep.as_mut().poll(&mut cx).is_ready());
+        drop(sleep);
+        assert_eq!(sleepers(), 0);
+    }
+}
@@ -0,0 +1,981 @@
+//! Small adapters gluing services together inline, through [`ServiceExt`].
+//!
+//! ```ignore
+//! // A missing user isn't an error for the caller
+//! let users = users.recover(|e| match e {
+//!     UserError::NotFound => Ok(None),
+//!     e => Err(e),
+//! });
+//!
+//! // Only the name of the user, as an error of the application
+//! let names = users
+//!     .map_request(|id: UserId| id.0)
+//!     .map_response(|user: User| user.name)
+//!     .map_err(AppError::Users);
+//!
+//! // Look the order up in the archive when it's not in the live database
+//! let orders = orders.or_else(|id, e| async move {
+//!     match e {
+//!         OrderError::NotFound => archive.fetch(id).await.map_err(OrderError::Archive),
+//!         e => Err(e),
+//!     }
+//! });
+//!
+//! // Or from the read replica, whatever the error
+//! let users = primary.fallback(replica);
+//!
+//! // The profile of the user the session belongs to
+//! let profiles = sessions.then(users);
+//! ```
+//!
+//! [`service_fn`] goes the other way, making a service out of an async
+//! closure, for leaf services and tests that don't need a type of their own.
+//!
+//! With the `derive` feature, [`Dispatch`] makes one service out of a
+//! service per operation, e.g. for an API client.
+
+use core::error::Error;
+use std::{collections::BTreeMap, future::Future, marker::PhantomData};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::IntoFlat,
+    Middleware, Service,
+};
+
+/// Generates a service dispatching each variant of an enum of requests to
+/// its own inner service.
+#[cfg(feature = "derive")]
+pub use jenga_derive::Dispatch;
+
+/// Adapters available on every service.
+pub trait ServiceExt<R>: Service<R> + Sized {
+    /// See [`Recover`].
+    fn recover<F>(self, f: F) -> Recover<R, Self, F>
+    where
+        F: Fn(Self::Error) -> Result<Self::Response, Self::Error>,
+    {
+        Recover::new(self, f)
+    }
+
+    /// See [`OrElse`].
+    fn or_else<F, Fut, E>(self, f: F) -> OrElse<R, Self, F>
+    where
+        R: Clone,
+        F: Fn(R, Self::Error) -> Fut,
+        Fut: Future<Output = Result<Self::Response, E>>,
+        E: Error + 'static,
+    {
+        OrElse::new(self, f)
+    }
+
+    /// See [`MapResponse`].
+    fn map_response<F, U>(self, f: F) -> MapResponse<R, Self, F>
+    where
+        F: Fn(Self::Response) -> U,
+    {
+        MapResponse::new(self, f)
+    }
+
+    /// See [`MapErr`].
+    fn map_err<F, E>(self, f: F) -> MapErr<R, Self, F>
+    where
+        F: Fn(Self::Error) -> E,
+        E: Error + 'static,
+    {
+        MapErr::new(self, f)
+    }
+
+    /// See [`AndThen`].
+    fn and_then<F, Fut, U>(self, f: F) -> AndThen<R, Self, F>
+    where
+        F: Fn(Self::Response) -> Fut,
+        Fut: Future<Output = Result<U, Self::Error>>,
+    {
+        AndThen::new(self, f)
+    }
+
+    /// See [`Fallback`].
+    fn fallback<S>(self, secondary: S) -> Fallback<R, Self, S>
+    where
+        R: Clone,
+        S: Service<R, Response = Self::Response>,
+    {
+        Fallback::new(self, secondary)
+    }
+
+    /// See [`Then`].
+    fn then<S>(self, next: S) -> Then<R, Self, S>
+    where
+        S: Service<Self::Response>,
+    {
+        Then::new(self, next)
+    }
+
+    /// See [`MapRequest`].
+    fn map_request<Q, F>(self, f: F) -> MapRequest<Q, Self, F>
+    where
+        F: Fn(Q) -> R,
+    {
+        MapRequest::new(self, f)
+    }
+
+    /// See [`call_all`](crate::stream::call_all).
+    #[cfg(feature = "stream")]
+    fn call_all<St>(self, requests: St) -> crate::stream::CallAll<Self, St>
+    where
+        Self: 'static,
+        R: 'static,
+        St: crate::stream::Stream<Item = R>,
+    {
+        crate::stream::call_all(std::sync::Arc::new(self), requests)
+    }
+}
+
+impl<R, T: Service<R>> ServiceExt<R> for T {}
+
+/// Makes a service out of an async closure, see [`ServiceFn`].
+///
+/// ```ignore
+/// let hits = AtomicU64::new(0);
+/// let service = service_fn(|key: String| {
+///     hits.fetch_add(1, Ordering::Relaxed);
+///     async move { store.get(&key).await.ok_or(StoreError::NotFound(key)) }
+/// });
+/// ```
+pub fn service_fn<R, F, Fut, U, E>(f: F) -> ServiceFn<R, F>
+where
+    F: Fn(R) -> Fut,
+    Fut: Future<Output = Result<U, E>>,
+    E: Error + 'static,
+{
+    ServiceFn {
+        f,
+        phantom: PhantomData,
+    }
+}
+
+/// A service calling `f` for every request. It can capture state, which
+/// the futures it returns can only borrow if it's moved into them.
+pub struct ServiceFn<R, F> {
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, F, Fut, U, E> Service<R> for ServiceFn<R, F>
+where
+    F: Fn(R) -> Fut,
+    Fut: Future<Output = Result<U, E>>,
+    E: Error + 'static,
+{
+    type Response = U;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        (self.f)(msg).await
+    }
+}
+
+impl<R, F> Describe for ServiceFn<R, F> {
+    fn name(&self) -> &'static str {
+        "service_fn"
+    }
+}
+
+impl<R: 'static, F: 'static> FindLayer for ServiceFn<R, F> {}
+
+/// Turns some errors of the inner service into responses. `f` gets every
+/// error, and returns either the response to use instead or the error
+/// (possibly another one) to fail with.
+///
+/// Unlike a fallback, no other service is called.
+pub struct Recover<R, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, F> Recover<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Error) -> Result<T::Response, T::Error>,
+{
+    pub fn new(service: T, f: F) -> Self {
+        Recover {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T, F> Service<R> for Recover<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Error) -> Result<T::Response, T::Error>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.request(msg).await.or_else(&self.f)
+    }
+}
+
+impl<R, T, F> Middleware<R, T> for Recover<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Error) -> Result<T::Response, T::Error>,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, F> Describe for Recover<R, T, F> {
+    fn name(&self) -> &'static str {
+        "recover"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, F: 'static> FindLayer for Recover<R, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Calls the async `f` with the request and the error when the inner
+/// service fails, for it to come up with a response or an error, possibly
+/// of another type.
+///
+/// The request is cloned before each call to the inner service, to be
+/// there for `f`.
+pub struct OrElse<R, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, F> OrElse<R, T, F> {
+    pub fn new(service: T, f: F) -> Self {
+        OrElse {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T, F, Fut, E> Service<R> for OrElse<R, T, F>
+where
+    R: Clone,
+    T: Service<R>,
+    F: Fn(R, T::Error) -> Fut,
+    Fut: Future<Output = Result<T::Response, E>>,
+    E: Error + 'static,
+{
+    type Response = T::Response;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        match self.inner.request(msg.clone()).await {
+            Ok(resp) => Ok(resp),
+            Err(e) => (self.f)(msg, e).await,
+        }
+    }
+}
+
+impl<R, T, F, Fut, E> Middleware<R, T> for OrElse<R, T, F>
+where
+    R: Clone,
+    T: Service<R>,
+    F: Fn(R, T::Error) -> Fut,
+    Fut: Future<Output = Result<T::Response, E>>,
+    E: Error + 'static,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, F> Describe for OrElse<R, T, F> {
+    fn name(&self) -> &'static str {
+        "or_else"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, F: 'static> FindLayer for OrElse<R, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Turns the responses of the inner service into something else.
+pub struct MapResponse<R, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, F> MapResponse<R, T, F> {
+    pub fn new(service: T, f: F) -> Self {
+        MapResponse {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T, F, U> Service<R> for MapResponse<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Response) -> U,
+{
+    type Response = U;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.request(msg).await.map(&self.f)
+    }
+}
+
+impl<R, T, F, U> Middleware<R, T> for MapResponse<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Response) -> U,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, F> Describe for MapResponse<R, T, F> {
+    fn name(&self) -> &'static str {
+        "map_response"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, F: 'static> FindLayer for MapResponse<R, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Turns the errors of the inner service into another error, e.g. the
+/// error type of the application.
+pub struct MapErr<R, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, F> MapErr<R, T, F> {
+    pub fn new(service: T, f: F) -> Self {
+        MapErr {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T, F, E> Service<R> for MapErr<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Error) -> E,
+    E: Error + 'static,
+{
+    type Response = T::Response;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.inner.request(msg).await.map_err(&self.f)
+    }
+}
+
+impl<R, T, F, E> Middleware<R, T> for MapErr<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Error) -> E,
+    E: Error + 'static,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, F> Describe for MapErr<R, T, F> {
+    fn name(&self) -> &'static str {
+        "map_err"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, F: 'static> FindLayer for MapErr<R, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Calls the async `f` with the response of the inner service when it
+/// succeeds, e.g. to fetch what the response points to. `f` fails with the
+/// error type of the inner service, combine with [`MapErr`] otherwise.
+pub struct AndThen<R, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, F> AndThen<R, T, F> {
+    pub fn new(service: T, f: F) -> Self {
+        AndThen {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T, F, Fut, U> Service<R> for AndThen<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Response) -> Fut,
+    Fut: Future<Output = Result<U, T::Error>>,
+{
+    type Response = U;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let resp = self.inner.request(msg).await?;
+        (self.f)(resp).await
+    }
+}
+
+impl<R, T, F, Fut, U> Middleware<R, T> for AndThen<R, T, F>
+where
+    T: Service<R>,
+    F: Fn(T::Response) -> Fut,
+    Fut: Future<Output = Result<U, T::Error>>,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, F> Describe for AndThen<R, T, F> {
+    fn name(&self) -> &'static str {
+        "and_then"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, F: 'static> FindLayer for AndThen<R, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Sends the request to the `secondary` service when the inner one fails,
+/// e.g. a read replica or a cache for when the database is down.
+///
+/// The request is cloned before each call to the inner service, to be
+/// there for the secondary. Use [`OrElse`] to only fall back on some errors:
+/// it takes a closure rather than a service, which is why this one isn't
+/// called `or_else` too.
+pub struct Fallback<R, T, S> {
+    inner: T,
+    secondary: S,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// Both services of a [`Fallback`] failed.
+///
+/// Classified and flattened as the error of the secondary service, the last
+/// one to be tried.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("primary and secondary services failed")]
+pub struct FallbackError<A: Error, B: Error> {
+    pub primary: A,
+    #[source]
+    pub secondary: B,
+}
+
+impl<A: Error, B: Error + IntoFlat<F>, F> IntoFlat<F> for FallbackError<A, B> {
+    fn into_flat(self) -> F {
+        self.secondary.into_flat()
+    }
+}
+
+impl<A: Error, B: Error + Classify> Classify for FallbackError<A, B> {
+    fn classify(&self) -> Class {
+        self.secondary.classify()
+    }
+}
+
+impl<R, T, S> Fallback<R, T, S> {
+    pub fn new(service: T, secondary: S) -> Self {
+        Fallback {
+            inner: service,
+            secondary,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+}
+
+impl<R, T, S> Service<R> for Fallback<R, T, S>
+where
+    R: Clone,
+    T: Service<R>,
+    S: Service<R, Response = T::Response>,
+{
+    type Response = T::Response;
+    type Error = FallbackError<T::Error, S::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        match self.inner.request(msg.clone()).await {
+            Ok(resp) => Ok(resp),
+            Err(primary) => self
+                .secondary
+                .request(msg)
+                .await
+                .map_err(|secondary| FallbackError { primary, secondary }),
+        }
+    }
+}
+
+impl<R, T, S> Middleware<R, T> for Fallback<R, T, S>
+where
+    R: Clone,
+    T: Service<R>,
+    S: Service<R, Response = T::Response>,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// Describes the stack of the primary service, naming the secondary one.
+impl<R, T: Service<R> + Describe, S: Describe> Describe for Fallback<R, T, S> {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("secondary", self.secondary.name().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, S: 'static> FindLayer for Fallback<R, T, S> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Sends the responses of the inner service to the `next` service as its
+/// requests, answering with the responses of the latter. Like [`AndThen`],
+/// with a service instead of a closure.
+pub struct Then<R, T, S> {
+    inner: T,
+    next: S,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// The error of a [`Then`], from whichever service failed.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ThenError<A: Error, B: Error> {
+    #[error("first service failed")]
+    First(#[source] A),
+    #[error("next service failed")]
+    Next(#[source] B),
+}
+
+impl<A: Error + IntoFlat<F>, B: Error + IntoFlat<F>, F> IntoFlat<F> for ThenError<A, B> {
+    fn into_flat(self) -> F {
+        match self {
+            ThenError::First(e) => e.into_flat(),
+            ThenError::Next(e) => e.into_flat(),
+        }
+    }
+}
+
+impl<A: Error + Classify, B: Error + Classify> Classify for ThenError<A, B> {
+    fn classify(&self) -> Class {
+        match self {
+            ThenError::First(e) => e.classify(),
+            ThenError::Next(e) => e.classify(),
+        }
+    }
+}
+
+impl<R, T, S> Then<R, T, S> {
+    pub fn new(service: T, next: S) -> Self {
+        Then {
+            inner: service,
+            next,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn next(&self) -> &S {
+        &self.next
+    }
+}
+
+impl<R, T, S> Service<R> for Then<R, T, S>
+where
+    T: Service<R>,
+    S: Service<T::Response>,
+{
+    type Response = S::Response;
+    type Error = ThenError<T::Error, S::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let resp = self.inner.request(msg).await.map_err(ThenError::First)?;
+        self.next.request(resp).await.map_err(ThenError::Next)
+    }
+}
+
+impl<R, T, S> Middleware<R, T> for Then<R, T, S>
+where
+    T: Service<R>,
+    S: Service<T::Response>,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// Describes the stack of the first service, naming the next one.
+impl<R, T: Service<R> + Describe, S: Describe> Describe for Then<R, T, S> {
+    fn name(&self) -> &'static str {
+        "then"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("next", self.next.name().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, S: 'static> FindLayer for Then<R, T, S> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Turns requests of type `Q` into the requests of the inner service.
+///
+/// Not a [`Middleware`], since the inner service takes other requests.
+pub struct MapRequest<Q, T, F> {
+    inner: T,
+    f: F,
+    phantom: PhantomData<fn(Q) -> Q>,
+}
+
+impl<Q, T, F> MapRequest<Q, T, F> {
+    pub fn new(service: T, f: F) -> Self {
+        MapRequest {
+            inner: service,
+            f,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<Q, R, T, F> Service<Q> for MapRequest<Q, T, F>
+where
+    T: Service<R>,
+    F: Fn(Q) -> R,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: Q) -> Result<Self::Response, Self::Error> {
+        self.inner.request((self.f)(msg)).await
+    }
+}
+
+impl<Q, T: Describe, F> Describe for MapRequest<Q, T, F> {
+    fn name(&self) -> &'static str {
+        "map_request"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<Q: 'static, T: FindLayer, F: 'static> FindLayer for MapRequest<Q, T, F> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum UserError {
+        #[error("no such user")]
+        NotFound,
+        #[error("database is down")]
+        Unavailable,
+    }
+
+    impl Classify for UserError {
+        fn classify(&self) -> Class {
+            match self {
+                UserError::NotFound => Class::Permanent,
+                UserError::Unavailable => Class::Transient,
+            }
+        }
+    }
+
+    /// Knows user 1, and fails on 0
+    pub struct TestUserService {}
+
+    impl Service<u64> for TestUserService {
+        type Response = Option<&'static str>;
+        type Error = UserError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            match msg {
+                0 => Err(UserError::Unavailable),
+                1 => Ok(Some("alice")),
+                _ => Err(UserError::NotFound),
+            }
+        }
+    }
+
+    impl Describe for TestUserService {
+        fn name(&self) -> &'static str {
+            "users"
+        }
+    }
+
+    #[tokio::test]
+    async fn recover_test() {
+        let users = TestUserService {}.recover(|e| match e {
+            UserError::NotFound => Ok(None),
+            e => Err(e),
+        });
+
+        assert_eq!(users.request(1).await, Ok(Some("alice")));
+        assert_eq!(users.request(2).await, Ok(None));
+        assert_eq!(users.request(0).await, Err(UserError::Unavailable));
+    }
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum AppError {
+        #[error("user {0} is unavailable")]
+        Unavailable(u64),
+    }
+
+    #[tokio::test]
+    async fn or_else_test() {
+        let users = TestUserService {}.or_else(|id, e| async move {
+            match e {
+                UserError::NotFound => Ok(Some("guest")),
+                UserError::Unavailable => Err(AppError::Unavailable(id)),
+            }
+        });
+
+        assert_eq!(users.request(1).await, Ok(Some("alice")));
+        assert_eq!(users.request(2).await, Ok(Some("guest")));
+        assert_eq!(users.request(0).await, Err(AppError::Unavailable(0)));
+    }
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum LookupError {
+        #[error("user lookup failed")]
+        Users(#[source] UserError),
+    }
+
+    #[tokio::test]
+    async fn map_test() {
+        // Users looked up by the length of their name
+        let names = TestUserService {}
+            .map_request(|name: &str| name.len() as u64)
+            .map_response(|user| user.unwrap_or_default())
+            .map_err(LookupError::Users);
+
+        assert_eq!(names.request("a").await, Ok("alice"));
+        assert_eq!(
+            names.request("").await,
+            Err(LookupError::Users(UserError::Unavailable))
+        );
+    }
+
+    #[tokio::test]
+    async fn service_fn_test() {
+        let calls = std::sync::atomic::AtomicU64::new(0);
+        let lookups = service_fn(|id: u64| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            async move {
+                match id {
+                    1 => Ok("alice"),
+                    _ => Err(UserError::NotFound),
+                }
+            }
+        });
+
+        assert_eq!(lookups.request(1).await, Ok("alice"));
+        assert_eq!(lookups.request(2).await, Err(UserError::NotFound));
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        // Works as the leaf of a stack
+        let names = lookups.map_response(str::to_uppercase);
+        assert_eq!(names.request(1).await, Ok("ALICE".to_string()));
+        assert_eq!(names.describe().layers[1].name, "service_fn");
+    }
+
+    #[tokio::test]
+    async fn and_then_test() {
+        let lengths = TestUserService {}.and_then(|user| async move {
+            match user {
+                Some(name) => Ok(name.len()),
+                None => Err(UserError::NotFound),
+            }
+        });
+
+        assert_eq!(lengths.request(1).await, Ok(5));
+        assert_eq!(lengths.request(0).await, Err(UserError::Unavailable));
+    }
+
+    /// Knows every user but 3, as a guest
+    pub struct TestGuestService {}
+
+    impl Service<u64> for TestGuestService {
+        type Response = Option<&'static str>;
+        type Error = UserError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            match msg {
+                3 => Err(UserError::NotFound),
+                _ => Ok(Some("guest")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_test() {
+        let users = TestUserService {}.fallback(TestGuestService {});
+
+        assert_eq!(users.request(1).await, Ok(Some("alice")));
+        assert_eq!(users.request(0).await, Ok(Some("guest")));
+
+        let err = users.request(3).await.unwrap_err();
+        assert_eq!(
+            err,
+            FallbackError {
+                primary: UserError::NotFound,
+                secondary: UserError::NotFound
+            }
+        );
+        assert_eq!(err.classify(), Class::Permanent);
+    }
+
+    #[tokio::test]
+    async fn then_test() {
+        // Users looked up by the id their session points to
+        let sessions = service_fn(|session: &str| async move {
+            match session {
+                "expired" => Err(AppError::Unavailable(0)),
+                _ => Ok(session.len() as u64),
+            }
+        });
+        let users = sessions.then(TestUserService {});
+
+        assert_eq!(users.request("a").await, Ok(Some("alice")));
+        assert_eq!(
+            users.request("").await,
+            Err(ThenError::Next(UserError::Unavailable))
+        );
+        assert_eq!(
+            users.request("expired").await,
+            Err(ThenError::First(AppError::Unavailable(0)))
+        );
+        assert_eq!(users.describe().layers[0].params["next"], "users");
+    }
+
+    #[cfg(feature = "derive")]
+    #[tokio::test]
+    async fn dispatch_test() {
+        #[derive(Debug, Dispatch)]
+        pub enum DirectoryRequest {
+            GetUser(u64),
+            Ping,
+        }
+
+        pub struct TestPingService {}
+
+        impl Service<()> for TestPingService {
+            type Response = &'static str;
+            type Error = UserError;
+
+            async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+                Ok("pong")
+            }
+        }
+
+        let directory = DirectoryService {
+            get_user: TestUserService {},
+            ping: TestPingService {},
+        };
+
+        assert_eq!(
+            directory.request(DirectoryRequest::GetUser(1)).await,
+            Ok(DirectoryResponse::GetUser(Some("alice")))
+        );
+        assert_eq!(
+            directory.request(DirectoryRequest::Ping).await,
+            Ok(DirectoryResponse::Ping("pong"))
+        );
+
+        let err = directory
+            .request(DirectoryRequest::GetUser(0))
+            .await
+            .unwrap_err();
+        assert_eq!(err, DirectoryError::GetUser(UserError::Unavailable));
+        assert_eq!(err.to_string(), "database is down");
+        assert_eq!(err.classify(), Class::Transient);
+    }
+}
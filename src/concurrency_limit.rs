@@ -0,0 +1,592 @@
+//! A concurrency limit that adapts to the latency of the inner service.
+//!
+//! Unlike [`RateLimit`](crate::rate_limit::RateLimit)'s fixed limit, the
+//! limit of a [`ConcurrencyLimit`] is moved by a [`LimitAlgorithm`] after
+//! every request: [`Aimd`] backs off when requests fail, [`Gradient`] when
+//! they get slower than the service's no-load latency. Other algorithms
+//! can be plugged in by implementing the trait.
+
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::PoisonError,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack, StackStats},
+    error::{FromRateLimited, IntoFlat},
+    events::{self, StackEvent},
+    load::{Admission, Admit, Load, LoadMetric},
+    sync::{AtomicUsize, Mutex, Ordering},
+    Middleware, Service,
+};
+
+/// How a request went, as seen by a [`LimitAlgorithm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// How long the inner service took to answer. Zero on wasm, where
+    /// there's no clock.
+    pub rtt: Duration,
+    /// Requests in flight when this one started, itself included.
+    pub in_flight: usize,
+    /// Whether the request failed in a way that signals overload.
+    pub dropped: bool,
+}
+
+/// Decides the concurrency limit of a [`ConcurrencyLimit`].
+pub trait LimitAlgorithm {
+    /// The limit before any request went through.
+    fn initial_limit(&self) -> usize;
+
+    /// Called after every request, returns the new limit.
+    fn update(&mut self, sample: Sample) -> usize;
+}
+
+/// Additive increase, multiplicative decrease: the limit grows by one
+/// after every successful request while the limit is being used, and is
+/// multiplied by `backoff` after every dropped one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aimd {
+    limit: usize,
+    min_limit: usize,
+    max_limit: usize,
+    backoff: f64,
+}
+
+impl Aimd {
+    /// Starts at `initial_limit`, staying within `min_limit..=max_limit`,
+    /// and halves the limit on drops.
+    pub fn new(initial_limit: usize, min_limit: usize, max_limit: usize) -> Self {
+        let min_limit = min_limit.max(1);
+        let max_limit = max_limit.max(min_limit);
+        Aimd {
+            limit: initial_limit.clamp(min_limit, max_limit),
+            min_limit,
+            max_limit,
+            backoff: 0.5,
+        }
+    }
+
+    /// Multiplies the limit by `backoff` (clamped to `0.0..1.0`) on drops.
+    pub fn with_backoff(self, backoff: f64) -> Self {
+        Aimd {
+            backoff: backoff.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+}
+
+impl LimitAlgorithm for Aimd {
+    fn initial_limit(&self) -> usize {
+        self.limit
+    }
+
+    fn update(&mut self, sample: Sample) -> usize {
+        if sample.dropped {
+            self.limit = (self.limit as f64 * self.backoff) as usize;
+        } else if sample.in_flight * 2 >= self.limit {
+            // Only grow when the limit is what's holding requests back
+            self.limit += 1;
+        }
+        self.limit = self.limit.clamp(self.min_limit, self.max_limit);
+        self.limit
+    }
+}
+
+/// Gradient controller, after Netflix's Gradient2.
+///
+/// Tracks the no-load latency of the service as a long-term average of the
+/// RTTs, and scales the limit by how far the last RTT is from it (the
+/// gradient). Requests queueing up in the service make the RTT grow and the
+/// limit shrink, while a steady RTT lets the limit grow by a queue of
+/// `sqrt(limit)` requests. Changes are smoothed, so single slow requests
+/// don't make the limit collapse.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    limit: f64,
+    min_limit: usize,
+    max_limit: usize,
+    /// Estimated no-load RTT, in seconds.
+    long_rtt: Option<f64>,
+    /// How many samples the long-term average is over.
+    window: f64,
+    /// How much slower than the no-load RTT requests can be before the
+    /// limit shrinks.
+    tolerance: f64,
+    smoothing: f64,
+}
+
+impl Gradient {
+    /// Starts at `initial_limit`, staying within `min_limit..=max_limit`.
+    pub fn new(initial_limit: usize, min_limit: usize, max_limit: usize) -> Self {
+        let min_limit = min_limit.max(1);
+        let max_limit = max_limit.max(min_limit);
+        Gradient {
+            limit: initial_limit.clamp(min_limit, max_limit) as f64,
+            min_limit,
+            max_limit,
+            long_rtt: None,
+            window: 600.0,
+            tolerance: 1.5,
+            smoothing: 0.2,
+        }
+    }
+
+    /// Averages the no-load RTT over the last `samples` requests (600 by
+    /// default). Longer windows react slower to the service getting faster.
+    pub fn with_window(self, samples: usize) -> Self {
+        Gradient {
+            window: samples.max(1) as f64,
+            ..self
+        }
+    }
+
+    /// Lets requests be up to `tolerance` times slower than the no-load
+    /// RTT (1.5 by default) before the limit shrinks.
+    pub fn with_tolerance(self, tolerance: f64) -> Self {
+        Gradient {
+            tolerance: tolerance.max(1.0),
+            ..self
+        }
+    }
+
+    /// Moves the limit by `smoothing` (clamped to `0.0..=1.0`, 0.2 by
+    /// default) of the way to its new value on every request.
+    pub fn with_smoothing(self, smoothing: f64) -> Self {
+        Gradient {
+            smoothing: smoothing.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// The estimated no-load RTT, once a request went through.
+    pub fn no_load_rtt(&self) -> Option<Duration> {
+        self.long_rtt.map(Duration::from_secs_f64)
+    }
+}
+
+impl LimitAlgorithm for Gradient {
+    fn initial_limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn update(&mut self, sample: Sample) -> usize {
+        let rtt = sample.rtt.as_secs_f64();
+        let long_rtt = match self.long_rtt {
+            None => rtt,
+            // Recovers faster once the service is no longer overloaded
+            Some(long_rtt) if long_rtt > rtt * 2.0 => long_rtt * 0.95,
+            Some(long_rtt) => long_rtt + (rtt - long_rtt) / self.window,
+        };
+        self.long_rtt = Some(long_rtt);
+
+        // Not enough load to tell whether the limit is right
+        if !sample.dropped && (sample.in_flight as f64) < self.limit / 2.0 {
+            return self.limit as usize;
+        }
+
+        let gradient = if rtt > 0.0 {
+            (self.tolerance * long_rtt / rtt).clamp(0.5, 1.0)
+        } else {
+            1.0
+        };
+        let new_limit = self.limit * gradient + self.limit.sqrt();
+        let new_limit = self.limit * (1.0 - self.smoothing) + new_limit * self.smoothing;
+
+        self.limit = new_limit.clamp(self.min_limit as f64, self.max_limit as f64);
+        self.limit as usize
+    }
+}
+
+/// Limits how many concurrent requests can happen on a given service, with
+/// a limit decided by a [`LimitAlgorithm`].
+///
+/// Every error of the inner service counts as a drop, unless the limit is
+/// [`ConcurrencyLimit::classified`].
+pub struct ConcurrencyLimit<R, T: Service<R>, A> {
+    inner: T,
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+    algorithm: Mutex<A>,
+    is_dropped: fn(&T::Error) -> bool,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ConcurrencyLimitError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("concurrency limit reached")]
+    Limited,
+}
+
+impl<E: core::error::Error> ConcurrencyLimitError<E> {
+    #[inline]
+    pub fn is_limited(&self) -> bool {
+        matches!(self, ConcurrencyLimitError::Limited)
+    }
+
+    #[inline]
+    pub fn into_service_error(self) -> Option<E> {
+        match self {
+            ConcurrencyLimitError::ServiceError(e) => Some(e),
+            ConcurrencyLimitError::Limited => None,
+        }
+    }
+}
+
+impl<E: core::error::Error + IntoFlat<F>, F: FromRateLimited> IntoFlat<F>
+    for ConcurrencyLimitError<E>
+{
+    fn into_flat(self) -> F {
+        match self {
+            ConcurrencyLimitError::ServiceError(e) => e.into_flat(),
+            ConcurrencyLimitError::Limited => F::rate_limited(),
+        }
+    }
+}
+
+impl<E: core::error::Error + Classify> Classify for ConcurrencyLimitError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            ConcurrencyLimitError::ServiceError(e) => e.classify(),
+            ConcurrencyLimitError::Limited => Class::Throttled,
+        }
+    }
+}
+
+impl<R, T: Service<R>, A: LimitAlgorithm> ConcurrencyLimit<R, T, A> {
+    pub fn new(service: T, algorithm: A) -> Self {
+        Self {
+            inner: service,
+            limit: AtomicUsize::new(algorithm.initial_limit()),
+            in_flight: AtomicUsize::new(0),
+            algorithm: Mutex::new(algorithm),
+            is_dropped: |_| true,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Only counts the errors that are [retryable](crate::classify::Class::is_retryable)
+    /// as drops, since the others don't say anything about load.
+    pub fn classified(self) -> Self
+    where
+        T::Error: Classify,
+    {
+        ConcurrencyLimit {
+            is_dropped: |e| e.classify().is_retryable(),
+            ..self
+        }
+    }
+
+    /// The current limit.
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Requests currently being processed.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Calls `f` with the algorithm, e.g. to look at its estimates.
+    pub fn algorithm<O>(&self, f: impl FnOnce(&A) -> O) -> O {
+        f(&self.algorithm.lock().unwrap())
+    }
+
+    /// Takes a slot of the limit for work that doesn't go through the
+    /// service, so it shares the limit with the requests. `None` if the
+    /// limit is reached: like requests, permits are rejected rather than
+    /// queued.
+    ///
+    /// The slot is released when the permit is dropped, and how long it was
+    /// held is a sample for the algorithm like a request's RTT.
+    pub fn try_acquire(&self) -> Option<Permit<'_, A>> {
+        let in_flight = self
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                (v < self.limit.load(Ordering::Relaxed)).then_some(v + 1)
+            })
+            .ok()?;
+
+        Some(Permit {
+            limit: &self.limit,
+            in_flight: &self.in_flight,
+            algorithm: &self.algorithm,
+            started: (!cfg!(target_arch = "wasm32")).then(Instant::now),
+            in_flight_at_start: in_flight + 1,
+            dropped: false,
+        })
+    }
+}
+
+/// A slot of a [`ConcurrencyLimit`], released when dropped.
+pub struct Permit<'a, A: LimitAlgorithm> {
+    limit: &'a AtomicUsize,
+    in_flight: &'a AtomicUsize,
+    algorithm: &'a Mutex<A>,
+    started: Option<Instant>,
+    in_flight_at_start: usize,
+    dropped: bool,
+}
+
+impl<A: LimitAlgorithm> Permit<'_, A> {
+    /// Reports the work done under the permit as a drop, for the algorithm
+    /// to back off.
+    pub fn mark_dropped(&mut self) {
+        self.dropped = true;
+    }
+}
+
+impl<A: LimitAlgorithm> Drop for Permit<'_, A> {
+    fn drop(&mut self) {
+        let sample = Sample {
+            rtt: self
+                .started
+                .map_or(Duration::ZERO, |started| started.elapsed()),
+            in_flight: self.in_flight_at_start,
+            dropped: self.dropped,
+        };
+
+        // Released first, in case the algorithm panics
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        // A panic of the algorithm poisons its lock, permits are still
+        // dropped without panicking, possibly while unwinding
+        let limit = self
+            .algorithm
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .update(sample);
+        self.limit.store(limit.max(1), Ordering::Relaxed);
+    }
+}
+
+impl<R, T: Service<R>, A: LimitAlgorithm> Service<R> for ConcurrencyLimit<R, T, A> {
+    type Response = T::Response;
+    type Error = ConcurrencyLimitError<T::Error>;
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let Some(mut permit) = self.try_acquire() else {
+            events::emit(StackEvent::ConcurrencyLimited);
+            return Err(ConcurrencyLimitError::Limited);
+        };
+
+        let resp = self.inner.request(msg).await;
+        if resp.as_ref().is_err_and(self.is_dropped) {
+            permit.mark_dropped();
+        }
+
+        resp.map_err(ConcurrencyLimitError::ServiceError)
+    }
+}
+
+impl<R, T: Service<R>, A: LimitAlgorithm> Middleware<R, T> for ConcurrencyLimit<R, T, A> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// The capacity is the current limit, which moves with the algorithm.
+impl<R, T: Service<R>, A> Load for ConcurrencyLimit<R, T, A> {
+    fn load(&self) -> LoadMetric {
+        LoadMetric {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            queued: 0,
+            capacity: Some(self.limit.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<R, T: Service<R>, A> Admit<R> for ConcurrencyLimit<R, T, A> {
+    fn try_request(&self, _msg: &R) -> Admission {
+        if self.in_flight.load(Ordering::Relaxed) < self.limit.load(Ordering::Relaxed) {
+            Admission::Admitted
+        } else {
+            Admission::Rejected { retry_after: None }
+        }
+    }
+}
+
+impl<R, T: Service<R> + Describe, A> Describe for ConcurrencyLimit<R, T, A> {
+    fn name(&self) -> &'static str {
+        "concurrency_limit"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("limit", self.limit.load(Ordering::Relaxed).to_string()),
+            (
+                "in_flight",
+                self.in_flight.load(Ordering::Relaxed).to_string(),
+            ),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, A: 'static> FindLayer for ConcurrencyLimit<R, T, A> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+
+    fn record_stats(&self, stats: &mut StackStats) {
+        stats
+            .in_flight
+            .get_or_insert(self.in_flight.load(Ordering::Relaxed));
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::{join, time::sleep};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestConcurrencyService {}
+
+    #[derive(Debug, Error)]
+    pub enum TestError {
+        #[error("failed")]
+        Failed,
+    }
+
+    impl Service<bool> for TestConcurrencyService {
+        type Response = ();
+        type Error = TestError;
+
+        async fn request(&self, fail: bool) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(10)).await;
+            if fail {
+                Err(TestError::Failed)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn sample(rtt_ms: u64, in_flight: usize, dropped: bool) -> Sample {
+        Sample {
+            rtt: Duration::from_millis(rtt_ms),
+            in_flight,
+            dropped,
+        }
+    }
+
+    #[test]
+    fn aimd() {
+        let mut aimd = Aimd::new(10, 2, 12);
+        assert_eq!(aimd.update(sample(10, 10, false)), 11);
+        assert_eq!(aimd.update(sample(10, 11, false)), 12);
+        assert_eq!(aimd.update(sample(10, 12, false)), 12);
+        // Mostly idle, the limit isn't the bottleneck
+        assert_eq!(aimd.update(sample(10, 1, false)), 12);
+
+        assert_eq!(aimd.update(sample(10, 12, true)), 6);
+        assert_eq!(aimd.update(sample(10, 6, true)), 3);
+        assert_eq!(aimd.update(sample(10, 3, true)), 2);
+    }
+
+    #[test]
+    fn gradient() {
+        let mut gradient = Gradient::new(20, 1, 100);
+
+        // Steady latency, the limit grows
+        let mut limit = 20;
+        for _ in 0..50 {
+            limit = gradient.update(sample(10, limit, false));
+        }
+        assert!(limit > 40, "{limit}");
+        let no_load_rtt = gradient.no_load_rtt().unwrap().as_secs_f64();
+        assert!((no_load_rtt - 0.010).abs() < 1e-6, "{no_load_rtt}");
+
+        // Requests queue up and take 4 times longer, the limit shrinks
+        let grown = limit;
+        for _ in 0..20 {
+            limit = gradient.update(sample(40, limit, false));
+        }
+        assert!(limit < grown / 2, "{limit} < {grown} / 2");
+
+        // Too few requests to learn anything from
+        assert_eq!(gradient.update(sample(40, 1, false)), limit);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit() {
+        let service = ConcurrencyLimit::new(TestConcurrencyService {}, Aimd::new(2, 1, 10));
+
+        let (a, b, c) = join!(
+            service.request(false),
+            service.request(false),
+            service.request(false)
+        );
+        assert_eq!([&a, &b, &c].iter().filter(|res| res.is_ok()).count(), 2);
+        assert!(a.and(b).and(c).unwrap_err().is_limited());
+        // Both requests used the whole limit, so it grew
+        let grown = service.limit();
+        assert!(grown > 2, "{grown}");
+        assert_eq!(service.in_flight(), 0);
+
+        // Failures make it back off
+        assert!(service.request(true).await.is_err());
+        assert_eq!(service.limit(), grown / 2);
+        assert_eq!(service.algorithm(|aimd| aimd.initial_limit()), grown / 2);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_permit() {
+        let service = ConcurrencyLimit::new(TestConcurrencyService {}, Aimd::new(2, 1, 10));
+
+        let mut permit = service.try_acquire().unwrap();
+        assert!(service.try_request(&false).is_admitted());
+        let (a, b) = join!(service.request(false), service.request(false));
+        assert!(a.is_ok() != b.is_ok());
+        assert_eq!(service.in_flight(), 1);
+
+        // Dropping the permit releases the slot and feeds the algorithm
+        let limit = service.limit();
+        permit.mark_dropped();
+        drop(permit);
+        assert_eq!(service.in_flight(), 0);
+        assert_eq!(service.limit(), limit / 2);
+    }
+
+    /// Panics on dropped samples
+    pub struct TestPanickingAlgorithm {}
+
+    impl LimitAlgorithm for TestPanickingAlgorithm {
+        fn initial_limit(&self) -> usize {
+            2
+        }
+
+        fn update(&mut self, sample: Sample) -> usize {
+            assert!(!sample.dropped, "dropped");
+            2
+        }
+    }
+
+    #[test]
+    fn concurrency_limit_poisoned() {
+        let service = ConcurrencyLimit::new(TestConcurrencyService {}, TestPanickingAlgorithm {});
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut permit = service.try_acquire().unwrap();
+            permit.mark_dropped();
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(service.in_flight(), 0);
+
+        // The algorithm's lock is poisoned, permits still release their slot
+        let permit = service.try_acquire().unwrap();
+        drop(permit);
+        assert_eq!(service.in_flight(), 0);
+        assert_eq!(service.limit(), 2);
+    }
+}
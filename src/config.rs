@@ -0,0 +1,450 @@
+//! Declarative description of a middleware stack.
+//!
+//! A [`StackConfig`] can be deserialized (from YAML, JSON, ...) and turned into
+//! a [`ConfiguredStack`] around a leaf service:
+//!
+//! `RateLimit` → `Retry` → `Timeout` → leaf
+//!
+//! so every attempt made by `Retry` gets its own timeout, and retries don't
+//! take more than one slot of the rate limit.
+//!
+//! Retry counts and limits are const generics in jenga, so they're still
+//! chosen at compile time: the configured values are checked against them
+//! when building the stack. Durations are fully configurable.
+//!
+//! Building also rejects combinations of layers that can't work together,
+//! like a zero timeout, or attempts that can't fit in the optional overall
+//! `budget_ms` of a request. [`StackConfig::validate`] runs these checks
+//! on their own.
+//!
+//! A stack built with [`StackConfig::build_watched`] follows the updates of
+//! a `watch` channel instead: each request takes the latest config as a
+//! whole, so every layer sees the same one, and requests already running
+//! keep theirs. The timeout, the retry wait and the retry count (up to the
+//! compiled one) can change this way, published with [`StackConfig::publish`].
+
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::{
+    describe::{Describe, FindLayer, Stack},
+    meta::{self, Meta},
+    rate_limit::RateLimit,
+    retry::Retry,
+    timeout::Timeout,
+    Middleware, Service,
+};
+
+pub type ConfiguredStack<const RETRY_COUNT: usize, const LIMIT: usize, R, T> =
+    RateLimit<LIMIT, R, Retry<RETRY_COUNT, R, Timeout<R, T>>>;
+
+pub type WatchedStack<const RETRY_COUNT: usize, const LIMIT: usize, R, T> =
+    Watched<R, ConfiguredStack<RETRY_COUNT, LIMIT, R, T>>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StackConfig {
+    pub rate_limit: RateLimitConfig,
+    pub retry: RetryConfig,
+    pub timeout: TimeoutConfig,
+    /// The longest a request should take, every attempt and wait included,
+    /// in milliseconds. Only used to validate the other durations.
+    #[serde(default)]
+    pub budget_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Maximum amount of concurrent requests
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// How many times a failed request is retried
+    pub count: usize,
+    /// How long to wait between two attempts, in milliseconds
+    #[serde(default)]
+    pub wait_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Timeout of a single attempt, in milliseconds
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum ConfigError {
+    #[error("configured retry count {configured} doesn't match the stack's {compiled}")]
+    RetryCountMismatch { configured: usize, compiled: usize },
+    #[error("configured rate limit {configured} doesn't match the stack's {compiled}")]
+    RateLimitMismatch { configured: usize, compiled: usize },
+    #[error("a timeout of 0ms fails every request")]
+    ZeroTimeout,
+    #[error("a rate limit of 0 rejects every request")]
+    ZeroRateLimit,
+    #[error("attempts and waits can take up to {worst_case_ms}ms, over the {budget_ms}ms budget")]
+    BudgetExceeded { worst_case_ms: u64, budget_ms: u64 },
+}
+
+impl StackConfig {
+    /// Longest a request can take: every attempt timing out, with a wait
+    /// between each of them.
+    pub fn worst_case_ms(&self) -> u64 {
+        let retries = self.retry.count as u64;
+        retries
+            .saturating_add(1)
+            .saturating_mul(self.timeout.duration_ms)
+            .saturating_add(retries.saturating_mul(self.retry.wait_ms))
+    }
+
+    /// Checks that the layers make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.timeout.duration_ms == 0 {
+            return Err(ConfigError::ZeroTimeout);
+        }
+
+        if self.rate_limit.limit == 0 {
+            return Err(ConfigError::ZeroRateLimit);
+        }
+
+        if let Some(budget_ms) = self.budget_ms {
+            let worst_case_ms = self.worst_case_ms();
+            if worst_case_ms > budget_ms {
+                return Err(ConfigError::BudgetExceeded {
+                    worst_case_ms,
+                    budget_ms,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the stack described by this config around `service`.
+    pub fn build<const RETRY_COUNT: usize, const LIMIT: usize, R: Clone, T: Service<R>>(
+        &self,
+        service: T,
+    ) -> Result<ConfiguredStack<RETRY_COUNT, LIMIT, R, T>, ConfigError> {
+        self.validate()?;
+
+        if self.retry.count != RETRY_COUNT {
+            return Err(ConfigError::RetryCountMismatch {
+                configured: self.retry.count,
+                compiled: RETRY_COUNT,
+            });
+        }
+
+        if self.rate_limit.limit != LIMIT {
+            return Err(ConfigError::RateLimitMismatch {
+                configured: self.rate_limit.limit,
+                compiled: LIMIT,
+            });
+        }
+
+        let timeout = Timeout::new(service, Duration::from_millis(self.timeout.duration_ms));
+        let retry = Retry::with_wait(timeout, Duration::from_millis(self.retry.wait_ms));
+
+        Ok(RateLimit::new(retry))
+    }
+
+    /// Builds the stack described by the current config of `config` around
+    /// `service`, following its later updates.
+    pub fn build_watched<const RETRY_COUNT: usize, const LIMIT: usize, R: Clone, T: Service<R>>(
+        config: watch::Receiver<StackConfig>,
+        service: T,
+    ) -> Result<WatchedStack<RETRY_COUNT, LIMIT, R, T>, ConfigError> {
+        let stack = config.borrow().build(service)?;
+        Ok(Watched {
+            inner: stack,
+            config,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Validates this config and sends it to the stacks watching `sender`,
+    /// built with a retry count of `RETRY_COUNT`. The rate limit is compiled
+    /// in, so it can't change, and the retry count can't go over the
+    /// compiled one.
+    pub fn publish<const RETRY_COUNT: usize>(
+        self,
+        sender: &watch::Sender<StackConfig>,
+    ) -> Result<(), ConfigError> {
+        self.validate()?;
+
+        if self.retry.count > RETRY_COUNT {
+            return Err(ConfigError::RetryCountMismatch {
+                configured: self.retry.count,
+                compiled: RETRY_COUNT,
+            });
+        }
+
+        let current = sender.borrow().rate_limit.limit;
+        if self.rate_limit.limit != current {
+            return Err(ConfigError::RateLimitMismatch {
+                configured: self.rate_limit.limit,
+                compiled: current,
+            });
+        }
+
+        sender.send_replace(self);
+        Ok(())
+    }
+}
+
+/// Applies the latest config of a `watch` channel to the stack below, see
+/// the [module docs](self).
+///
+/// The config goes through the [overrides](crate::meta::Overrides) of the
+/// request's metadata, so the ones the request set itself come first.
+pub struct Watched<R, T: Service<R>> {
+    inner: T,
+    config: watch::Receiver<StackConfig>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> Watched<R, T> {
+    /// The config the next request will use.
+    pub fn config(&self) -> StackConfig {
+        self.config.borrow().clone()
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for Watched<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let mut meta = meta::current().map_or_else(Meta::default, |meta| (*meta).clone());
+        {
+            let config = self.config.borrow();
+            let overrides = &mut meta.overrides;
+            overrides
+                .timeout
+                .get_or_insert(Duration::from_millis(config.timeout.duration_ms));
+            overrides.max_retries.get_or_insert(config.retry.count);
+            overrides
+                .retry_wait
+                .get_or_insert(Duration::from_millis(config.retry.wait_ms));
+        }
+
+        meta::scope(Some(Arc::new(meta)), self.inner.request(msg)).await
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for Watched<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for Watched<R, T> {
+    fn name(&self) -> &'static str {
+        "watched_config"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        let config = self.config.borrow();
+        BTreeMap::from([
+            ("timeout_ms", config.timeout.duration_ms.to_string()),
+            ("retry_count", config.retry.count.to_string()),
+            ("retry_wait_ms", config.retry.wait_ms.to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Watched<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::{rate_limit::RateLimitError, timeout::TimeoutError, Middleware};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestConfigService {
+        counter: Mutex<usize>,
+        limit: usize,
+    }
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    impl Service<()> for TestConfigService {
+        type Response = usize;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            let mut counter_lock = self.counter.lock().unwrap();
+            *counter_lock += 1;
+            if *counter_lock <= self.limit {
+                Err(FakeError::Error)
+            } else {
+                Ok(*counter_lock)
+            }
+        }
+    }
+
+    const CONFIG: &str = r#"{
+        "rate_limit": { "limit": 5 },
+        "retry": { "count": 2, "wait_ms": 1 },
+        "timeout": { "duration_ms": 100 }
+    }"#;
+
+    #[tokio::test]
+    async fn config_test() {
+        let config: StackConfig = serde_json::from_str(CONFIG).unwrap();
+        assert_eq!(config.timeout.duration_ms, 100);
+
+        let service = |limit| TestConfigService {
+            counter: Mutex::new(0),
+            limit,
+        };
+
+        assert_eq!(
+            config.build::<3, 5, _, _>(service(0)).err(),
+            Some(ConfigError::RetryCountMismatch {
+                configured: 2,
+                compiled: 3
+            })
+        );
+        assert_eq!(
+            config.build::<2, 1, _, _>(service(0)).err(),
+            Some(ConfigError::RateLimitMismatch {
+                configured: 5,
+                compiled: 1
+            })
+        );
+
+        let stack = config.build::<2, 5, _, _>(service(2)).unwrap();
+        assert_eq!(stack.request(()).await.unwrap(), 3);
+
+        let stack = config.build::<2, 5, _, _>(service(3)).unwrap();
+        assert!(matches!(
+            stack.request(()).await,
+            Err(RateLimitError::ServiceError(TimeoutError::ServiceError(
+                FakeError::Error
+            )))
+        ));
+        assert_eq!(
+            *stack
+                .inner_service()
+                .inner_service()
+                .inner_service()
+                .counter
+                .lock()
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn watched_config_test() {
+        let config: StackConfig = serde_json::from_str(CONFIG).unwrap();
+        let (sender, receiver) = watch::channel(config.clone());
+
+        let stack = StackConfig::build_watched::<2, 5, _, _>(
+            receiver,
+            TestConfigService {
+                counter: Mutex::new(0),
+                limit: 100,
+            },
+        )
+        .unwrap();
+        let attempts = || {
+            *stack
+                .inner_service()
+                .inner_service()
+                .inner_service()
+                .inner_service()
+                .counter
+                .lock()
+                .unwrap()
+        };
+
+        assert!(stack.request(()).await.is_err());
+        assert_eq!(attempts(), 3);
+
+        let mut update = config.clone();
+        update.retry.count = 0;
+        update.clone().publish::<2>(&sender).unwrap();
+        assert_eq!(stack.config(), update);
+        assert!(stack.request(()).await.is_err());
+        assert_eq!(attempts(), 4);
+
+        // Rejected updates leave the config as it was
+        let mut update = config.clone();
+        update.rate_limit.limit = 1;
+        assert!(update.publish::<2>(&sender).is_err());
+        let mut update = config.clone();
+        update.retry.count = 3;
+        assert_eq!(
+            update.publish::<2>(&sender),
+            Err(ConfigError::RetryCountMismatch {
+                configured: 3,
+                compiled: 2
+            })
+        );
+        let mut update = config;
+        update.timeout.duration_ms = 0;
+        assert_eq!(update.publish::<2>(&sender), Err(ConfigError::ZeroTimeout));
+        assert_eq!(stack.config().retry.count, 0);
+    }
+
+    #[test]
+    fn config_validation_test() {
+        let mut config: StackConfig = serde_json::from_str(CONFIG).unwrap();
+        assert_eq!(config.budget_ms, None);
+        assert_eq!(config.worst_case_ms(), 302);
+        assert_eq!(config.validate(), Ok(()));
+
+        config.budget_ms = Some(300);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::BudgetExceeded {
+                worst_case_ms: 302,
+                budget_ms: 300
+            })
+        );
+
+        config.budget_ms = Some(302);
+        assert_eq!(config.validate(), Ok(()));
+
+        // Doesn't overflow
+        let mut huge = config.clone();
+        huge.retry.count = usize::MAX;
+        assert_eq!(huge.worst_case_ms(), u64::MAX);
+        assert!(huge.validate().is_err());
+
+        config.timeout.duration_ms = 0;
+        assert_eq!(
+            config
+                .build::<2, 5, _, _>(TestConfigService {
+                    counter: Mutex::new(0),
+                    limit: 0,
+                })
+                .err(),
+            Some(ConfigError::ZeroTimeout)
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Processing the messages of a message queue (Kafka, AMQP, SQS...) with a
+//! stack.
+//!
+//! [`Consumer`] takes the messages of a [`MessageQueue`] one at a time,
+//! passes each of them through the stack, and acknowledges the message once
+//! the stack succeeded. A failed message is negatively acknowledged instead,
+//! and requeued for another delivery if the error says so, e.g. with
+//! [`Consumer::classified`] only when another attempt can succeed.
+//!
+//! Retries and dead letters are the stack's business: with a `Retry`, the
+//! message is only nacked once every attempt failed, and a `DeadLetter`
+//! around it keeps the messages given up on. Messages are processed in the
+//! order they come, as partitions and most queues expect.
+//!
+//! Implementing [`MessageQueue`] for a client only takes its receive, ack
+//! and nack calls.
+
+use core::error::Error;
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+};
+
+use crate::{classify::Classify, Service};
+
+/// Client of a message queue, see the [module docs](self).
+#[allow(async_fn_in_trait)]
+pub trait MessageQueue {
+    type Message;
+    /// Identifies a delivered message when acknowledging it, e.g. a Kafka
+    /// offset or an AMQP delivery tag.
+    type Tag;
+    type Error: Error + 'static;
+
+    /// Waits for the next message, `None` once the queue is closed.
+    async fn receive(&mut self) -> Result<Option<Self::Message>, Self::Error>;
+
+    fn tag(message: &Self::Message) -> Self::Tag;
+
+    async fn ack(&mut self, tag: Self::Tag) -> Result<(), Self::Error>;
+
+    /// Gives the message back, to be delivered again if `requeue`.
+    async fn nack(&mut self, tag: Self::Tag, requeue: bool) -> Result<(), Self::Error>;
+}
+
+/// Counts of what a [`Consumer`] did with its messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsumerStats {
+    pub acked: u64,
+    pub requeued: u64,
+    /// Nacked without requeuing.
+    pub rejected: u64,
+}
+
+/// Feeds the messages of a queue to a stack, see the [module docs](self).
+pub struct Consumer<Q: MessageQueue, S: Service<Q::Message>> {
+    queue: Q,
+    service: S,
+    requeue: fn(&S::Error) -> bool,
+}
+
+impl<Q: MessageQueue, S: Service<Q::Message>> Consumer<Q, S> {
+    /// Failed messages are never requeued, unless configured otherwise.
+    pub fn new(queue: Q, service: S) -> Self {
+        Consumer {
+            queue,
+            service,
+            requeue: |_| false,
+        }
+    }
+
+    /// Requeues the messages that failed with an error for which `requeue`
+    /// is true.
+    pub fn requeue_if(self, requeue: fn(&S::Error) -> bool) -> Self {
+        Consumer { requeue, ..self }
+    }
+
+    /// Requeues the messages whose error is [retryable](crate::classify::Class::is_retryable).
+    pub fn classified(self) -> Self
+    where
+        S::Error: Classify,
+    {
+        self.requeue_if(|e| e.classify().is_retryable())
+    }
+
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+
+    /// Processes messages until the queue is closed, or fails to receive or
+    /// acknowledge one.
+    pub async fn run(&mut self) -> Result<ConsumerStats, Q::Error> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Like [`Consumer::run`], but also stops once `signal` resolves. The
+    /// message being processed at that point is still acknowledged.
+    pub async fn run_until<F: Future<Output = ()>>(
+        &mut self,
+        signal: F,
+    ) -> Result<ConsumerStats, Q::Error> {
+        let mut stats = ConsumerStats::default();
+        let mut signal = pin!(signal);
+
+        loop {
+            let message = {
+                let mut receive = pin!(self.queue.receive());
+                poll_fn(|cx| {
+                    if signal.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Ok(None));
+                    }
+                    receive.as_mut().poll(cx)
+                })
+                .await?
+            };
+            let Some(message) = message else {
+                return Ok(stats);
+            };
+
+            let tag = Q::tag(&message);
+            match self.service.request(message).await {
+                Ok(_) => {
+                    self.queue.ack(tag).await?;
+                    stats.acked += 1;
+                }
+                Err(e) if (self.requeue)(&e) => {
+                    self.queue.nack(tag, true).await?;
+                    stats.requeued += 1;
+                }
+                Err(_) => {
+                    self.queue.nack(tag, false).await?;
+                    stats.rejected += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use thiserror::Error;
+
+    use super::*;
+    use crate::classify::Class;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Transient,
+        #[error("")]
+        Permanent,
+    }
+
+    impl Classify for FakeError {
+        fn classify(&self) -> Class {
+            match self {
+                FakeError::Transient => Class::Transient,
+                FakeError::Permanent => Class::Permanent,
+            }
+        }
+    }
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    /// Messages are (tag, payload), requeued ones come back last
+    #[derive(Debug, Default)]
+    pub struct TestQueue {
+        messages: VecDeque<(u64, u64)>,
+        acked: Vec<u64>,
+        nacked: Vec<(u64, bool)>,
+    }
+
+    impl MessageQueue for TestQueue {
+        type Message = (u64, u64);
+        type Tag = u64;
+        type Error = EmptyError;
+
+        async fn receive(&mut self) -> Result<Option<Self::Message>, Self::Error> {
+            Ok(self.messages.pop_front())
+        }
+
+        fn tag(message: &Self::Message) -> u64 {
+            message.0
+        }
+
+        async fn ack(&mut self, tag: u64) -> Result<(), Self::Error> {
+            self.acked.push(tag);
+            Ok(())
+        }
+
+        async fn nack(&mut self, tag: u64, requeue: bool) -> Result<(), Self::Error> {
+            self.nacked.push((tag, requeue));
+            Ok(())
+        }
+    }
+
+    /// Fails on payloads 1 and 2, transiently for 1
+    #[derive(Debug)]
+    pub struct TestConsumerService {}
+
+    impl Service<(u64, u64)> for TestConsumerService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, (_, payload): (u64, u64)) -> Result<Self::Response, Self::Error> {
+            match payload {
+                1 => Err(FakeError::Transient),
+                2 => Err(FakeError::Permanent),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn consumer_test() {
+        let queue = TestQueue {
+            messages: (10..14).zip(0..4).collect(),
+            ..TestQueue::default()
+        };
+        let mut consumer = Consumer::new(queue, TestConsumerService {}).classified();
+
+        let stats = consumer.run().await.unwrap();
+        assert_eq!(
+            stats,
+            ConsumerStats {
+                acked: 2,
+                requeued: 1,
+                rejected: 1
+            }
+        );
+        assert_eq!(consumer.queue.acked, [10, 13]);
+        assert_eq!(consumer.queue.nacked, [(11, true), (12, false)]);
+    }
+
+    #[tokio::test]
+    async fn consumer_until_test() {
+        let queue = TestQueue {
+            messages: (10..14).zip(0..4).collect(),
+            ..TestQueue::default()
+        };
+        let mut consumer = Consumer::new(queue, TestConsumerService {});
+
+        // Signalled before the first message
+        let stats = consumer.run_until(async {}).await.unwrap();
+        assert_eq!(stats, ConsumerStats::default());
+        assert_eq!(consumer.queue.messages.len(), 4);
+    }
+}
@@ -0,0 +1,261 @@
+//! Keeping the requests a stack gave up on.
+//!
+//! Placed on top of a `Retry`, [`DeadLetter`] hands every request that
+//! still failed to a sink service (a channel, a file, a queue...) as a
+//! [`Letter`], with the errors of each attempt, so it can be inspected and
+//! replayed later. The caller still gets the error.
+
+use core::error::Error;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    describe::{Describe, FindLayer, Stack},
+    sync::{AtomicU64, Ordering},
+    Middleware, Service,
+};
+
+/// A request the stack gave up on.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Letter<R> {
+    pub request: R,
+    /// The error of each attempt, oldest first, with its sources.
+    pub errors: Vec<String>,
+}
+
+thread_local! {
+    static ATTEMPTS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Renders an error with its sources, e.g. `inner service failed: disk on fire`.
+fn render(err: &(dyn Error + 'static)) -> String {
+    crate::error::chain(err)
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Records the error of an attempt that will be retried, for the
+/// [`DeadLetter`] above, if any.
+#[cfg(feature = "retry")]
+pub(crate) fn record(err: &(dyn Error + 'static)) {
+    ATTEMPTS.with(|attempts| {
+        if let Some(attempts) = attempts.borrow_mut().as_mut() {
+            attempts.push(render(err));
+        }
+    });
+}
+
+/// Takes back the errors recorded for this request and restores the
+/// previous ones, even if polling panics.
+struct Enter<'a> {
+    attempts: &'a mut Option<Vec<String>>,
+    previous: Option<Vec<String>>,
+}
+
+impl<'a> Enter<'a> {
+    fn new(attempts: &'a mut Option<Vec<String>>) -> Self {
+        let previous = ATTEMPTS.with(|current| current.replace(attempts.take()));
+        Enter { attempts, previous }
+    }
+}
+
+impl Drop for Enter<'_> {
+    fn drop(&mut self) {
+        *self.attempts = ATTEMPTS.with(|current| current.replace(self.previous.take()));
+    }
+}
+
+/// Sends the requests failing with any error to `sink`, along with the
+/// errors of their previous attempts, before returning the error.
+///
+/// A sink failing loses the letter, which [`DeadLetter::undelivered`]
+/// counts; the caller gets the error of the request either way.
+pub struct DeadLetter<R, T: Service<R>, D> {
+    inner: T,
+    sink: D,
+    sent: AtomicU64,
+    undelivered: AtomicU64,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>, D: Service<Letter<R>>> DeadLetter<R, T, D> {
+    pub fn new(service: T, sink: D) -> Self {
+        DeadLetter {
+            inner: service,
+            sink,
+            sent: AtomicU64::new(0),
+            undelivered: AtomicU64::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn sink(&self) -> &D {
+        &self.sink
+    }
+
+    /// Letters the sink took.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Letters lost because the sink failed.
+    pub fn undelivered(&self) -> u64 {
+        self.undelivered.load(Ordering::Relaxed)
+    }
+}
+
+impl<R: Clone, T: Service<R>, D: Service<Letter<R>>> Service<R> for DeadLetter<R, T, D> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let mut attempts = Some(Vec::new());
+        let mut future = pin!(self.inner.request(msg.clone()));
+        let res = poll_fn(|cx| {
+            let _enter = Enter::new(&mut attempts);
+            future.as_mut().poll(cx)
+        })
+        .await;
+
+        let Err(err) = res else {
+            return res;
+        };
+
+        let mut errors = attempts.unwrap_or_default();
+        errors.push(render(&err));
+        let letter = Letter {
+            request: msg,
+            errors,
+        };
+        match self.sink.request(letter).await {
+            Ok(_) => self.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.undelivered.fetch_add(1, Ordering::Relaxed),
+        };
+        Err(err)
+    }
+}
+
+impl<R: Clone, T: Service<R>, D: Service<Letter<R>>> Middleware<R, T> for DeadLetter<R, T, D> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, D: Service<Letter<R>>> Describe for DeadLetter<R, T, D> {
+    fn name(&self) -> &'static str {
+        "dead_letter"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("sent", self.sent().to_string()),
+            ("undelivered", self.undelivered().to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, D: 'static> FindLayer for DeadLetter<R, T, D> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("attempt {0} failed")]
+        Error(u64),
+    }
+
+    /// Fails every request, numbering its attempts
+    #[derive(Debug, Default)]
+    pub struct TestFailingService {
+        attempts: AtomicU64,
+    }
+
+    impl Service<u64> for TestFailingService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            if msg == 0 {
+                return Ok(0);
+            }
+            Err(FakeError::Error(
+                self.attempts.fetch_add(1, Ordering::Relaxed),
+            ))
+        }
+    }
+
+    /// Keeps the letters it gets
+    #[derive(Debug, Default)]
+    pub struct TestSink {
+        letters: Arc<Mutex<Vec<Letter<u64>>>>,
+    }
+
+    impl Service<Letter<u64>> for TestSink {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: Letter<u64>) -> Result<Self::Response, Self::Error> {
+            self.letters.lock().unwrap().push(msg);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letter_test() {
+        let service = DeadLetter::new(TestFailingService::default(), TestSink::default());
+
+        assert_eq!(service.request(0).await, Ok(0));
+        assert_eq!(service.request(1).await, Err(FakeError::Error(0)));
+        assert_eq!(
+            *service.sink().letters.lock().unwrap(),
+            [Letter {
+                request: 1,
+                errors: vec!["attempt 0 failed".to_string()]
+            }]
+        );
+        assert_eq!(service.sent(), 1);
+    }
+
+    #[cfg(feature = "retry")]
+    #[tokio::test]
+    async fn dead_letter_retry_test() {
+        use crate::retry::Retry;
+
+        let service = DeadLetter::new(
+            Retry::<2, _, _>::instant(TestFailingService::default()),
+            TestSink::default(),
+        );
+
+        assert_eq!(service.request(7).await, Err(FakeError::Error(2)));
+        let letters = service.sink().letters.lock().unwrap();
+        assert_eq!(letters[0].request, 7);
+        assert_eq!(
+            letters[0].errors,
+            ["attempt 0 failed", "attempt 1 failed", "attempt 2 failed"]
+        );
+    }
+}
@@ -0,0 +1,394 @@
+//! Adapter exposing a [`deadpool`](::deadpool) managed pool as a service.
+//!
+//! Each request checks out an object of the pool, calls it, and
+//! returns it to the pool once the request is done.
+//!
+//! Deadpool only creates objects when they're checked out, so the first
+//! requests pay for establishing them. [`PooledService::warm_up`] creates
+//! them ahead of time, e.g. at startup before taking traffic.
+//!
+//! Objects are never closed either once the pool grew to absorb a peak.
+//! [`PooledService::reap`] closes the idle ones an [`IdlePolicy`] doesn't
+//! keep, and [`PooledService::reap_until`] does it in the background,
+//! topping the pool back up to its minimum size. [`PooledService::resize`]
+//! changes the max size of a pool that's in use.
+
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::{pin, Pin},
+    task::Poll,
+    time::Duration,
+};
+
+use ::deadpool::managed::{Manager, Pool, PoolError};
+use thiserror::Error;
+use tokio::{select, time::sleep};
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, StackStats},
+    error::IntoFlat,
+    Service,
+};
+
+/// Service calling the objects of a deadpool pool, which must be services themselves.
+pub struct PooledService<R, M: Manager> {
+    pool: Pool<M>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Error)]
+pub enum PooledError<PE: Error + 'static, SE: Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] SE),
+    #[error("could not check out from the pool")]
+    PoolError(#[source] PoolError<PE>),
+}
+
+impl<PE: Error, SE: Error + IntoFlat<F>, F> IntoFlat<F> for PooledError<PE, SE>
+where
+    PoolError<PE>: IntoFlat<F>,
+{
+    fn into_flat(self) -> F {
+        match self {
+            PooledError::ServiceError(e) => e.into_flat(),
+            PooledError::PoolError(e) => e.into_flat(),
+        }
+    }
+}
+
+/// Only timing out while waiting for an object is worth another try.
+impl<PE: Error, SE: Error + Classify> Classify for PooledError<PE, SE> {
+    fn classify(&self) -> Class {
+        match self {
+            PooledError::ServiceError(e) => e.classify(),
+            PooledError::PoolError(PoolError::Timeout(_)) => Class::Transient,
+            PooledError::PoolError(_) => Class::Permanent,
+        }
+    }
+}
+
+/// Which idle objects of a pool to keep, see [`PooledService::reap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdlePolicy {
+    /// Objects never closed, idle or not.
+    pub min_size: usize,
+    /// Most objects kept idle, the others being closed.
+    pub max_idle: usize,
+    /// Idle objects not used for that long are closed.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Keeps every object, like a pool without reaping.
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        IdlePolicy {
+            min_size: 0,
+            max_idle: usize::MAX,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl<R, M: Manager> PooledService<R, M> {
+    pub fn new(pool: Pool<M>) -> Self {
+        PooledService {
+            pool,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn pool(&self) -> &Pool<M> {
+        &self.pool
+    }
+
+    /// Creates objects until the pool holds `min_size` of them (at most its
+    /// max size), establishing up to `parallelism` at a time. Resolves once
+    /// they're all in the pool and ready to be checked out.
+    pub async fn warm_up(
+        &self,
+        min_size: usize,
+        parallelism: usize,
+    ) -> Result<(), PoolError<M::Error>> {
+        if self.pool.is_closed() {
+            return Err(PoolError::Closed);
+        }
+        let min_size = min_size.min(self.pool.status().max_size);
+        let parallelism = parallelism.max(1);
+
+        // Objects are held until the end, so that each checkout creates a
+        // new one instead of reusing the previous
+        let mut objects = Vec::with_capacity(min_size);
+        while objects.len() < min_size && self.pool.status().size < min_size {
+            let batch = parallelism.min(min_size - objects.len());
+            let mut checkouts: Vec<_> = (0..batch)
+                .map(|_| Some(Box::pin(self.pool.get())))
+                .collect();
+
+            poll_fn(|cx| {
+                let mut pending = false;
+                for checkout in checkouts.iter_mut() {
+                    let Some(future) = checkout else {
+                        continue;
+                    };
+                    match Pin::new(future).poll(cx) {
+                        Poll::Ready(res) => {
+                            *checkout = None;
+                            objects.push(res);
+                        }
+                        Poll::Pending => pending = true,
+                    }
+                }
+                if pending {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+
+            if let Some(pos) = objects.iter().position(Result::is_err) {
+                return objects.swap_remove(pos).map(|_| ());
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes how many objects the pool can hold. Shrinking it closes
+    /// idle objects right away, and the others once they're returned.
+    pub fn resize(&self, max_size: usize) {
+        self.pool.resize(max_size);
+    }
+
+    /// Closes the idle objects that `policy` doesn't keep, never going
+    /// below its min size, and returns how many were closed. Objects
+    /// checked out are left alone.
+    pub fn reap(&self, policy: IdlePolicy) -> usize {
+        let status = self.pool.status();
+        let (mut size, mut idle) = (status.size, status.available);
+        let reaped = self.pool.retain(|_, metrics| {
+            let expired = policy
+                .idle_timeout
+                .is_some_and(|timeout| metrics.last_used() >= timeout);
+            if size > policy.min_size && (expired || idle > policy.max_idle) {
+                size -= 1;
+                idle -= 1;
+                false
+            } else {
+                true
+            }
+        });
+        reaped.removed.len()
+    }
+
+    /// Reaps the pool every `every` following `policy`, and creates objects
+    /// back up to its min size, until `signal` resolves or the pool is
+    /// closed. Failing to create objects is retried on the next round.
+    pub async fn reap_until<F>(&self, policy: IdlePolicy, every: Duration, signal: F)
+    where
+        F: Future<Output = ()>,
+    {
+        let mut signal = pin!(signal);
+        loop {
+            self.reap(policy);
+            if let Err(PoolError::Closed) = self.warm_up(policy.min_size, 1).await {
+                return;
+            }
+
+            select! {
+                _ = sleep(every) => {}
+                _ = signal.as_mut() => return,
+            }
+        }
+    }
+}
+
+impl<R, M> Service<R> for PooledService<R, M>
+where
+    M: Manager,
+    M::Type: Service<R>,
+    M::Error: Error + 'static,
+{
+    type Response = <M::Type as Service<R>>::Response;
+    type Error = PooledError<M::Error, <M::Type as Service<R>>::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let object = self.pool.get().await.map_err(PooledError::PoolError)?;
+
+        object.request(msg).await.map_err(PooledError::ServiceError)
+    }
+}
+
+impl<R, M: Manager> Describe for PooledService<R, M> {
+    fn name(&self) -> &'static str {
+        "deadpool"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        let status = self.pool.status();
+        BTreeMap::from([
+            ("max_size", status.max_size.to_string()),
+            ("size", status.size.to_string()),
+            ("available", status.available.to_string()),
+        ])
+    }
+}
+
+impl<R: 'static, M: Manager + 'static> FindLayer for PooledService<R, M> {
+    fn record_stats(&self, stats: &mut StackStats) {
+        let status = self.pool.status();
+        StackStats::add(&mut stats.pool_size, status.size);
+        StackStats::add(&mut stats.pool_available, status.available);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use ::deadpool::managed::{Metrics, RecycleResult};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestPooledService {
+        id: usize,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestPooledService {
+        type Response = usize;
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(self.id)
+        }
+    }
+
+    pub struct TestManager {
+        counter: AtomicUsize,
+    }
+
+    impl Manager for TestManager {
+        type Type = TestPooledService;
+        type Error = EmptyError;
+
+        async fn create(&self) -> Result<Self::Type, Self::Error> {
+            Ok(TestPooledService {
+                id: self.counter.fetch_add(1, Ordering::SeqCst),
+            })
+        }
+
+        async fn recycle(&self, _obj: &mut Self::Type, _: &Metrics) -> RecycleResult<EmptyError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn warm_up_test() {
+        let manager = TestManager {
+            counter: AtomicUsize::new(0),
+        };
+        let pool = Pool::builder(manager).max_size(4).build().unwrap();
+        let service = PooledService::<(), _>::new(pool);
+
+        service.warm_up(3, 2).await.unwrap();
+        let status = service.pool().status();
+        assert_eq!((status.size, status.available), (3, 3));
+
+        // Capped to the max size, and only creates what's missing
+        service.warm_up(10, 2).await.unwrap();
+        assert_eq!(service.pool().status().size, 4);
+        service.warm_up(2, 2).await.unwrap();
+        assert_eq!(service.pool().status().size, 4);
+    }
+
+    #[tokio::test]
+    async fn reap_test() {
+        let manager = TestManager {
+            counter: AtomicUsize::new(0),
+        };
+        let pool = Pool::builder(manager).max_size(6).build().unwrap();
+        let service = PooledService::<(), _>::new(pool);
+        service.warm_up(6, 6).await.unwrap();
+
+        // Checked out objects are left alone
+        let object = service.pool().get().await.unwrap();
+        let policy = IdlePolicy {
+            min_size: 2,
+            max_idle: 3,
+            ..IdlePolicy::default()
+        };
+        assert_eq!(service.reap(policy), 2);
+        assert_eq!(service.pool().status().available, 3);
+
+        // Never below the min size, even if they're all expired
+        let policy = IdlePolicy {
+            idle_timeout: Some(Duration::ZERO),
+            ..policy
+        };
+        assert_eq!(service.reap(policy), 2);
+        drop(object);
+        assert_eq!(service.pool().status().size, 2);
+
+        service.resize(1);
+        assert_eq!(service.pool().status().size, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reap_until_test() {
+        let manager = TestManager {
+            counter: AtomicUsize::new(0),
+        };
+        let pool = Pool::builder(manager).max_size(4).build().unwrap();
+        let service = PooledService::<(), _>::new(pool);
+        let policy = IdlePolicy {
+            min_size: 2,
+            max_idle: 2,
+            ..IdlePolicy::default()
+        };
+
+        let shutdown = crate::shutdown::Shutdown::new();
+        let reaper = service.reap_until(policy, Duration::from_secs(10), shutdown.signal());
+        let checks = async {
+            // Filled up to the min size right away
+            tokio::task::yield_now().await;
+            assert_eq!(service.pool().status().size, 2);
+
+            service.warm_up(4, 4).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(11)).await;
+            assert_eq!(service.pool().status().size, 2);
+            shutdown.trigger();
+        };
+        tokio::join!(reaper, checks);
+    }
+
+    #[tokio::test]
+    async fn pooled_test() {
+        let manager = TestManager {
+            counter: AtomicUsize::new(0),
+        };
+        let pool = Pool::builder(manager).max_size(1).build().unwrap();
+        let service = PooledService::new(pool);
+
+        // The only object of the pool is reused
+        assert_eq!(service.request(()).await.unwrap(), 0);
+        assert_eq!(service.request(()).await.unwrap(), 0);
+        assert_eq!(service.pool().status().size, 1);
+
+        service.pool().close();
+        assert!(matches!(
+            service.warm_up(1, 1).await,
+            Err(PoolError::Closed)
+        ));
+        assert!(matches!(
+            service.request(()).await,
+            Err(PooledError::PoolError(PoolError::Closed))
+        ));
+    }
+}
@@ -0,0 +1,369 @@
+//! Deduplication of requests delivered more than once.
+//!
+//! Producers with at-least-once delivery (message queues, webhooks...)
+//! sometimes send the same message twice, seconds or minutes apart.
+//! [`Dedup`] hashes the content of each request and rejects the requests
+//! whose hash it saw within a window, whether the original is still being
+//! processed or was processed a while ago. With [`Dedup::replaying`], the
+//! duplicates of a processed request get its response again instead.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+    sync::PoisonError,
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack, StackStats},
+    error::{FromDuplicate, IntoFlat},
+    events::{self, StackEvent},
+    load::{Admission, Admit},
+    sync::{AtomicU64, Mutex, Ordering},
+    Middleware, Service,
+};
+
+/// Rejects the requests with the same content hash as a request processed
+/// within the window, or still being processed.
+///
+/// Only successful requests count as processed: after a failure, the same
+/// request can go through again. The window starts when the response comes.
+pub struct Dedup<R, T: Service<R>> {
+    inner: T,
+    window: Duration,
+    hash: fn(&R) -> u64,
+    replay: Option<Replay<T::Response>>,
+    seen: Mutex<Seen<T::Response>>,
+    duplicates: AtomicU64,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// Copies a response for a duplicate.
+type Replay<Resp> = fn(&Resp) -> Resp;
+
+struct Seen<Resp> {
+    entries: HashMap<u64, Entry<Resp>>,
+    /// Processed requests, oldest first.
+    expiring: VecDeque<(Instant, u64)>,
+}
+
+enum Entry<Resp> {
+    InFlight,
+    /// The response is only kept when replaying.
+    Done {
+        at: Instant,
+        response: Option<Resp>,
+    },
+}
+
+impl<Resp> Seen<Resp> {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(&(at, hash)) = self.expiring.front() {
+            if now.saturating_duration_since(at) < window {
+                break;
+            }
+            self.expiring.pop_front();
+            if matches!(self.entries.get(&hash), Some(Entry::Done { at: done, .. }) if *done == at)
+            {
+                self.entries.remove(&hash);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum DedupError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("duplicate of a request already processed")]
+    Duplicate,
+}
+
+impl<E: core::error::Error> DedupError<E> {
+    #[inline]
+    pub fn is_duplicate(&self) -> bool {
+        matches!(self, DedupError::Duplicate)
+    }
+}
+
+impl<E: core::error::Error + IntoFlat<F>, F: FromDuplicate> IntoFlat<F> for DedupError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            DedupError::ServiceError(e) => e.into_flat(),
+            DedupError::Duplicate => F::duplicate(),
+        }
+    }
+}
+
+/// Sending it again within the window gets the same answer.
+impl<E: core::error::Error + Classify> Classify for DedupError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            DedupError::ServiceError(e) => e.classify(),
+            DedupError::Duplicate => Class::Permanent,
+        }
+    }
+}
+
+/// Forgets a request that didn't succeed when dropped, so it can be sent
+/// again.
+struct Processing<'a, Resp> {
+    seen: &'a Mutex<Seen<Resp>>,
+    hash: u64,
+}
+
+impl<Resp> Processing<'_, Resp> {
+    fn done(self, response: Option<Resp>) {
+        let at = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.entries.insert(self.hash, Entry::Done { at, response });
+        seen.expiring.push_back((at, self.hash));
+    }
+}
+
+impl<Resp> Drop for Processing<'_, Resp> {
+    fn drop(&mut self) {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if matches!(seen.entries.get(&self.hash), Some(Entry::InFlight)) {
+            seen.entries.remove(&self.hash);
+        }
+    }
+}
+
+fn hash_content<R: Hash>(msg: &R) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    msg.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<R, T: Service<R>> Dedup<R, T> {
+    /// Deduplicates the requests hashing to the same [`Hash`] within
+    /// `window`.
+    pub fn new(service: T, window: Duration) -> Self
+    where
+        R: Hash,
+    {
+        Self::by_hash(service, window, hash_content::<R>)
+    }
+
+    /// Like [`Dedup::new`], with the content hash computed by `hash`, e.g.
+    /// from a digest the producer sends along the message.
+    pub fn by_hash(service: T, window: Duration, hash: fn(&R) -> u64) -> Self {
+        Dedup {
+            inner: service,
+            window,
+            hash,
+            replay: None,
+            seen: Mutex::new(Seen {
+                entries: HashMap::new(),
+                expiring: VecDeque::new(),
+            }),
+            duplicates: AtomicU64::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Responds to the duplicates of a processed request with its response,
+    /// instead of [`DedupError::Duplicate`]. The duplicates of a request
+    /// still being processed are rejected all the same.
+    pub fn replaying(self) -> Self
+    where
+        T::Response: Clone,
+    {
+        Dedup {
+            replay: Some(Clone::clone),
+            ..self
+        }
+    }
+
+    /// Duplicates rejected or replayed so far.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for Dedup<R, T> {
+    type Response = T::Response;
+    type Error = DedupError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        if crate::meta::current().is_some_and(|meta| meta.overrides.skip_dedup) {
+            return self
+                .inner
+                .request(msg)
+                .await
+                .map_err(DedupError::ServiceError);
+        }
+
+        let hash = (self.hash)(&msg);
+        {
+            let mut seen = self.seen.lock().unwrap();
+            seen.expire(Instant::now(), self.window);
+            match (seen.entries.get(&hash), self.replay) {
+                (None, _) => {
+                    seen.entries.insert(hash, Entry::InFlight);
+                }
+                (
+                    Some(Entry::Done {
+                        response: Some(response),
+                        ..
+                    }),
+                    Some(replay),
+                ) => {
+                    self.duplicates.fetch_add(1, Ordering::Relaxed);
+                    events::emit(StackEvent::ResponseReplayed);
+                    return Ok(replay(response));
+                }
+                (Some(_), _) => {
+                    self.duplicates.fetch_add(1, Ordering::Relaxed);
+                    events::emit(StackEvent::DuplicateRejected);
+                    return Err(DedupError::Duplicate);
+                }
+            }
+        }
+        let processing = Processing {
+            seen: &self.seen,
+            hash,
+        };
+
+        let res = self.inner.request(msg).await;
+        if let Ok(response) = &res {
+            processing.done(self.replay.map(|replay| replay(response)));
+        }
+        res.map_err(DedupError::ServiceError)
+    }
+}
+
+/// Only duplicates that would be rejected are, not the replayed ones.
+impl<R, T: Service<R>> Admit<R> for Dedup<R, T> {
+    fn try_request(&self, msg: &R) -> Admission {
+        if crate::meta::current().is_some_and(|meta| meta.overrides.skip_dedup) {
+            return Admission::Admitted;
+        }
+
+        let hash = (self.hash)(msg);
+        let mut seen = self.seen.lock().unwrap();
+        seen.expire(Instant::now(), self.window);
+        match (seen.entries.get(&hash), self.replay) {
+            (None, _)
+            | (
+                Some(Entry::Done {
+                    response: Some(_), ..
+                }),
+                Some(_),
+            ) => Admission::Admitted,
+            (Some(_), _) => Admission::Rejected { retry_after: None },
+        }
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for Dedup<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for Dedup<R, T> {
+    fn name(&self) -> &'static str {
+        "dedup"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("window_ms", self.window.as_millis().to_string()),
+            ("replaying", self.replay.is_some().to_string()),
+            ("duplicates", self.duplicates().to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Dedup<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+
+    fn record_stats(&self, stats: &mut StackStats) {
+        StackStats::add(&mut stats.duplicates, self.duplicates());
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::{join, time::advance};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    /// Counts the requests it processed, failing the odd ones
+    #[derive(Debug, Default)]
+    pub struct TestDedupService {
+        processed: AtomicU64,
+    }
+
+    impl Service<u64> for TestDedupService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let processed = self.processed.fetch_add(1, Ordering::Relaxed) + 1;
+            if msg % 2 == 1 {
+                Err(FakeError::Error)
+            } else {
+                Ok(processed)
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_test() {
+        let service = Dedup::new(TestDedupService::default(), Duration::from_secs(60));
+
+        // Still in flight, or already processed
+        let (a, b) = join!(service.request(2), service.request(2));
+        assert_eq!((a, b), (Ok(1), Err(DedupError::Duplicate)));
+        assert_eq!(service.request(2).await, Err(DedupError::Duplicate));
+        assert_eq!(service.request(4).await, Ok(2));
+        assert_eq!(service.duplicates(), 2);
+        assert!(!service.try_request(&2).is_admitted());
+        assert!(service.try_request(&3).is_admitted());
+
+        // Failures don't count as processed
+        assert_eq!(
+            service.request(1).await,
+            Err(DedupError::ServiceError(FakeError::Error))
+        );
+        assert_eq!(
+            service.request(1).await,
+            Err(DedupError::ServiceError(FakeError::Error))
+        );
+
+        // Forgotten after the window
+        advance(Duration::from_secs(60)).await;
+        assert_eq!(service.request(2).await, Ok(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_replaying_test() {
+        let service = Dedup::new(TestDedupService::default(), Duration::from_secs(60)).replaying();
+
+        assert_eq!(service.request(2).await, Ok(1));
+        assert_eq!(service.request(2).await, Ok(1));
+        assert_eq!(service.inner_service().processed.load(Ordering::Relaxed), 1);
+        assert_eq!(service.duplicates(), 1);
+    }
+}
@@ -0,0 +1,326 @@
+//! Introspection of built stacks.
+//!
+//! Every built-in middleware implements [`Describe`], giving its name, its
+//! parameters and live state, like the requests in flight of a rate limiter.
+//! [`Describe::describe`] walks down the stack through
+//! [`Middleware::inner_service`](crate::Middleware::inner_service) to list
+//! every layer, outermost first, which makes a mis-ordered stack obvious:
+//!
+//! ```text
+//! rate_limit(in_flight=0, limit=10) -> retry(count=3) -> timeout(duration_ms=500) -> my_app::Db
+//! ```
+//!
+//! Services outside of jenga only need an empty `impl Describe for MyService {}`
+//! to show up under their type name.
+//!
+//! [`FindLayer`] walks the same way to hand out a reference to a given layer
+//! of an already-built stack, e.g. to read the requests in flight of its rate
+//! limiter without keeping a separate handle around. It also collects the
+//! counters of every layer into one [`StackStats`] snapshot, e.g. for a debug
+//! endpoint.
+
+use std::{any::Any, collections::BTreeMap, fmt};
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// One layer of a stack.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Layer {
+    pub name: &'static str,
+    pub params: BTreeMap<&'static str, String>,
+}
+
+/// Every layer of a stack, outermost first.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Stack {
+    pub layers: Vec<Layer>,
+}
+
+pub trait Describe {
+    /// Name of the layer. Defaults to the type name.
+    fn name(&self) -> &'static str {
+        core::any::type_name::<Self>()
+    }
+
+    /// Current configuration and live state of the layer.
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::new()
+    }
+
+    fn layer(&self) -> Layer {
+        Layer {
+            name: self.name(),
+            params: self.params(),
+        }
+    }
+
+    /// This layer and every layer below it. Middlewares append the
+    /// description of their inner service to their own layer.
+    fn describe(&self) -> Stack {
+        Stack {
+            layers: vec![self.layer()],
+        }
+    }
+}
+
+/// Counters of the layers of a stack, see [`FindLayer::stack_stats`].
+///
+/// A counter is `None` when no layer of the stack keeps it, and adds up the
+/// layers keeping it otherwise. The requests in flight are the outermost
+/// layer's that counts them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct StackStats {
+    pub in_flight: Option<usize>,
+    /// Retries made, not counting the first attempts.
+    pub retries: Option<u64>,
+    /// Requests that still failed after every retry.
+    pub retries_exhausted: Option<u64>,
+    pub restarts: Option<u64>,
+    pub failed_restarts: Option<u64>,
+    /// Requests rejected by `DeadlineShed`.
+    pub shed: Option<u64>,
+    /// Requests deduplicated by `Dedup`, rejected or replayed.
+    pub duplicates: Option<u64>,
+    /// Objects in pools, checked out or not.
+    pub pool_size: Option<usize>,
+    /// Objects waiting in pools to be checked out.
+    pub pool_available: Option<usize>,
+}
+
+impl StackStats {
+    /// Adds `n` to a counter, which is then kept by the stack.
+    #[allow(dead_code)]
+    pub(crate) fn add<N: core::ops::Add<Output = N> + Default>(counter: &mut Option<N>, n: N) {
+        *counter = Some(counter.take().unwrap_or_default() + n);
+    }
+}
+
+/// Lookup of a layer by type within a stack.
+///
+/// Like [`Describe`], services outside of jenga only need an empty
+/// `impl FindLayer for MyService {}`. Stacks must be `'static`.
+pub trait FindLayer: Any {
+    /// The layer right below this one, if it can be borrowed.
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        None
+    }
+
+    /// Adds the counters of this layer, not the ones below, to `stats`.
+    fn record_stats(&self, _stats: &mut StackStats) {}
+
+    /// The counters of every layer, from this one down.
+    fn stack_stats(&self) -> StackStats
+    where
+        Self: Sized,
+    {
+        let mut stats = StackStats::default();
+        record_stack_stats(self, &mut stats);
+        stats
+    }
+
+    /// Returns the outermost layer of type `L`, this one included.
+    fn find_layer<L: Any>(&self) -> Option<&L>
+    where
+        Self: Sized,
+    {
+        let mut current: &dyn FindLayer = self;
+        loop {
+            if let Some(layer) = (current as &dyn Any).downcast_ref::<L>() {
+                return Some(layer);
+            }
+            current = current.inner_layer()?;
+        }
+    }
+}
+
+/// Adds the counters of `layer` and every layer below it to `stats`.
+fn record_stack_stats(layer: &dyn FindLayer, stats: &mut StackStats) {
+    let mut current = Some(layer);
+    while let Some(layer) = current {
+        layer.record_stats(stats);
+        current = layer.inner_layer();
+    }
+}
+
+/// Pointers to a service describe it as if it was there, and are skipped
+/// over when looking for a layer.
+macro_rules! deref_describe {
+    ($($pointer:ty),+) => {
+        $(
+            impl<S: Describe + ?Sized> Describe for $pointer {
+                fn name(&self) -> &'static str {
+                    (**self).name()
+                }
+
+                fn params(&self) -> BTreeMap<&'static str, String> {
+                    (**self).params()
+                }
+
+                fn describe(&self) -> Stack {
+                    (**self).describe()
+                }
+            }
+        )+
+    };
+}
+
+deref_describe!(&S, Box<S>, std::rc::Rc<S>, std::sync::Arc<S>);
+
+macro_rules! deref_find_layer {
+    ($($pointer:ty),+) => {
+        $(
+            impl<S: FindLayer> FindLayer for $pointer {
+                fn inner_layer(&self) -> Option<&dyn FindLayer> {
+                    Some(&**self)
+                }
+            }
+        )+
+    };
+}
+
+deref_find_layer!(&'static S, Box<S>, std::rc::Rc<S>, std::sync::Arc<S>);
+
+impl Stack {
+    /// Describes `layer` on top of `inner`.
+    pub fn on_top_of(layer: Layer, inner: Stack) -> Stack {
+        let mut layers = Vec::with_capacity(inner.layers.len() + 1);
+        layers.push(layer);
+        layers.extend(inner.layers);
+        Stack { layers }
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)?;
+        if !self.params.is_empty() {
+            let params: Vec<_> = self
+                .params
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            write!(f, "({})", params.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" -> ")?;
+            }
+            write!(f, "{layer}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "rate_limit", feature = "retry", feature = "timeout"))]
+mod tests {
+    use std::time::Duration;
+
+    use thiserror::Error;
+
+    use super::*;
+    use crate::{rate_limit::RateLimit, retry::Retry, timeout::Timeout, Service};
+
+    pub struct TestDescribeService {}
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestDescribeService {
+        type Response = ();
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl Describe for TestDescribeService {
+        fn name(&self) -> &'static str {
+            "db"
+        }
+    }
+
+    impl FindLayer for TestDescribeService {}
+
+    #[test]
+    fn describe_stack() {
+        let stack = RateLimit::<10, (), _>::new(Retry::<3, _, _>::instant(Timeout::new(
+            TestDescribeService {},
+            Duration::from_millis(500),
+        )));
+
+        let description = stack.describe();
+        assert_eq!(description.layers.len(), 4);
+        assert_eq!(description.layers[0].params["in_flight"], "0");
+        assert!(description
+            .to_string()
+            .starts_with("rate_limit(in_flight=0, limit=10) -> retry(count=3"));
+        assert!(description
+            .to_string()
+            .ends_with("-> timeout(duration_ms=500) -> db"));
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::to_value(&description).unwrap()[2],
+            serde_json::json!({ "name": "timeout", "params": { "duration_ms": "500" } })
+        );
+    }
+
+    #[test]
+    fn find_layer() {
+        type Limiter = RateLimit<10, (), Timeout<(), TestDescribeService>>;
+
+        let stack = Retry::<3, (), _>::instant(Limiter::new(Timeout::new(
+            TestDescribeService {},
+            Duration::from_millis(500),
+        )));
+
+        assert_eq!(stack.find_layer::<Limiter>().unwrap().in_flight(), 0);
+        assert!(stack.find_layer::<TestDescribeService>().is_some());
+        assert!(stack
+            .find_layer::<RateLimit<5, (), TestDescribeService>>()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stack_stats() {
+        let stack = Retry::<3, (), _>::instant(RateLimit::<10, (), _>::new(Timeout::new(
+            TestDescribeService {},
+            Duration::from_millis(500),
+        )));
+        assert_eq!(stack.request(()).await.ok(), Some(()));
+
+        let stats = stack.stack_stats();
+        assert_eq!(stats.in_flight, Some(0));
+        assert_eq!((stats.retries, stats.retries_exhausted), (Some(0), Some(0)));
+        // No layer below keeps these
+        assert_eq!((stats.shed, stats.pool_size), (None, None));
+    }
+
+    #[tokio::test]
+    async fn shared_stack() {
+        type Limiter = RateLimit<10, (), TestDescribeService>;
+
+        let stack = std::sync::Arc::new(Limiter::new(TestDescribeService {}));
+        let shared = stack.clone();
+        tokio::spawn(async move { shared.request(()).await.unwrap() })
+            .await
+            .unwrap();
+
+        // The pointer is invisible in the description of the stack
+        assert_eq!(stack.describe(), (*stack).describe());
+        assert!(stack.find_layer::<Limiter>().is_some());
+    }
+}
@@ -0,0 +1,158 @@
+//! One of two services, for layers applied depending on the configuration.
+//!
+//! A stack with a timeout and the same stack without one are two different
+//! types, which makes "timeout on/off" awkward to build. [`optional`] wraps a
+//! service in a layer only if enabled, and returns an [`Either`] of the two,
+//! a single type either way:
+//!
+//! ```ignore
+//! let stack = optional(config.timeout.is_some(), service, |service| {
+//!     Timeout::new(service, config.timeout.unwrap())
+//! });
+//! ```
+
+use core::error::Error;
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::IntoFlat,
+    Service,
+};
+
+/// A service of one of two types, both taking the same requests and giving
+/// the same responses.
+#[derive(Debug, Clone)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// The error of an [`Either`], from whichever service it was.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum EitherError<A: Error, B: Error> {
+    #[error(transparent)]
+    Left(A),
+    #[error(transparent)]
+    Right(B),
+}
+
+impl<A: Error + IntoFlat<F>, B: Error + IntoFlat<F>, F> IntoFlat<F> for EitherError<A, B> {
+    fn into_flat(self) -> F {
+        match self {
+            EitherError::Left(e) => e.into_flat(),
+            EitherError::Right(e) => e.into_flat(),
+        }
+    }
+}
+
+impl<A: Error + Classify, B: Error + Classify> Classify for EitherError<A, B> {
+    fn classify(&self) -> Class {
+        match self {
+            EitherError::Left(e) => e.classify(),
+            EitherError::Right(e) => e.classify(),
+        }
+    }
+}
+
+/// Wraps `service` with `layer` if `enabled`, leaves it as it is otherwise.
+pub fn optional<S, L>(enabled: bool, service: S, layer: impl FnOnce(S) -> L) -> Either<L, S> {
+    if enabled {
+        Either::Left(layer(service))
+    } else {
+        Either::Right(service)
+    }
+}
+
+impl<R, A, B> Service<R> for Either<A, B>
+where
+    A: Service<R>,
+    B: Service<R, Response = A::Response>,
+{
+    type Response = A::Response;
+    type Error = EitherError<A::Error, B::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        match self {
+            Either::Left(service) => service.request(msg).await.map_err(EitherError::Left),
+            Either::Right(service) => service.request(msg).await.map_err(EitherError::Right),
+        }
+    }
+}
+
+/// Describes itself as whichever service it is.
+impl<A: Describe, B: Describe> Describe for Either<A, B> {
+    fn name(&self) -> &'static str {
+        match self {
+            Either::Left(service) => service.name(),
+            Either::Right(service) => service.name(),
+        }
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        match self {
+            Either::Left(service) => service.params(),
+            Either::Right(service) => service.params(),
+        }
+    }
+
+    fn describe(&self) -> Stack {
+        match self {
+            Either::Left(service) => service.describe(),
+            Either::Right(service) => service.describe(),
+        }
+    }
+}
+
+impl<A: FindLayer, B: FindLayer> FindLayer for Either<A, B> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        match self {
+            Either::Left(service) => Some(service),
+            Either::Right(service) => Some(service),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combinators::ServiceExt;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    #[derive(Debug)]
+    pub struct TestFailingService {}
+
+    impl Service<u64> for TestFailingService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: u64) -> Result<Self::Response, Self::Error> {
+            Err(FakeError::Error)
+        }
+    }
+
+    #[tokio::test]
+    async fn optional_test() {
+        let recovered = |enabled| {
+            optional(enabled, TestFailingService {}, |service| {
+                service.recover(|_| Ok(0))
+            })
+        };
+
+        // Both are the same type
+        let stacks = [recovered(true), recovered(false)];
+        assert_eq!(stacks[0].request(1).await, Ok(0));
+        assert_eq!(
+            stacks[1].request(1).await,
+            Err(EitherError::Right(FakeError::Error))
+        );
+    }
+}
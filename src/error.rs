@@ -0,0 +1,170 @@
+//! Helpers to walk the [`source()`](core::error::Error::source) chain of
+//! middleware errors.
+//!
+//! Every built-in middleware error points its `source()` at the error it
+//! wraps, so error reporters (anyhow, eyre...) show the whole stack, and a
+//! given layer's error can be found without matching every layer above it.
+
+use core::error::Error;
+
+/// Derives the `From` impls turning a whole stack's nested error into a
+/// flat application error, see [`IntoFlat`].
+#[cfg(feature = "derive")]
+pub use jenga_derive::FlatError;
+
+/// Conversion of a middleware error, and everything it wraps, into the
+/// flat error `F`.
+///
+/// Each middleware error converts itself with the `From*` hooks of `F`
+/// and defers to its inner error otherwise, all the way down to the
+/// innermost service's error. [`FlatError`] implements all of these
+/// for an enum.
+pub trait IntoFlat<F> {
+    fn into_flat(self) -> F;
+}
+
+/// Flat errors that can stand for a timed out request.
+pub trait FromTimeout {
+    fn timed_out() -> Self;
+}
+
+/// Flat errors that can stand for a rate limited request.
+pub trait FromRateLimited {
+    fn rate_limited() -> Self;
+}
+
+/// Flat errors that can stand for a closed channel or worker.
+pub trait FromClosed {
+    fn closed() -> Self;
+}
+
+/// Flat errors that can stand for a duplicate request.
+pub trait FromDuplicate {
+    fn duplicate() -> Self;
+}
+
+/// Iterates over `err` and its sources, outermost first.
+pub fn chain<'a>(
+    err: &'a (dyn Error + 'static),
+) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    let mut next = Some(err);
+    core::iter::from_fn(move || {
+        let current = next?;
+        next = current.source();
+        Some(current)
+    })
+}
+
+/// Returns the innermost error of the chain, the one that has no source.
+pub fn root_cause<'a>(err: &'a (dyn Error + 'static)) -> &'a (dyn Error + 'static) {
+    chain(err).last().unwrap_or(err)
+}
+
+/// Returns the outermost error of type `E` in the chain, if any.
+pub fn find_cause<'a, E: Error + 'static>(err: &'a (dyn Error + 'static)) -> Option<&'a E> {
+    chain(err).find_map(|e| e.downcast_ref::<E>())
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    #[error("disk on fire")]
+    pub struct RootError;
+
+    #[derive(Debug, Error)]
+    pub enum LayerError<E: Error> {
+        #[error("inner service failed")]
+        ServiceError(#[source] E),
+        #[error("layer gave up")]
+        GaveUp,
+    }
+
+    #[test]
+    fn walks_the_chain() {
+        let err = LayerError::ServiceError(LayerError::ServiceError(RootError));
+
+        assert_eq!(chain(&err).count(), 3);
+        assert_eq!(root_cause(&err).to_string(), "disk on fire");
+        assert_eq!(find_cause::<RootError>(&err), Some(&RootError));
+        assert!(find_cause::<LayerError<RootError>>(&err).is_some());
+
+        let err = LayerError::<RootError>::GaveUp;
+        assert_eq!(root_cause(&err).to_string(), "layer gave up");
+        assert_eq!(find_cause::<RootError>(&err), None);
+    }
+
+    #[cfg(all(feature = "rate_limit", feature = "timeout"))]
+    #[test]
+    fn middleware_errors_chain() {
+        use crate::{rate_limit::RateLimitError, timeout::TimeoutError};
+
+        let err = TimeoutError::ServiceError(RateLimitError::ServiceError(RootError));
+        assert_eq!(find_cause::<RootError>(&err), Some(&RootError));
+
+        let err =
+            TimeoutError::<RateLimitError<RootError>>::ServiceError(RateLimitError::RateLimited);
+        assert!(find_cause::<RateLimitError<RootError>>(&err)
+            .unwrap()
+            .is_rate_limited());
+        assert_eq!(root_cause(&err).to_string(), "rate limited");
+    }
+
+    #[cfg(all(feature = "derive", feature = "rate_limit", feature = "timeout"))]
+    #[tokio::test]
+    async fn flat_error_derive() {
+        use std::time::Duration;
+
+        use crate::{
+            rate_limit::{RateLimit, RateLimitError},
+            timeout::{Timeout, TimeoutError},
+            Service,
+        };
+
+        type StackError = TimeoutError<RateLimitError<RootError>>;
+
+        #[derive(Debug, PartialEq, Error, FlatError)]
+        #[jenga(from(StackError))]
+        pub enum AppError {
+            #[error("timed out")]
+            #[jenga(timeout)]
+            Timeout,
+            #[error("rate limited")]
+            #[jenga(rate_limited)]
+            RateLimited,
+            #[error(transparent)]
+            #[jenga(service)]
+            Root(RootError),
+        }
+
+        pub struct FailingService {}
+
+        impl Service<()> for FailingService {
+            type Response = ();
+            type Error = RootError;
+
+            async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+                Err(RootError)
+            }
+        }
+
+        async fn call() -> Result<(), AppError> {
+            let stack = Timeout::new(
+                RateLimit::<1, _, _>::new(FailingService {}),
+                Duration::from_secs(1),
+            );
+            stack.request(()).await?;
+            Ok(())
+        }
+
+        assert_eq!(call().await, Err(AppError::Root(RootError)));
+        assert_eq!(AppError::from(StackError::TimeoutError), AppError::Timeout);
+        assert_eq!(
+            AppError::from(StackError::ServiceError(RateLimitError::RateLimited)),
+            AppError::RateLimited
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! One place to see what happens inside a stack.
+//!
+//! The built-in middlewares report what they do beyond forwarding requests
+//! (retrying, timing out, rejecting...) as [`StackEvent`]s. An [`Events`]
+//! layer on top of a stack hands the events of every layer below it to a
+//! single [`Subscriber`], for logs, metrics or tests, instead of hooking
+//! each layer separately.
+//!
+//! Events are only seen while the request's future is polled, like
+//! [metadata](crate::meta): a request handed over to a channel worker
+//! reports to the [`Events`] layers of the worker's stack, if any. With
+//! nested layers, the closest one above the middleware gets the event.
+
+use std::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    describe::{Describe, FindLayer, Stack},
+    Middleware, Service,
+};
+
+/// Something a middleware did while processing a request.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum StackEvent {
+    /// `Retry` is making another attempt, the first retry being number 1.
+    RetryAttempted { attempt: usize },
+    /// `Retry` used all of its retries and still failed.
+    RetriesExhausted,
+    /// `Timeout` stopped waiting for the inner service.
+    TimedOut { after: Duration },
+    /// `Restart` replaced its service, or failed to.
+    Restarted { succeeded: bool },
+    /// `DeadlineShed` rejected a request that couldn't finish in time.
+    Shed,
+    /// `RateLimit` rejected a request.
+    RateLimited,
+    /// `ConcurrencyLimit` rejected a request.
+    ConcurrencyLimited,
+    /// `FixedWindow` rejected a request until its window resets.
+    QuotaExhausted { resets_in: Duration },
+    /// `Dedup` rejected a duplicate request.
+    DuplicateRejected,
+    /// `Dedup` answered a duplicate request with the response it kept.
+    ResponseReplayed,
+}
+
+pub trait Subscriber {
+    fn on_event(&self, event: &StackEvent);
+}
+
+impl<F: Fn(&StackEvent)> Subscriber for F {
+    fn on_event(&self, event: &StackEvent) {
+        self(event)
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<dyn Subscriber>>> = const { RefCell::new(None) };
+}
+
+/// Reports `event` to the [`Events`] layer above, if any.
+#[allow(dead_code)]
+pub(crate) fn emit(event: StackEvent) {
+    // Cloned out, so the subscriber can make requests of its own
+    let subscriber = CURRENT.with(|current| current.borrow().clone());
+    if let Some(subscriber) = subscriber {
+        subscriber.on_event(&event);
+    }
+}
+
+/// Restores the previous subscriber, even if polling panics.
+struct Enter {
+    previous: Option<Arc<dyn Subscriber>>,
+}
+
+impl Enter {
+    fn new(subscriber: Arc<dyn Subscriber>) -> Self {
+        let previous = CURRENT.with(|current| current.replace(Some(subscriber)));
+        Enter { previous }
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Hands the events of every layer below to a subscriber, see the
+/// [module docs](self).
+pub struct Events<R, T: Service<R>, S> {
+    inner: T,
+    subscriber: Arc<S>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>, S: Subscriber + 'static> Events<R, T, S> {
+    pub fn new(service: T, subscriber: S) -> Self {
+        Events {
+            inner: service,
+            subscriber: Arc::new(subscriber),
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn subscriber(&self) -> &S {
+        &self.subscriber
+    }
+}
+
+impl<R, T: Service<R>, S: Subscriber + 'static> Service<R> for Events<R, T, S> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let mut future = pin!(self.inner.request(msg));
+        poll_fn(|cx| {
+            let _enter = Enter::new(self.subscriber.clone());
+            future.as_mut().poll(cx)
+        })
+        .await
+    }
+}
+
+impl<R, T: Service<R>, S: Subscriber + 'static> Middleware<R, T> for Events<R, T, S> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, S> Describe for Events<R, T, S> {
+    fn name(&self) -> &'static str {
+        "events"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, S: 'static> FindLayer for Events<R, T, S> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    #[derive(Debug)]
+    pub struct TestFailingService {}
+
+    impl Service<()> for TestFailingService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            emit(StackEvent::Shed);
+            Err(FakeError::Error)
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct TestSubscriber {
+        events: Mutex<Vec<StackEvent>>,
+    }
+
+    impl Subscriber for TestSubscriber {
+        fn on_event(&self, event: &StackEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn events_test() {
+        let service = Events::new(TestFailingService {}, TestSubscriber::default());
+        assert!(service.request(()).await.is_err());
+        assert_eq!(
+            *service.subscriber().events.lock().unwrap(),
+            [StackEvent::Shed]
+        );
+
+        // Not reported outside of the layer
+        assert!(service.inner_service().request(()).await.is_err());
+        assert_eq!(service.subscriber().events.lock().unwrap().len(), 1);
+    }
+
+    #[cfg(all(feature = "retry", feature = "timeout"))]
+    #[tokio::test(start_paused = true)]
+    async fn events_retry_timeout_test() {
+        use crate::{retry::Retry, timeout::Timeout};
+
+        pub struct TestHangingService {}
+
+        impl Service<()> for TestHangingService {
+            type Response = ();
+            type Error = FakeError;
+
+            async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+                std::future::pending().await
+            }
+        }
+
+        let service = Events::new(
+            Retry::<1, _, _>::instant(Timeout::new(TestHangingService {}, Duration::from_secs(1))),
+            TestSubscriber::default(),
+        );
+        assert!(service.request(()).await.is_err());
+
+        let after = Duration::from_secs(1);
+        assert_eq!(
+            *service.subscriber().events.lock().unwrap(),
+            [
+                StackEvent::TimedOut { after },
+                StackEvent::RetryAttempted { attempt: 1 },
+                StackEvent::TimedOut { after },
+                StackEvent::RetriesExhausted,
+            ]
+        );
+    }
+}
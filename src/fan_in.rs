@@ -0,0 +1,170 @@
+//! Many producers sharing one service.
+//!
+//! Each producer gets its own [`ChannelService`] from [`FanIn::source`],
+//! tagged with a key. A single [`FanInWorker`] owns the actual service and
+//! calls it with `(key, request)`, taking requests from the sources in turn:
+//! a producer with a long queue waits for its next turn, instead of starving
+//! the others.
+
+use std::{future::poll_fn, task::Poll};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    channel::{ChannelService, Envelope},
+    meta, Service,
+};
+
+type Source<K, R, Resp, E> = (K, mpsc::Receiver<Envelope<R, Resp, E>>);
+
+/// Creates the sources of a [`FanInWorker`].
+pub struct FanIn<K, R, Resp, E> {
+    sources: mpsc::UnboundedSender<Source<K, R, Resp, E>>,
+}
+
+/// Calls the actual service with the requests of every source.
+pub struct FanInWorker<K, R, Resp, E> {
+    new_sources: mpsc::UnboundedReceiver<Source<K, R, Resp, E>>,
+}
+
+impl<K, R, Resp, E> FanIn<K, R, Resp, E> {
+    pub fn new() -> (Self, FanInWorker<K, R, Resp, E>) {
+        let (sources, new_sources) = mpsc::unbounded_channel();
+        (FanIn { sources }, FanInWorker { new_sources })
+    }
+
+    /// Adds a source, whose requests reach the service tagged with `key`.
+    /// `buffer` is how many of its requests can be waiting for the worker
+    /// before its callers have to wait.
+    pub fn source(&self, key: K, buffer: usize) -> ChannelService<R, Resp, E> {
+        let (service, worker) = ChannelService::new(buffer);
+        // Without a worker, the channel is closed and so is the source
+        let _ = self.sources.send((key, worker.into_receiver()));
+        service
+    }
+}
+
+impl<K, R, Resp, E> FanInWorker<K, R, Resp, E> {
+    /// Processes incoming requests one at a time with `service`, one source
+    /// after the other, until the [`FanIn`] and every source have been
+    /// dropped.
+    pub async fn run<S>(mut self, service: S)
+    where
+        K: Clone,
+        S: Service<(K, R), Response = Resp, Error = E>,
+    {
+        let mut sources: Vec<Source<K, R, Resp, E>> = Vec::new();
+        let mut adding = true;
+        // Where to start looking next, so every source gets its turn
+        let mut next = 0;
+
+        loop {
+            let envelope = poll_fn(|cx| {
+                while adding {
+                    match self.new_sources.poll_recv(cx) {
+                        Poll::Ready(Some(source)) => sources.push(source),
+                        Poll::Ready(None) => adding = false,
+                        Poll::Pending => break,
+                    }
+                }
+
+                let mut i = 0;
+                while i < sources.len() {
+                    let index = (next + i) % sources.len();
+                    match sources[index].1.poll_recv(cx) {
+                        Poll::Ready(Some(envelope)) => {
+                            next = index + 1;
+                            return Poll::Ready(Some((sources[index].0.clone(), envelope)));
+                        }
+                        Poll::Ready(None) => {
+                            sources.remove(index);
+                            next = index;
+                            i = 0;
+                        }
+                        Poll::Pending => i += 1,
+                    }
+                }
+
+                if adding || !sources.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(None)
+                }
+            })
+            .await;
+
+            let Some((key, (msg, meta, sender))) = envelope else {
+                return;
+            };
+            // The caller may have given up on the response, which is fine
+            let _ = sender.send(meta::scope(meta, service.request((key, msg))).await);
+        }
+    }
+}
+
+impl<K, R, Resp, E> Clone for FanIn<K, R, Resp, E> {
+    fn clone(&self) -> Self {
+        FanIn {
+            sources: self.sources.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use thiserror::Error;
+    use tokio::join;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    /// Records which source each request came from
+    #[derive(Debug, Default)]
+    pub struct TestFanInService {
+        sources: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Service<(&'static str, u64)> for TestFanInService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, (key, msg): (&'static str, u64)) -> Result<u64, EmptyError> {
+            self.sources.lock().unwrap().push(key);
+            Ok(msg * 2)
+        }
+    }
+
+    #[tokio::test]
+    async fn fan_in_test() {
+        let (fan_in, worker) = FanIn::new();
+        let busy = fan_in.source("busy", 4);
+        let quiet = fan_in.source("quiet", 4);
+        drop(fan_in);
+
+        let service = TestFanInService::default();
+        let sources = service.sources.clone();
+        let worker = tokio::spawn(worker.run(service));
+
+        // All queued before the worker gets to run
+        let (a, b, c, d) = join!(
+            busy.request(1),
+            busy.request(2),
+            busy.request(3),
+            quiet.request(4)
+        );
+        assert_eq!([a, b, c, d], [Ok(2), Ok(4), Ok(6), Ok(8)]);
+        assert_eq!(
+            *sources.lock().unwrap(),
+            ["busy", "quiet", "busy", "busy"],
+            "the quiet source didn't wait behind the busy one"
+        );
+
+        // The worker stops once all the sources are gone
+        drop((busy, quiet));
+        worker.await.unwrap();
+    }
+}
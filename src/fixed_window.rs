@@ -0,0 +1,275 @@
+//! Quotas resetting on wall-clock boundaries.
+//!
+//! Some upstreams allow N requests per minute or per hour, counted in
+//! windows aligned to the clock (from 12:00:00 to 12:00:59, then from
+//! 12:01:00...) rather than over the last 60 seconds. [`FixedWindow`] counts
+//! requests the same way, so the quota is used up to the last request
+//! without ever going over it. Windows are aligned to the Unix epoch by
+//! default, or to a reset time given by the upstream.
+
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromRateLimited, IntoFlat},
+    events::{self, StackEvent},
+    load::{Admission, Admit},
+    sync::Mutex,
+    Middleware, Service,
+};
+
+/// Lets `limit` requests through per window, rejecting the others until the
+/// next window starts.
+///
+/// Windows are measured on Tokio's clock once the limiter is created, so
+/// adjustments of the system clock don't move them. It relies on Tokio's
+/// time driver.
+pub struct FixedWindow<R, T: Service<R>> {
+    inner: T,
+    limit: u64,
+    window: Duration,
+    /// When the limiter was created, with how far into its window it was.
+    created: Instant,
+    phase: Duration,
+    current: Mutex<Current>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Default)]
+struct Current {
+    /// Windows since the limiter was created.
+    index: u64,
+    requests: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum FixedWindowError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("quota exhausted, resets in {resets_in:?}")]
+    QuotaExhausted { resets_in: Duration },
+}
+
+impl<E: core::error::Error> FixedWindowError<E> {
+    #[inline]
+    pub fn is_quota_exhausted(&self) -> bool {
+        matches!(self, FixedWindowError::QuotaExhausted { .. })
+    }
+}
+
+impl<E: core::error::Error + IntoFlat<F>, F: FromRateLimited> IntoFlat<F> for FixedWindowError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            FixedWindowError::ServiceError(e) => e.into_flat(),
+            FixedWindowError::QuotaExhausted { .. } => F::rate_limited(),
+        }
+    }
+}
+
+impl<E: core::error::Error + Classify> Classify for FixedWindowError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            FixedWindowError::ServiceError(e) => e.classify(),
+            FixedWindowError::QuotaExhausted { .. } => Class::Throttled,
+        }
+    }
+}
+
+impl<R, T: Service<R>> FixedWindow<R, T> {
+    /// `limit` requests per `window`, with windows aligned to the Unix
+    /// epoch: a window of a minute starts at every minute, a window of an
+    /// hour at every hour (UTC).
+    pub fn new(service: T, limit: u64, window: Duration) -> Self {
+        Self::aligned_to(service, limit, window, UNIX_EPOCH)
+    }
+
+    /// Like [`FixedWindow::new`], with windows starting at `reset` (e.g. the
+    /// next reset announced by the upstream) and every `window` before and
+    /// after it.
+    pub fn aligned_to(service: T, limit: u64, window: Duration, reset: SystemTime) -> Self {
+        let window = window.max(Duration::from_nanos(1));
+        let now = SystemTime::now();
+        let since_reset = match now.duration_since(reset) {
+            Ok(since) => since.as_nanos() % window.as_nanos(),
+            // The reset is in the future, count back from it
+            Err(e) => {
+                let until = e.duration().as_nanos() % window.as_nanos();
+                (window.as_nanos() - until) % window.as_nanos()
+            }
+        };
+
+        FixedWindow {
+            inner: service,
+            limit,
+            window,
+            created: Instant::now(),
+            phase: Duration::from_nanos(since_reset as u64),
+            current: Mutex::new(Current::default()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Requests let through in the current window.
+    pub fn used(&self) -> u64 {
+        let (index, _) = self.position();
+        let current = self.current.lock().unwrap();
+        if current.index == index {
+            current.requests
+        } else {
+            0
+        }
+    }
+
+    /// Which window it is, and how long until the next one.
+    fn position(&self) -> (u64, Duration) {
+        let elapsed = (self.created.elapsed() + self.phase).as_nanos();
+        let window = self.window.as_nanos();
+        let resets_in = window - elapsed % window;
+        (
+            (elapsed / window) as u64,
+            Duration::from_nanos(resets_in as u64),
+        )
+    }
+
+    fn try_acquire(&self) -> Result<(), Duration> {
+        let (index, resets_in) = self.position();
+        let mut current = self.current.lock().unwrap();
+        if current.index != index {
+            *current = Current { index, requests: 0 };
+        }
+
+        if current.requests >= self.limit {
+            return Err(resets_in);
+        }
+        current.requests += 1;
+        Ok(())
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for FixedWindow<R, T> {
+    type Response = T::Response;
+    type Error = FixedWindowError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.try_acquire().map_err(|resets_in| {
+            events::emit(StackEvent::QuotaExhausted { resets_in });
+            FixedWindowError::QuotaExhausted { resets_in }
+        })?;
+
+        self.inner
+            .request(msg)
+            .await
+            .map_err(FixedWindowError::ServiceError)
+    }
+}
+
+/// Rejected until the window resets once the quota is used up.
+impl<R, T: Service<R>> Admit<R> for FixedWindow<R, T> {
+    fn try_request(&self, _msg: &R) -> Admission {
+        let (index, resets_in) = self.position();
+        let current = self.current.lock().unwrap();
+        if current.index == index && current.requests >= self.limit {
+            Admission::Rejected {
+                retry_after: Some(resets_in),
+            }
+        } else {
+            Admission::Admitted
+        }
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for FixedWindow<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for FixedWindow<R, T> {
+    fn name(&self) -> &'static str {
+        "fixed_window"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("limit", self.limit.to_string()),
+            ("window_ms", self.window.as_millis().to_string()),
+            ("used", self.used().to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for FixedWindow<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestWindowService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestWindowService {
+        type Response = ();
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_window_test() {
+        // The upstream's quota resets in 10 seconds, then every minute
+        let reset = SystemTime::now() + Duration::from_secs(10);
+        let service =
+            FixedWindow::aligned_to(TestWindowService {}, 2, Duration::from_secs(60), reset);
+
+        assert!(service.request(()).await.is_ok());
+        assert!(service.request(()).await.is_ok());
+        let Err(FixedWindowError::QuotaExhausted { resets_in }) = service.request(()).await else {
+            panic!("the quota is used up");
+        };
+        assert!(resets_in <= Duration::from_secs(10) && resets_in > Duration::from_secs(9));
+        assert_eq!(service.used(), 2);
+        assert_eq!(
+            service.try_request(&()),
+            Admission::Rejected {
+                retry_after: Some(resets_in)
+            }
+        );
+
+        // A new window started at the reset
+        advance(resets_in).await;
+        assert_eq!(service.used(), 0);
+        assert!(service.try_request(&()).is_admitted());
+        assert!(service.request(()).await.is_ok());
+        assert!(service.request(()).await.is_ok());
+        assert!(service.request(()).await.unwrap_err().is_quota_exhausted());
+
+        // Not before the next one
+        advance(Duration::from_secs(59)).await;
+        assert!(service.request(()).await.unwrap_err().is_quota_exhausted());
+        advance(Duration::from_secs(1)).await;
+        assert!(service.request(()).await.is_ok());
+    }
+}
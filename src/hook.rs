@@ -0,0 +1,208 @@
+//! Central reporting of the errors a stack returns.
+//!
+//! [`OnError`] wraps a whole stack and calls an [`ErrorHook`] with every
+//! error it returns, so errors can be sent to Sentry or logs in one place
+//! instead of at every call site. Wrap the outermost layer to only see
+//! terminal errors, and not the attempts `Retry` recovered from.
+//!
+//! Every built-in middleware error points its `source()` at the error it
+//! wraps, so the layer an error comes from can be found by walking
+//! [`ErrorReport::chain`].
+
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    describe::{Describe, FindLayer, Stack},
+    Middleware, Service,
+};
+
+/// What an [`ErrorHook`] is told about a failed request.
+#[derive(Debug)]
+pub struct ErrorReport<'a> {
+    /// Name the stack was given in [`OnError::new`].
+    pub stack: &'static str,
+    /// Summary of the request, from [`ErrorHook::summarize`].
+    pub request: Option<&'a str>,
+    pub error: &'a (dyn Error + 'static),
+    /// How long the request took to fail. Zero on wasm, where there's no
+    /// clock.
+    pub elapsed: Duration,
+}
+
+impl<'a> ErrorReport<'a> {
+    /// The error and its sources, outermost first.
+    pub fn chain(&self) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+        crate::error::chain(self.error)
+    }
+
+    pub fn root_cause(&self) -> &'a (dyn Error + 'static) {
+        crate::error::root_cause(self.error)
+    }
+}
+
+pub trait ErrorHook<R> {
+    fn on_error(&self, report: &ErrorReport<'_>);
+
+    /// Summary of a request, taken before it's sent since the stack
+    /// consumes it. Not summarized by default, to avoid paying for it
+    /// on every request.
+    fn summarize(&self, _request: &R) -> Option<String> {
+        None
+    }
+}
+
+impl<R, F: Fn(&ErrorReport<'_>)> ErrorHook<R> for F {
+    fn on_error(&self, report: &ErrorReport<'_>) {
+        self(report)
+    }
+}
+
+/// Service calling a hook with every error of the inner service.
+pub struct OnError<R, T: Service<R>, H: ErrorHook<R>> {
+    inner: T,
+    name: &'static str,
+    hook: H,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>, H: ErrorHook<R>> OnError<R, T, H> {
+    pub fn new(service: T, name: &'static str, hook: H) -> Self {
+        OnError {
+            inner: service,
+            name,
+            hook,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn hook(&self) -> &H {
+        &self.hook
+    }
+}
+
+impl<R, T: Service<R>, H: ErrorHook<R>> Service<R> for OnError<R, T, H> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let started = (!cfg!(target_arch = "wasm32")).then(Instant::now);
+        let summary = self.hook.summarize(&msg);
+
+        let res = self.inner.request(msg).await;
+
+        if let Err(e) = &res {
+            self.hook.on_error(&ErrorReport {
+                stack: self.name,
+                request: summary.as_deref(),
+                error: e,
+                elapsed: started.map_or(Duration::ZERO, |started| started.elapsed()),
+            });
+        }
+
+        res
+    }
+}
+
+impl<R, T: Service<R>, H: ErrorHook<R>> Middleware<R, T> for OnError<R, T, H> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, H: ErrorHook<R>> Describe for OnError<R, T, H> {
+    fn name(&self) -> &'static str {
+        "on_error"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("stack", self.name.to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, H: ErrorHook<R> + 'static> FindLayer
+    for OnError<R, T, H>
+{
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestHookService {}
+
+    #[derive(Debug, Error)]
+    pub enum FakeError {
+        #[error("odd request")]
+        Error,
+    }
+
+    impl Service<u64> for TestHookService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            if msg % 2 == 1 {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg)
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct TestHook {
+        reports: Mutex<Vec<(String, String)>>,
+    }
+
+    impl ErrorHook<u64> for TestHook {
+        fn on_error(&self, report: &ErrorReport<'_>) {
+            self.reports.lock().unwrap().push((
+                report.request.unwrap().to_string(),
+                report.root_cause().to_string(),
+            ));
+        }
+
+        fn summarize(&self, request: &u64) -> Option<String> {
+            Some(format!("request #{request}"))
+        }
+    }
+
+    #[tokio::test]
+    async fn on_error_test() {
+        let service = OnError::new(TestHookService {}, "test", TestHook::default());
+
+        assert!(service.request(2).await.is_ok());
+        assert!(service.request(3).await.is_err());
+
+        assert_eq!(
+            *service.hook().reports.lock().unwrap(),
+            vec![("request #3".to_string(), "odd request".to_string())]
+        );
+
+        let errors = Mutex::new(0);
+        let service = OnError::new(TestHookService {}, "test", |report: &ErrorReport<'_>| {
+            assert_eq!(report.stack, "test");
+            assert_eq!(report.request, None);
+            *errors.lock().unwrap() += 1;
+        });
+        assert!(service.request(5).await.is_err());
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,229 @@
+//! HTTP status codes of the middleware errors, for servers in front of a
+//! stack.
+//!
+//! [`HttpStatus`] gives the status code to respond with when a request
+//! failed, and how long the client should wait before trying again (the
+//! `Retry-After` header) when it's known. Middleware errors map their own
+//! failures and defer to the inner error otherwise, so the innermost
+//! service's error only needs an impl, e.g. made with [`status_of`] from its
+//! [`Classify`](crate::classify::Classify) impl.
+//!
+//! The other way around, [`classify_status`] classifies the status codes of
+//! an HTTP client's responses, for the middlewares that tell failures apart.
+//!
+//! | Error | Status |
+//! |-------|--------|
+//! | `TimeoutError::TimeoutError`, `BlockingError::TimeoutError`, `ShedError::Shed` | 504 Gateway Timeout |
+//! | `RateLimitError::RateLimited`, `FixedWindowError::QuotaExhausted` | 429 Too Many Requests |
+//! | `ConcurrencyLimitError::Limited`, `KeyedLockError::WaitTimedOut` | 503 Service Unavailable |
+//! | `ChannelError::Closed`, `MultiplexError::Closed`, `LeaseError::NotHeld` | 503 Service Unavailable |
+//! | `DedupError::Duplicate` | 409 Conflict |
+
+use std::time::Duration;
+
+use crate::classify::Class;
+
+pub const CONFLICT: u16 = 409;
+pub const TOO_MANY_REQUESTS: u16 = 429;
+/// Not standard, but widely used for requests the client gave up on.
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+pub const INTERNAL_SERVER_ERROR: u16 = 500;
+pub const SERVICE_UNAVAILABLE: u16 = 503;
+pub const GATEWAY_TIMEOUT: u16 = 504;
+
+/// Errors that can be turned into an HTTP response.
+pub trait HttpStatus {
+    /// Status code of the response.
+    fn status_code(&self) -> u16;
+
+    /// How long the client should wait before trying again, if known.
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The status code matching a class of failure.
+pub fn status_of(class: Class) -> u16 {
+    match class {
+        Class::Transient => SERVICE_UNAVAILABLE,
+        Class::Permanent => INTERNAL_SERVER_ERROR,
+        Class::Throttled => TOO_MANY_REQUESTS,
+        Class::Cancelled => CLIENT_CLOSED_REQUEST,
+    }
+}
+
+/// Class of failure of an error status code, `None` if it's not one.
+pub fn classify_status(status: u16) -> Option<Class> {
+    match status {
+        429 => Some(Class::Throttled),
+        499 => Some(Class::Cancelled),
+        408 | 500 | 502 | 503 | 504 => Some(Class::Transient),
+        400..=599 => Some(Class::Permanent),
+        _ => None,
+    }
+}
+
+/// Implements [`HttpStatus`] for a middleware error, its own failures
+/// mapping to the given status and the inner error deferring to it.
+#[allow(unused_macros)]
+macro_rules! http_status {
+    ($error:ident, $($variant:pat => $status:expr),+ $(,)?) => {
+        impl<E: core::error::Error + HttpStatus> HttpStatus for $error<E> {
+            fn status_code(&self) -> u16 {
+                match self {
+                    $error::ServiceError(e) => e.status_code(),
+                    $($variant => $status,)+
+                }
+            }
+
+            fn retry_after(&self) -> Option<Duration> {
+                match self {
+                    $error::ServiceError(e) => e.retry_after(),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "timeout")]
+use crate::timeout::TimeoutError;
+#[cfg(feature = "timeout")]
+http_status!(TimeoutError, TimeoutError::TimeoutError => GATEWAY_TIMEOUT);
+
+#[cfg(feature = "blocking")]
+use crate::blocking::BlockingError;
+#[cfg(feature = "blocking")]
+http_status!(BlockingError, BlockingError::TimeoutError => GATEWAY_TIMEOUT);
+
+#[cfg(feature = "shed")]
+use crate::shed::ShedError;
+#[cfg(feature = "shed")]
+http_status!(ShedError, ShedError::Shed => GATEWAY_TIMEOUT);
+
+#[cfg(feature = "rate_limit")]
+use crate::rate_limit::RateLimitError;
+#[cfg(feature = "rate_limit")]
+http_status!(RateLimitError, RateLimitError::RateLimited => TOO_MANY_REQUESTS);
+
+#[cfg(feature = "concurrency_limit")]
+use crate::concurrency_limit::ConcurrencyLimitError;
+#[cfg(feature = "concurrency_limit")]
+http_status!(ConcurrencyLimitError, ConcurrencyLimitError::Limited => SERVICE_UNAVAILABLE);
+
+#[cfg(feature = "keyed_lock")]
+use crate::keyed_lock::KeyedLockError;
+#[cfg(feature = "keyed_lock")]
+http_status!(KeyedLockError, KeyedLockError::WaitTimedOut => SERVICE_UNAVAILABLE);
+
+#[cfg(feature = "channel")]
+use crate::channel::ChannelError;
+#[cfg(feature = "channel")]
+http_status!(ChannelError, ChannelError::Closed => SERVICE_UNAVAILABLE);
+
+#[cfg(feature = "multiplex")]
+use crate::multiplex::MultiplexError;
+#[cfg(feature = "multiplex")]
+http_status!(MultiplexError, MultiplexError::Closed => SERVICE_UNAVAILABLE);
+
+#[cfg(feature = "dedup")]
+use crate::dedup::DedupError;
+#[cfg(feature = "dedup")]
+http_status!(DedupError, DedupError::Duplicate => CONFLICT);
+
+#[cfg(feature = "fixed_window")]
+use crate::fixed_window::FixedWindowError;
+
+/// Tells the client when the window resets.
+#[cfg(feature = "fixed_window")]
+impl<E: core::error::Error + HttpStatus> HttpStatus for FixedWindowError<E> {
+    fn status_code(&self) -> u16 {
+        match self {
+            FixedWindowError::ServiceError(e) => e.status_code(),
+            FixedWindowError::QuotaExhausted { .. } => TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FixedWindowError::ServiceError(e) => e.retry_after(),
+            FixedWindowError::QuotaExhausted { resets_in } => Some(*resets_in),
+        }
+    }
+}
+
+#[cfg(feature = "lease")]
+use crate::lease::LeaseError;
+
+/// Whoever holds the lease handles the request, not this instance.
+#[cfg(feature = "lease")]
+impl<E, P> HttpStatus for LeaseError<E, P>
+where
+    E: core::error::Error + HttpStatus,
+    P: core::error::Error,
+{
+    fn status_code(&self) -> u16 {
+        match self {
+            LeaseError::ServiceError(e) => e.status_code(),
+            LeaseError::ProviderError(_) | LeaseError::NotHeld => SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LeaseError::ServiceError(e) => e.retry_after(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_status_test() {
+        assert_eq!(classify_status(200), None);
+        assert_eq!(classify_status(404), Some(Class::Permanent));
+        assert_eq!(classify_status(429), Some(Class::Throttled));
+        assert_eq!(classify_status(503), Some(Class::Transient));
+        assert_eq!(status_of(Class::Throttled), TOO_MANY_REQUESTS);
+    }
+
+    #[cfg(all(feature = "fixed_window", feature = "rate_limit", feature = "timeout"))]
+    #[test]
+    fn http_status_test() {
+        use thiserror::Error;
+
+        #[derive(Debug, Error)]
+        #[error("not found")]
+        pub struct NotFound;
+
+        impl HttpStatus for NotFound {
+            fn status_code(&self) -> u16 {
+                404
+            }
+        }
+
+        type StackError = RateLimitError<FixedWindowError<TimeoutError<NotFound>>>;
+
+        let err: StackError = RateLimitError::RateLimited;
+        assert_eq!(err.status_code(), TOO_MANY_REQUESTS);
+
+        let err: StackError = RateLimitError::ServiceError(FixedWindowError::QuotaExhausted {
+            resets_in: Duration::from_secs(30),
+        });
+        assert_eq!(err.status_code(), TOO_MANY_REQUESTS);
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(30)));
+
+        let err: StackError = RateLimitError::ServiceError(FixedWindowError::ServiceError(
+            TimeoutError::TimeoutError,
+        ));
+        assert_eq!(err.status_code(), GATEWAY_TIMEOUT);
+
+        let err: StackError = RateLimitError::ServiceError(FixedWindowError::ServiceError(
+            TimeoutError::ServiceError(NotFound),
+        ));
+        assert_eq!((err.status_code(), err.retry_after()), (404, None));
+    }
+}
@@ -0,0 +1,221 @@
+//! Counting the requests inside a stack.
+//!
+//! Placed on top of a stack, [`InFlight`] knows how many requests are
+//! anywhere inside of it, whatever layer they're waiting in. This is the
+//! gauge to report the load of a stack, and [`InFlight::idle`] resolves once
+//! it's back to zero, e.g. to let the requests finish during a graceful
+//! shutdown before tearing down what they need.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::poll_fn,
+    marker::PhantomData,
+    sync::PoisonError,
+    task::{Poll, Waker},
+};
+
+use crate::{
+    describe::{Describe, FindLayer, Stack, StackStats},
+    load::{Load, LoadMetric},
+    sync::Mutex,
+    Middleware, Service,
+};
+
+#[derive(Default)]
+struct Gauge {
+    in_flight: usize,
+    next_id: u64,
+    /// Futures waiting for the gauge to drop to zero.
+    idle: HashMap<u64, Waker>,
+}
+
+/// Counts the requests until their future is dropped, completed or not.
+pub struct InFlight<R, T: Service<R>> {
+    inner: T,
+    gauge: Mutex<Gauge>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// Takes a request out of the gauge when it's done or dropped.
+struct Tracked<'a> {
+    gauge: &'a Mutex<Gauge>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let mut gauge = self.gauge.lock().unwrap_or_else(PoisonError::into_inner);
+        gauge.in_flight -= 1;
+        if gauge.in_flight == 0 {
+            for (_, waker) in gauge.idle.drain() {
+                waker.wake();
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        ::metrics::gauge!(crate::metrics::IN_FLIGHT, "layer" => "in_flight").decrement(1);
+    }
+}
+
+/// Unregisters an [`InFlight::idle`] future that was dropped.
+struct Waiting<'a> {
+    gauge: &'a Mutex<Gauge>,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.gauge
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .idle
+            .remove(&self.id);
+    }
+}
+
+impl<R, T: Service<R>> InFlight<R, T> {
+    pub fn new(service: T) -> Self {
+        InFlight {
+            inner: service,
+            gauge: Mutex::new(Gauge::default()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Requests currently inside the stack.
+    pub fn in_flight(&self) -> usize {
+        self.gauge.lock().unwrap().in_flight
+    }
+
+    /// Resolves once no request is inside the stack, right away if there's
+    /// none. New requests can still come in afterwards.
+    pub async fn idle(&self) {
+        let id = {
+            let mut gauge = self.gauge.lock().unwrap();
+            gauge.next_id += 1;
+            gauge.next_id
+        };
+        let _waiting = Waiting {
+            gauge: &self.gauge,
+            id,
+        };
+        poll_fn(|cx| {
+            let mut gauge = self.gauge.lock().unwrap();
+            if gauge.in_flight == 0 {
+                Poll::Ready(())
+            } else {
+                gauge.idle.insert(id, cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for InFlight<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.gauge.lock().unwrap().in_flight += 1;
+        let _tracked = Tracked { gauge: &self.gauge };
+
+        #[cfg(feature = "metrics")]
+        ::metrics::gauge!(crate::metrics::IN_FLIGHT, "layer" => "in_flight").increment(1);
+
+        self.inner.request(msg).await
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for InFlight<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// Every request is in flight as far as this layer can tell, queued or not.
+impl<R, T: Service<R>> Load for InFlight<R, T> {
+    fn load(&self) -> LoadMetric {
+        LoadMetric {
+            in_flight: self.in_flight(),
+            queued: 0,
+            capacity: None,
+        }
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for InFlight<R, T> {
+    fn name(&self) -> &'static str {
+        "in_flight"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("in_flight", self.in_flight().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for InFlight<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+
+    fn record_stats(&self, stats: &mut StackStats) {
+        stats.in_flight.get_or_insert(self.in_flight());
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::time::Duration;
+
+    use thiserror::Error;
+    use tokio::time::{sleep, Instant};
+
+    use super::*;
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    /// Takes as many seconds as the request says
+    #[derive(Debug)]
+    pub struct TestSlowService {}
+
+    impl Service<u64> for TestSlowService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_secs(msg)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_flight_test() {
+        let service = InFlight::new(TestSlowService {});
+        service.idle().await;
+
+        let started = Instant::now();
+        let requests = async {
+            let (a, b) = tokio::join!(service.request(1), service.request(3));
+            assert_eq!((a.unwrap(), b.unwrap()), (1, 3));
+        };
+        let watched = async {
+            tokio::task::yield_now().await;
+            assert_eq!(service.in_flight(), 2);
+            service.idle().await;
+            started.elapsed()
+        };
+        let ((), idle_after) = tokio::join!(requests, watched);
+        assert_eq!(idle_after, Duration::from_secs(3));
+        assert_eq!(service.load().in_flight, 0);
+
+        // Dropped requests are taken out too
+        let dropped = tokio::time::timeout(Duration::from_secs(1), service.request(5)).await;
+        assert!(dropped.is_err());
+        assert_eq!(service.in_flight(), 0);
+    }
+}
@@ -0,0 +1,228 @@
+//! Feeding the requests of a queue into a stack.
+//!
+//! Taking requests off a queue as fast as they come and sending them to a
+//! rate limited stack only gets most of them rejected. [`Ingest`] takes the
+//! next request only while the stack is ready for it, as told by its
+//! [`Load`] or a check of its own, and while fewer than its maximum are in
+//! flight. Requests left in the queue meanwhile wait there, so the
+//! backpressure reaches the producers through the queue's own bound.
+//!
+//! The requests come from a Tokio [`mpsc::Receiver`] or any [`Stream`], and
+//! their results are handed to a callback as they finish, in any order.
+//! Readiness is checked again when a request finishes, or every
+//! [`Ingest::poll_every`] when none is in flight. It relies on Tokio for
+//! its timer.
+
+use std::{
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Stream;
+use tokio::{sync::mpsc, time::sleep};
+
+use crate::{load::Load, Service};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// Sends the requests of a queue to a service as it can take them, see the
+/// [module docs](self).
+pub struct Ingest<S> {
+    service: S,
+    max_in_flight: usize,
+    ready: fn(&S) -> bool,
+    poll_every: Duration,
+}
+
+impl<S> Ingest<S> {
+    /// Sends up to `max_in_flight` requests at a time, without checking if
+    /// the service is ready for them.
+    pub fn new(service: S, max_in_flight: usize) -> Self {
+        Ingest {
+            service,
+            max_in_flight: max_in_flight.max(1),
+            ready: |_| true,
+            poll_every: Duration::from_millis(10),
+        }
+    }
+
+    /// Takes the next request only when [`Load::is_ready`] says so.
+    pub fn with_load(service: S, max_in_flight: usize) -> Self
+    where
+        S: Load,
+    {
+        Ingest {
+            ready: |service| service.is_ready(),
+            ..Ingest::new(service, max_in_flight)
+        }
+    }
+
+    /// Takes the next request only when `ready` says so, e.g. asking a
+    /// layer further down the stack found with
+    /// [`FindLayer`](crate::describe::FindLayer).
+    pub fn ready_when(self, ready: fn(&S) -> bool) -> Self {
+        Ingest { ready, ..self }
+    }
+
+    /// How often to check again for readiness while no request is in
+    /// flight. Defaults to 10 milliseconds.
+    pub fn poll_every(self, poll_every: Duration) -> Self {
+        Ingest { poll_every, ..self }
+    }
+
+    pub fn service(&self) -> &S {
+        &self.service
+    }
+
+    /// Sends the requests of `receiver` until every sender is dropped and the
+    /// requests in flight are done.
+    pub async fn run_receiver<R, F>(&self, mut receiver: mpsc::Receiver<R>, on_result: F)
+    where
+        S: Service<R>,
+        F: FnMut(Result<S::Response, S::Error>),
+    {
+        self.run(|cx| receiver.poll_recv(cx), on_result).await
+    }
+
+    /// Sends the items of `stream` until it ends and the requests in flight
+    /// are done.
+    pub async fn run_stream<R, St, F>(&self, stream: St, on_result: F)
+    where
+        S: Service<R>,
+        St: Stream<Item = R>,
+        F: FnMut(Result<S::Response, S::Error>),
+    {
+        let mut stream = pin!(stream);
+        self.run(|cx| stream.as_mut().poll_next(cx), on_result)
+            .await
+    }
+
+    async fn run<R, F>(
+        &self,
+        mut next: impl FnMut(&mut Context<'_>) -> Poll<Option<R>>,
+        mut on_result: F,
+    ) where
+        S: Service<R>,
+        F: FnMut(Result<S::Response, S::Error>),
+    {
+        let mut in_flight: Vec<BoxFuture<'_, Result<S::Response, S::Error>>> = Vec::new();
+        let mut done = false;
+        let mut pause: Option<Pin<Box<tokio::time::Sleep>>> = None;
+
+        poll_fn(|cx| loop {
+            in_flight.retain_mut(|request| match request.as_mut().poll(cx) {
+                Poll::Ready(res) => {
+                    on_result(res);
+                    false
+                }
+                Poll::Pending => true,
+            });
+
+            if done || in_flight.len() >= self.max_in_flight {
+                return match (done, in_flight.is_empty()) {
+                    (true, true) => Poll::Ready(()),
+                    _ => Poll::Pending,
+                };
+            }
+
+            if !(self.ready)(&self.service) {
+                // A finished request wakes this up, otherwise nothing will
+                if in_flight.is_empty() {
+                    let pause = pause.get_or_insert_with(|| Box::pin(sleep(self.poll_every)));
+                    if pause.as_mut().poll(cx).is_ready() {
+                        *pause = Box::pin(sleep(self.poll_every));
+                        continue;
+                    }
+                }
+                return Poll::Pending;
+            }
+            pause = None;
+
+            match next(cx) {
+                Poll::Ready(Some(msg)) => in_flight.push(Box::pin(self.service.request(msg))),
+                Poll::Ready(None) => done = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    #[derive(Debug)]
+    pub struct TestSlowService {}
+
+    impl Service<u64> for TestSlowService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(10)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ingest_receiver_test() {
+        let ingest = Ingest::new(TestSlowService {}, 2);
+        let (sender, receiver) = mpsc::channel(8);
+        for i in 0..5 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+
+        let started = Instant::now();
+        let mut responses = Vec::new();
+        ingest
+            .run_receiver(receiver, |res| responses.push(res.unwrap()))
+            .await;
+
+        // Two at a time
+        responses.sort();
+        assert_eq!(responses, [0, 1, 2, 3, 4]);
+        assert_eq!(started.elapsed(), Duration::from_millis(30));
+    }
+
+    #[cfg(feature = "rate_limit")]
+    #[tokio::test(start_paused = true)]
+    async fn ingest_load_test() {
+        use crate::rate_limit::RateLimit;
+
+        pub struct Range(std::ops::Range<u64>);
+
+        impl Stream for Range {
+            type Item = u64;
+
+            fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<u64>> {
+                Poll::Ready(self.0.next())
+            }
+        }
+
+        // More in flight than the limit, but none rejected
+        let ingest = Ingest::with_load(RateLimit::<2, _, _>::new(TestSlowService {}), 8);
+        let mut ok = 0;
+        ingest
+            .run_stream(Range(0..6), |res| ok += usize::from(res.is_ok()))
+            .await;
+        assert_eq!(ok, 6);
+
+        // Rejected without checking
+        let ingest = Ingest::new(RateLimit::<2, _, _>::new(TestSlowService {}), 8);
+        let mut ok = 0;
+        ingest
+            .run_stream(Range(0..6), |res| ok += usize::from(res.is_ok()))
+            .await;
+        assert_eq!(ok, 2);
+    }
+}
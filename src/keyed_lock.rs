@@ -0,0 +1,294 @@
+//! Mutual exclusion per key.
+//!
+//! Two concurrent writes to the same entity race, but writes to different
+//! entities don't need to wait for each other. [`KeyedLock`] extracts a key
+//! from each request (e.g. an account ID) and runs the requests sharing a key
+//! one at a time, in the order they came, while requests with other keys go
+//! through concurrently.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromTimeout, IntoFlat},
+    meta,
+    sync::Mutex,
+    Middleware, Service,
+};
+
+/// Runs the requests with the same key one at a time.
+///
+/// Locks only exist while requests hold or wait for them, so there's no need
+/// to bound the number of keys.
+pub struct KeyedLock<R, T: Service<R>, K> {
+    inner: T,
+    key: fn(&R) -> K,
+    locks: Mutex<HashMap<K, Entry>>,
+    wait_timeout: Option<Duration>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Default)]
+struct Entry {
+    lock: Arc<tokio::sync::Mutex<()>>,
+    /// Requests holding or waiting for the lock.
+    pending: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum KeyedLockError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("timed out waiting for the lock of the key")]
+    WaitTimedOut,
+}
+
+impl<E: core::error::Error> KeyedLockError<E> {
+    #[inline]
+    pub fn is_wait_timed_out(&self) -> bool {
+        matches!(self, KeyedLockError::WaitTimedOut)
+    }
+}
+
+impl<E: core::error::Error + IntoFlat<F>, F: FromTimeout> IntoFlat<F> for KeyedLockError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            KeyedLockError::ServiceError(e) => e.into_flat(),
+            KeyedLockError::WaitTimedOut => F::timed_out(),
+        }
+    }
+}
+
+/// Too many requests for the same key, the queue might be shorter later.
+impl<E: core::error::Error + Classify> Classify for KeyedLockError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            KeyedLockError::ServiceError(e) => e.classify(),
+            KeyedLockError::WaitTimedOut => Class::Throttled,
+        }
+    }
+}
+
+/// Removes the request from the pending ones of its key when dropped, and
+/// the lock with the last one.
+struct Pending<'a, K: Eq + Hash> {
+    locks: &'a Mutex<HashMap<K, Entry>>,
+    key: K,
+}
+
+impl<K: Eq + Hash> Drop for Pending<'_, K> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = locks.get_mut(&self.key) {
+            entry.pending -= 1;
+            if entry.pending == 0 {
+                locks.remove(&self.key);
+            }
+        }
+    }
+}
+
+impl<R, T: Service<R>, K: Eq + Hash + Clone> KeyedLock<R, T, K> {
+    /// Serializes the requests for which `key` returns the same key.
+    /// Requests wait for the lock as long as needed, unless
+    /// [`KeyedLock::with_wait_timeout`] is used.
+    pub fn new(service: T, key: fn(&R) -> K) -> Self {
+        KeyedLock {
+            inner: service,
+            key,
+            locks: Mutex::new(HashMap::new()),
+            wait_timeout: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Fails the requests that waited `timeout` for the lock of their key.
+    pub fn with_wait_timeout(self, timeout: Duration) -> Self {
+        KeyedLock {
+            wait_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Requests holding or waiting for the lock of `key`.
+    pub fn pending(&self, key: &K) -> usize {
+        self.locks
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |entry| entry.pending)
+    }
+
+    /// Keys with requests holding or waiting for their lock.
+    pub fn keys(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl<R, T: Service<R>, K: Eq + Hash + Clone> Service<R> for KeyedLock<R, T, K> {
+    type Response = T::Response;
+    type Error = KeyedLockError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let key = (self.key)(&msg);
+        let (lock, _queued) = {
+            let mut locks = self.locks.lock().unwrap();
+            let entry = locks.entry(key.clone()).or_default();
+            entry.pending += 1;
+            (entry.lock.clone(), entry.pending - 1)
+        };
+        let _pending = Pending {
+            locks: &self.locks,
+            key,
+        };
+
+        #[cfg(feature = "metrics")]
+        let waiting = {
+            ::metrics::histogram!(crate::metrics::KEYED_LOCK_QUEUE, "layer" => "keyed_lock")
+                .record(_queued as f64);
+            std::time::Instant::now()
+        };
+
+        // No waiting past the deadline of the request either
+        let wait_timeout = self.wait_timeout.into_iter().chain(meta::remaining()).min();
+        let _guard = match wait_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, lock.lock())
+                .await
+                .map_err(|_| KeyedLockError::WaitTimedOut)?,
+            None => lock.lock().await,
+        };
+
+        #[cfg(feature = "metrics")]
+        ::metrics::histogram!(crate::metrics::KEYED_LOCK_WAIT, "layer" => "keyed_lock")
+            .record(waiting.elapsed().as_secs_f64());
+
+        self.inner
+            .request(msg)
+            .await
+            .map_err(KeyedLockError::ServiceError)
+    }
+}
+
+impl<R, T: Service<R>, K: Eq + Hash + Clone> Middleware<R, T> for KeyedLock<R, T, K> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, K: Eq + Hash + Clone> Describe for KeyedLock<R, T, K> {
+    fn name(&self) -> &'static str {
+        "keyed_lock"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        let mut params = BTreeMap::from([("keys", self.keys().to_string())]);
+        if let Some(timeout) = self.wait_timeout {
+            params.insert("wait_timeout_ms", timeout.as_millis().to_string());
+        }
+        params
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, K: 'static> FindLayer for KeyedLock<R, T, K> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::{
+        join,
+        time::{sleep, Instant},
+    };
+
+    use super::*;
+    use crate::meta::Deadline;
+
+    #[derive(Debug)]
+    pub struct TestLockedService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    /// Takes 10ms to write `(key, value)`
+    impl Service<(&'static str, u64)> for TestLockedService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, (_, msg): (&'static str, u64)) -> Result<u64, EmptyError> {
+            sleep(Duration::from_millis(10)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keyed_lock_test() {
+        let service = KeyedLock::new(TestLockedService {}, |(key, _)| *key);
+
+        // Different keys don't wait for each other
+        let started = Instant::now();
+        let (a, b) = join!(service.request(("a", 1)), service.request(("b", 2)));
+        assert_eq!((a, b), (Ok(1), Ok(2)));
+        assert_eq!(started.elapsed(), Duration::from_millis(10));
+
+        // The same key does
+        let started = Instant::now();
+        let (a, b, pending) = join!(
+            service.request(("a", 1)),
+            service.request(("a", 2)),
+            async {
+                assert_eq!(service.pending(&"a"), 2);
+            }
+        );
+        assert_eq!((a, b, pending), (Ok(1), Ok(2), ()));
+        assert_eq!(started.elapsed(), Duration::from_millis(20));
+        assert_eq!(service.keys(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keyed_lock_timeout_test() {
+        let service = KeyedLock::new(TestLockedService {}, |(key, _)| *key)
+            .with_wait_timeout(Duration::from_millis(15));
+
+        let (a, b, c) = join!(
+            service.request(("a", 1)),
+            service.request(("a", 2)),
+            service.request(("a", 3))
+        );
+        assert_eq!(a, Ok(1));
+        assert_eq!(b, Ok(2));
+        assert_eq!(c, Err(KeyedLockError::WaitTimedOut));
+        assert_eq!(service.keys(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keyed_lock_deadline_test() {
+        let service = Deadline::new(
+            KeyedLock::new(TestLockedService {}, |(key, _)| *key),
+            Duration::from_millis(15),
+        );
+
+        // Same as a wait timeout, without one
+        let (a, b, c) = join!(
+            service.request(("a", 1)),
+            service.request(("a", 2)),
+            service.request(("a", 3))
+        );
+        assert_eq!((a, b), (Ok(1), Ok(2)));
+        assert_eq!(c, Err(KeyedLockError::WaitTimedOut));
+    }
+}
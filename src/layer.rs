@@ -0,0 +1,112 @@
+//! Building stacks from the inside out, one layer at a time.
+//!
+//! Writing the type of a stack by hand puts the outermost layer first,
+//! `Retry<3, _, Timeout<_, Inner>>`, while the stack is built from the
+//! innermost service up. [`ServiceBuilder`] follows the order of
+//! construction instead, each [`Layer`] wrapping what was built so far:
+//!
+//! ```ignore
+//! let service = ServiceBuilder::new(inner)
+//!     .layer(TimeoutLayer::new(Duration::from_secs(2)))
+//!     .layer(RetryLayer::<3, _>::instant())
+//!     .layer(|service| Shed::new(service, Duration::from_millis(50)))
+//!     .build();
+//! ```
+//!
+//! The result is the same nested type as written by hand, the builder only
+//! exists until [`ServiceBuilder::build`]. Any function taking the inner
+//! service is a layer, so constructors like `RateLimit::<5, _, _>::new`
+//! can be given as is.
+
+/// Wraps a service into another one, usually a middleware.
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(self, inner: S) -> Self::Service;
+}
+
+impl<S, F: FnOnce(S) -> T, T> Layer<S> for F {
+    type Service = T;
+
+    fn layer(self, inner: S) -> Self::Service {
+        self(inner)
+    }
+}
+
+/// A stack being built, see the [module docs](self).
+pub struct ServiceBuilder<S> {
+    service: S,
+}
+
+impl<S> ServiceBuilder<S> {
+    /// Starts from the innermost service.
+    pub fn new(service: S) -> Self {
+        ServiceBuilder { service }
+    }
+
+    /// Wraps the stack built so far with `layer`.
+    pub fn layer<L: Layer<S>>(self, layer: L) -> ServiceBuilder<L::Service> {
+        ServiceBuilder {
+            service: layer.layer(self.service),
+        }
+    }
+
+    pub fn build(self) -> S {
+        self.service
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+    use crate::{combinators::ServiceExt, Service};
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    #[derive(Debug)]
+    pub struct TestDoubleService {}
+
+    impl Service<u64> for TestDoubleService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            Ok(msg * 2)
+        }
+    }
+
+    #[tokio::test]
+    async fn service_builder_test() {
+        let service = ServiceBuilder::new(TestDoubleService {})
+            .layer(|service: TestDoubleService| service.map_response(|resp| resp + 1))
+            .layer(|service| ServiceExt::<u64>::map_request(service, |msg: u32| msg.into()))
+            .build();
+
+        // Innermost layer first
+        assert_eq!(service.request(3u32).await, Ok(7));
+    }
+
+    #[cfg(all(feature = "retry", feature = "timeout", feature = "rate_limit"))]
+    #[tokio::test]
+    async fn service_builder_layers_test() {
+        use std::time::Duration;
+
+        use crate::{
+            rate_limit::{RateLimit, RateLimitLayer},
+            retry::{Retry, RetryLayer},
+            timeout::{Timeout, TimeoutLayer},
+        };
+
+        let service: RateLimit<5, u64, Retry<3, u64, Timeout<u64, TestDoubleService>>> =
+            ServiceBuilder::new(TestDoubleService {})
+                .layer(TimeoutLayer::new(Duration::from_secs(1)))
+                .layer(RetryLayer::instant())
+                .layer(RateLimitLayer::new())
+                .build();
+
+        assert_eq!(service.request(4).await, Ok(8));
+    }
+}
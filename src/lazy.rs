@@ -0,0 +1,205 @@
+//! Deferring the construction of a service until it's first needed.
+//!
+//! A stack is usually built at program start, but its innermost service (a
+//! database client, a connection...) may not need to exist until the first
+//! request. [`Lazy`] takes a generator service, like `Restart`, and only
+//! calls it on the first request. Requests arriving while it runs wait for
+//! that same initialization instead of starting their own.
+//!
+//! A failed initialization is returned to the requests waiting for it, and
+//! the next request tries again.
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::IntoFlat,
+    Service,
+};
+
+#[derive(Debug, Error)]
+pub enum LazyError<E: core::error::Error, GE: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("could not initialize the service")]
+    InitFailed(#[source] GE),
+}
+
+impl<E: core::error::Error, GE: core::error::Error> LazyError<E, GE> {
+    #[inline]
+    pub fn is_init_failed(&self) -> bool {
+        matches!(self, LazyError::InitFailed(_))
+    }
+}
+
+impl<E, GE, F> IntoFlat<F> for LazyError<E, GE>
+where
+    E: core::error::Error + IntoFlat<F>,
+    GE: core::error::Error + IntoFlat<F>,
+{
+    fn into_flat(self) -> F {
+        match self {
+            LazyError::ServiceError(e) => e.into_flat(),
+            LazyError::InitFailed(e) => e.into_flat(),
+        }
+    }
+}
+
+/// A failed initialization is transient, the next request tries again.
+impl<E: core::error::Error + Classify, GE: core::error::Error> Classify for LazyError<E, GE> {
+    fn classify(&self) -> Class {
+        match self {
+            LazyError::ServiceError(e) => e.classify(),
+            LazyError::InitFailed(_) => Class::Transient,
+        }
+    }
+}
+
+pub struct Lazy<R, GR, G: Service<GR>> {
+    service: OnceCell<G::Response>,
+    generator: G,
+    generator_msg: GR,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, GR: Clone, G: Service<GR>> Lazy<R, GR, G> {
+    /// Calls `generator` with `generator_msg` on the first request.
+    pub fn new(generator: G, generator_msg: GR) -> Self {
+        Lazy {
+            service: OnceCell::new(),
+            generator,
+            generator_msg,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Initializes the service now if it isn't already, e.g. to warm it up
+    /// in the background.
+    pub async fn init(&self) -> Result<&G::Response, G::Error> {
+        self.service
+            .get_or_try_init(|| self.generator.request(self.generator_msg.clone()))
+            .await
+    }
+
+    /// The service, if it was initialized.
+    pub fn get(&self) -> Option<&G::Response> {
+        self.service.get()
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.service.initialized()
+    }
+}
+
+impl<R, GR, G> Service<R> for Lazy<R, GR, G>
+where
+    GR: Clone,
+    G: Service<GR>,
+    G::Response: Service<R>,
+{
+    type Response = <G::Response as Service<R>>::Response;
+    type Error = LazyError<<G::Response as Service<R>>::Error, G::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let service = self.init().await.map_err(LazyError::InitFailed)?;
+        service.request(msg).await.map_err(LazyError::ServiceError)
+    }
+}
+
+impl<R, GR, G> Describe for Lazy<R, GR, G>
+where
+    G: Service<GR>,
+    G::Response: Describe,
+{
+    fn name(&self) -> &'static str {
+        "lazy"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("initialized", self.service.initialized().to_string())])
+    }
+
+    /// Describes the service below only once it was initialized.
+    fn describe(&self) -> Stack {
+        match self.service.get() {
+            Some(service) => Stack::on_top_of(self.layer(), service.describe()),
+            None => Stack {
+                layers: vec![self.layer()],
+            },
+        }
+    }
+}
+
+impl<R: 'static, GR: 'static, G> FindLayer for Lazy<R, GR, G>
+where
+    G: Service<GR> + 'static,
+    G::Response: FindLayer,
+{
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        self.service.get().map(|service| service as &dyn FindLayer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    #[derive(Debug)]
+    pub struct TestEchoService {}
+
+    impl Service<u64> for TestEchoService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            Ok(msg)
+        }
+    }
+
+    /// Fails the first time it's called
+    #[derive(Debug, Default)]
+    pub struct TestGeneratorService {
+        calls: AtomicUsize,
+    }
+
+    impl Service<()> for TestGeneratorService {
+        type Response = TestEchoService;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            tokio::task::yield_now().await;
+            match self.calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(FakeError::Error),
+                _ => Ok(TestEchoService {}),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lazy_test() {
+        let service = Lazy::new(TestGeneratorService::default(), ());
+        assert!(!service.is_initialized());
+
+        let res = service.request(1).await;
+        assert!(matches!(res, Err(LazyError::InitFailed(FakeError::Error))));
+        assert!(!service.is_initialized());
+
+        // Concurrent first requests share one initialization
+        let (a, b) = tokio::join!(service.request(2), service.request(3));
+        assert_eq!((a.unwrap(), b.unwrap()), (2, 3));
+        assert_eq!(service.generator.calls.load(Ordering::Relaxed), 2);
+        assert!(service.get().is_some());
+    }
+}
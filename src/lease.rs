@@ -0,0 +1,341 @@
+//! Gating a service on holding a lease.
+//!
+//! In an active/standby pair, only the instance holding the lease may do the
+//! work. [`Lease`] acquires the lease from a [`LeaseProvider`] (a lock in a
+//! database, etcd, Consul...) before forwarding requests, renews it as it
+//! goes, and rejects requests right away when it's held by someone else.
+//! [`LocalLease`] is a provider for instances sharing the same process.
+
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromClosed, IntoFlat},
+    sync::{AtomicU64, Mutex, Ordering},
+    Middleware, Service,
+};
+
+/// Where leases come from.
+#[allow(async_fn_in_trait)]
+pub trait LeaseProvider {
+    type Error: core::error::Error + 'static;
+
+    /// Acquires the lease, or renews it if it's already held by this
+    /// provider. Returns how long the lease is valid for, or `None` when it's
+    /// held by someone else.
+    async fn acquire(&self) -> Result<Option<Duration>, Self::Error>;
+}
+
+/// Forwards requests only while the lease is held, acquiring it first when
+/// needed.
+///
+/// The lease is renewed by the first requests coming once less than the
+/// renewal margin is left, so an idle service might lose it. Requests
+/// arriving during a renewal renew it too, which providers should allow.
+pub struct Lease<R, T: Service<R>, P> {
+    inner: T,
+    provider: P,
+    /// When the lease held expires, `None` when it isn't held.
+    expires: Mutex<Option<Instant>>,
+    renew_margin: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum LeaseError<E: core::error::Error, P: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("lease provider failed")]
+    ProviderError(#[source] P),
+    #[error("lease held by someone else")]
+    NotHeld,
+}
+
+impl<E: core::error::Error, P: core::error::Error> LeaseError<E, P> {
+    #[inline]
+    pub fn is_not_held(&self) -> bool {
+        matches!(self, LeaseError::NotHeld)
+    }
+}
+
+/// Without the lease, this instance is as good as closed.
+impl<E, P, F> IntoFlat<F> for LeaseError<E, P>
+where
+    E: core::error::Error + IntoFlat<F>,
+    P: core::error::Error + IntoFlat<F>,
+    F: FromClosed,
+{
+    fn into_flat(self) -> F {
+        match self {
+            LeaseError::ServiceError(e) => e.into_flat(),
+            LeaseError::ProviderError(e) => e.into_flat(),
+            LeaseError::NotHeld => F::closed(),
+        }
+    }
+}
+
+/// The active instance should get the request instead.
+impl<E, P> Classify for LeaseError<E, P>
+where
+    E: core::error::Error + Classify,
+    P: core::error::Error + Classify,
+{
+    fn classify(&self) -> Class {
+        match self {
+            LeaseError::ServiceError(e) => e.classify(),
+            LeaseError::ProviderError(e) => e.classify(),
+            LeaseError::NotHeld => Class::Permanent,
+        }
+    }
+}
+
+impl<R, T: Service<R>, P: LeaseProvider> Lease<R, T, P> {
+    /// Renews the lease once it expired. Use [`Lease::renew_within`] to
+    /// renew it before that.
+    pub fn new(service: T, provider: P) -> Self {
+        Lease {
+            inner: service,
+            provider,
+            expires: Mutex::new(None),
+            renew_margin: Duration::ZERO,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Renews the lease once less than `margin` is left, so requests don't
+    /// have to wait for it to be acquired again.
+    pub fn renew_within(self, margin: Duration) -> Self {
+        Lease {
+            renew_margin: margin,
+            ..self
+        }
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    /// Whether the lease is held, as of the last time it was acquired.
+    pub fn is_held(&self) -> bool {
+        self.expires
+            .lock()
+            .unwrap()
+            .is_some_and(|expires| Instant::now() < expires)
+    }
+
+    fn needs_renewal(&self, now: Instant) -> bool {
+        self.expires
+            .lock()
+            .unwrap()
+            .is_none_or(|expires| expires.saturating_duration_since(now) <= self.renew_margin)
+    }
+
+    /// Makes sure the lease is held before a request goes through.
+    async fn hold(&self) -> Result<(), LeaseError<T::Error, P::Error>> {
+        let now = Instant::now();
+        if !self.needs_renewal(now) {
+            return Ok(());
+        }
+
+        let acquired = self.provider.acquire().await;
+        let mut expires = self.expires.lock().unwrap();
+        match acquired {
+            Ok(Some(valid_for)) => {
+                *expires = Some(expiry(now, valid_for));
+                Ok(())
+            }
+            Ok(None) => {
+                *expires = None;
+                Err(LeaseError::NotHeld)
+            }
+            // The lease can't be trusted past its expiry
+            Err(e) => match *expires {
+                Some(at) if Instant::now() < at => Ok(()),
+                _ => {
+                    *expires = None;
+                    Err(LeaseError::ProviderError(e))
+                }
+            },
+        }
+    }
+}
+
+/// When a lease valid for `valid_for` from `now` expires. `Instant` has no
+/// maximum, so leases too long for one expire in a century instead.
+fn expiry(now: Instant, valid_for: Duration) -> Instant {
+    const CENTURY: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+    now.checked_add(valid_for).unwrap_or_else(|| now + CENTURY)
+}
+
+impl<R, T: Service<R>, P: LeaseProvider> Service<R> for Lease<R, T, P> {
+    type Response = T::Response;
+    type Error = LeaseError<T::Error, P::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.hold().await?;
+
+        self.inner
+            .request(msg)
+            .await
+            .map_err(LeaseError::ServiceError)
+    }
+}
+
+impl<R, T: Service<R>, P: LeaseProvider> Middleware<R, T> for Lease<R, T, P> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, P: LeaseProvider> Describe for Lease<R, T, P> {
+    fn name(&self) -> &'static str {
+        "lease"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("held", self.is_held().to_string()),
+            ("renew_margin_ms", self.renew_margin.as_millis().to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, P: 'static> FindLayer for Lease<R, T, P> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// A lease shared by the services of one process, held for `ttl` at a time.
+/// Each [`LocalLease::contender`] competes for the same lease.
+pub struct LocalLease {
+    id: u64,
+    ttl: Duration,
+    /// Who holds the lease, and until when.
+    holder: Arc<Mutex<Option<(u64, Instant)>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum LocalLeaseError {}
+
+impl LocalLease {
+    pub fn new(ttl: Duration) -> Self {
+        LocalLease {
+            id: 0,
+            ttl,
+            holder: Arc::new(Mutex::new(None)),
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Another provider of the same lease.
+    pub fn contender(&self) -> Self {
+        LocalLease {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            ttl: self.ttl,
+            holder: self.holder.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+
+    /// Gives the lease up if it's held by this provider, so a contender can
+    /// take it over without waiting for it to expire.
+    pub fn release(&self) {
+        let mut holder = self.holder.lock().unwrap();
+        if holder.is_some_and(|(id, _)| id == self.id) {
+            *holder = None;
+        }
+    }
+}
+
+impl LeaseProvider for LocalLease {
+    type Error = LocalLeaseError;
+
+    async fn acquire(&self) -> Result<Option<Duration>, Self::Error> {
+        let now = Instant::now();
+        let mut holder = self.holder.lock().unwrap();
+        match *holder {
+            Some((id, expires)) if id != self.id && now < expires => Ok(None),
+            _ => {
+                *holder = Some((self.id, expiry(now, self.ttl)));
+                Ok(Some(self.ttl))
+            }
+        }
+    }
+}
+
+impl Classify for LocalLeaseError {
+    fn classify(&self) -> Class {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::time::advance;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestLeaseService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    impl Service<u64> for TestLeaseService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lease_test() {
+        let lease = LocalLease::new(Duration::from_secs(10));
+        let standby = Lease::new(TestLeaseService {}, lease.contender());
+        let active = Lease::new(TestLeaseService {}, lease).renew_within(Duration::from_secs(2));
+
+        assert_eq!(active.request(1).await, Ok(1));
+        assert!(active.is_held());
+        assert_eq!(standby.request(2).await, Err(LeaseError::NotHeld));
+
+        // Renewed before it expired, the standby never gets its chance
+        advance(Duration::from_secs(9)).await;
+        assert_eq!(active.request(3).await, Ok(3));
+        advance(Duration::from_secs(9)).await;
+        assert_eq!(standby.request(4).await, Err(LeaseError::NotHeld));
+
+        // The active instance goes idle, the standby takes over
+        advance(Duration::from_secs(2)).await;
+        assert_eq!(standby.request(5).await, Ok(5));
+        assert_eq!(active.request(6).await, Err(LeaseError::NotHeld));
+        assert!(!active.is_held());
+
+        // Handed over right away when released
+        standby.provider().release();
+        assert_eq!(active.request(7).await, Ok(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lease_forever_test() {
+        let lease = LocalLease::new(Duration::MAX);
+        let standby = Lease::new(TestLeaseService {}, lease.contender());
+        let active = Lease::new(TestLeaseService {}, lease);
+
+        assert_eq!(active.request(1).await, Ok(1));
+        advance(Duration::from_secs(365 * 24 * 60 * 60)).await;
+        assert!(active.is_held());
+        assert_eq!(standby.request(2).await, Err(LeaseError::NotHeld));
+    }
+}
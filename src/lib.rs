@@ -1,19 +1,253 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod boxed;
+pub mod cancel;
+#[cfg(feature = "channel")]
+pub mod channel;
+pub mod classify;
+#[cfg(any(feature = "timeout", feature = "retry_wait"))]
+pub mod clock;
+pub mod combinators;
+#[cfg(feature = "concurrency_limit")]
+pub mod concurrency_limit;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "consumer")]
+pub mod consumer;
+#[cfg(feature = "dead_letter")]
+pub mod dead_letter;
+#[cfg(feature = "deadpool")]
+pub mod deadpool;
+#[cfg(feature = "dedup")]
+pub mod dedup;
+pub mod describe;
+pub mod either;
+pub mod error;
+pub mod events;
+#[cfg(feature = "fan_in")]
+pub mod fan_in;
+#[cfg(feature = "fixed_window")]
+pub mod fixed_window;
+#[cfg(feature = "hook")]
+pub mod hook;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "in_flight")]
+pub mod in_flight;
+#[cfg(feature = "ingest")]
+pub mod ingest;
+#[cfg(feature = "keyed_lock")]
+pub mod keyed_lock;
+pub mod layer;
+#[cfg(feature = "lazy")]
+pub mod lazy;
+#[cfg(feature = "lease")]
+pub mod lease;
+pub mod load;
+pub mod meta;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "multiplex")]
+pub mod multiplex;
+#[cfg(feature = "ordered")]
+pub mod ordered;
+#[cfg(feature = "outbox")]
+pub mod outbox;
+#[cfg(feature = "presets")]
+pub mod presets;
+pub mod priority;
 #[cfg(feature = "rate_limit")]
 pub mod rate_limit;
 #[cfg(feature = "restart")]
 pub mod restart;
+pub mod resume;
 #[cfg(feature = "retry")]
 pub mod retry;
+#[cfg(feature = "retry_queue")]
+pub mod retry_queue;
+#[cfg(feature = "saga")]
+pub mod saga;
+#[cfg(feature = "scatter")]
+pub mod scatter;
+#[cfg(feature = "shed")]
+pub mod shed;
+pub mod shutdown;
+#[cfg(feature = "spawn")]
+pub mod spawn;
+#[cfg(feature = "stream")]
+pub mod stream;
+pub mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
 #[cfg(feature = "timeout")]
 pub mod timeout;
 
+#[cfg(any(feature = "timeout", feature = "retry_wait"))]
+mod time;
+
+pub use combinators::service_fn;
+
+// Lets the derive macros' `::jenga` paths resolve in this crate's tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as jenga;
+
+/// An asynchronous function from a `Request` to a `Response`.
+///
+/// Neither the trait nor the built-in middlewares require services or their
+/// futures to be `Send`, so `Rc`-based services work as well, e.g. inside a
+/// Tokio `LocalSet` or on thread-per-core runtimes. Whether a stack is `Send`
+/// only depends on the services it's made of.
+///
+/// The `request()` futures of every built-in middleware are `Send` whenever the
+/// inner services (and their futures), requests, responses and errors are. For
+/// `Restart`, the inner service also needs to be `Sync`. Stable Rust can't
+/// express this as a bound on generic code, so it's enforced by compile-time
+/// tests on concrete stacks instead.
+///
+/// Errors are `'static` so middlewares can expose them as the
+/// [`source()`](core::error::Error::source) of their own errors, see
+/// [`error`].
 #[allow(async_fn_in_trait)]
 pub trait Service<Request> {
     type Response;
-    type Error: core::error::Error;
+    type Error: core::error::Error + 'static;
     async fn request(&self, msg: Request) -> Result<Self::Response, Self::Error>;
 }
 
 pub trait Middleware<R, S: Service<R>>: Service<R> {
     fn inner_service(&self) -> &S;
 }
+
+/// References and smart pointers to a service are services too, so a
+/// service can be shared (e.g. behind an `Arc` across tasks) without a
+/// wrapper type.
+macro_rules! deref_service {
+    ($($pointer:ty),+) => {
+        $(
+            impl<R, S: Service<R> + ?Sized> Service<R> for $pointer {
+                type Response = S::Response;
+                type Error = S::Error;
+
+                async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+                    (**self).request(msg).await
+                }
+            }
+        )+
+    };
+}
+
+deref_service!(&S, Box<S>, std::rc::Rc<S>, std::sync::Arc<S>);
+
+#[cfg(test)]
+mod send_tests {
+    use std::future::Future;
+
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestSendService {}
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<u64> for TestSendService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            Ok(msg)
+        }
+    }
+
+    fn assert_send<F: Future + Send>(_: F) {}
+
+    /// Only needs to compile, the futures are never polled
+    #[test]
+    fn futures_are_send() {
+        assert_send(TestSendService {}.request(1));
+        assert_send(std::sync::Arc::new(TestSendService {}).request(1));
+
+        #[cfg(feature = "rate_limit")]
+        assert_send(rate_limit::RateLimit::<1, _, _>::new(TestSendService {}).request(1));
+
+        #[cfg(feature = "retry")]
+        assert_send(retry::Retry::<1, _, _>::instant(TestSendService {}).request(1));
+
+        #[cfg(feature = "concurrency_limit")]
+        assert_send(
+            concurrency_limit::ConcurrencyLimit::new(
+                TestSendService {},
+                concurrency_limit::Aimd::new(1, 1, 1),
+            )
+            .request(1),
+        );
+
+        #[cfg(feature = "in_flight")]
+        assert_send(in_flight::InFlight::new(TestSendService {}).request(1));
+
+        #[cfg(feature = "keyed_lock")]
+        assert_send(
+            keyed_lock::KeyedLock::new(TestSendService {}, |msg: &u64| *msg % 2).request(1),
+        );
+
+        #[cfg(feature = "lazy")]
+        assert_send(lazy::Lazy::new(TestGeneratorService {}, ()).request(1));
+
+        #[cfg(feature = "timeout")]
+        assert_send(
+            timeout::Timeout::new(TestSendService {}, std::time::Duration::ZERO).request(1),
+        );
+
+        #[cfg(feature = "channel")]
+        assert_send(
+            channel::ChannelService::<u64, u64, EmptyError>::new(1)
+                .0
+                .request(1),
+        );
+
+        #[cfg(feature = "spawn")]
+        assert_send(spawn::spawn_service(TestSendService {}).request(1));
+
+        #[cfg(feature = "testing")]
+        assert_send(testing::SpyService::new(TestSendService {}).request(1));
+
+        #[cfg(all(feature = "rate_limit", feature = "retry", feature = "timeout"))]
+        assert_send(
+            rate_limit::RateLimit::<1, _, _>::new(retry::Retry::<1, _, _>::instant(
+                timeout::Timeout::new(TestSendService {}, std::time::Duration::ZERO),
+            ))
+            .request(1),
+        );
+    }
+
+    #[cfg(any(feature = "lazy", feature = "restart"))]
+    pub struct TestGeneratorService {}
+
+    #[cfg(any(feature = "lazy", feature = "restart"))]
+    impl Service<()> for TestGeneratorService {
+        type Response = TestSendService;
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(TestSendService {})
+        }
+    }
+
+    #[cfg(feature = "restart")]
+    #[test]
+    fn restart_futures_are_send() {
+        assert_send(restart::Restart::<u64, _, _, _, _, _, _>::new(
+            TestGeneratorService {},
+            (),
+        ));
+
+        assert_send(async {
+            let restart = restart::Restart::new(TestGeneratorService {}, ())
+                .await
+                .unwrap();
+            restart.request(1).await
+        });
+    }
+}
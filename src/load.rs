@@ -0,0 +1,164 @@
+//! Load of a service, for the layers that spread or shed requests.
+//!
+//! The layers that limit or queue requests know how busy their service is:
+//! requests in flight, requests waiting for their turn, and how many can
+//! be in flight at most. [`Load`] exposes it, so load balancers (P2C,
+//! Peak-EWMA...) can pick the least loaded service, and load shedders can
+//! tell when to start rejecting.
+//!
+//! [`Load::is_ready`] is the short answer: whether a request sent now would
+//! be accepted right away, rather than rejected by a limiter or left waiting
+//! for room in a channel. Callers can hold back their work until it is, to
+//! apply backpressure to their own sources. It's only a hint, other callers
+//! may take the room first.
+//!
+//! Some layers let requests through depending on the request itself, like
+//! `Dedup` rejecting duplicates. [`Admit::try_request`] tells whether a given
+//! request would be let through by the layer right now, without sending it,
+//! e.g. before building an expensive request or taking it off a queue.
+
+use std::time::Duration;
+
+/// How busy a service is, at the time it was asked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct LoadMetric {
+    /// Requests being processed.
+    pub in_flight: usize,
+    /// Requests waiting to be processed.
+    pub queued: usize,
+    /// Requests that can be in flight at most, if there's a limit.
+    pub capacity: Option<usize>,
+}
+
+impl LoadMetric {
+    /// Requests either in flight or queued, what balancers usually compare.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.in_flight + self.queued
+    }
+
+    /// Share of the capacity in use, from 0 (idle) to 1 (full), `None`
+    /// without a limit.
+    pub fn utilization(&self) -> Option<f64> {
+        self.capacity.map(|capacity| match capacity {
+            0 => 1.0,
+            capacity => (self.in_flight as f64 / capacity as f64).min(1.0),
+        })
+    }
+
+    /// Whether another request can be in flight, always the case without a
+    /// limit.
+    #[inline]
+    pub fn has_capacity(&self) -> bool {
+        self.capacity
+            .is_none_or(|capacity| self.in_flight < capacity)
+    }
+}
+
+pub trait Load {
+    fn load(&self) -> LoadMetric;
+
+    /// Whether a request sent now would be accepted without waiting, see the
+    /// [module docs](self).
+    fn is_ready(&self) -> bool {
+        self.load().has_capacity()
+    }
+}
+
+impl<L: Load + ?Sized> Load for &L {
+    fn load(&self) -> LoadMetric {
+        (**self).load()
+    }
+
+    fn is_ready(&self) -> bool {
+        (**self).is_ready()
+    }
+}
+
+impl<L: Load + ?Sized> Load for std::sync::Arc<L> {
+    fn load(&self) -> LoadMetric {
+        (**self).load()
+    }
+
+    fn is_ready(&self) -> bool {
+        (**self).is_ready()
+    }
+}
+
+/// Whether a layer would let a request through, see [`Admit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Admission {
+    Admitted,
+    /// Rejected, until `retry_after` has passed if the layer knows when.
+    Rejected {
+        retry_after: Option<Duration>,
+    },
+}
+
+impl Admission {
+    #[inline]
+    pub fn is_admitted(&self) -> bool {
+        matches!(self, Admission::Admitted)
+    }
+}
+
+/// Layers that can tell ahead of time whether they'd reject a request. Only
+/// the layer itself is checked, not the ones below it, which can be found
+/// with [`FindLayer`](crate::describe::FindLayer).
+pub trait Admit<R> {
+    fn try_request(&self, msg: &R) -> Admission;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_metric_test() {
+        let load = LoadMetric {
+            in_flight: 3,
+            queued: 2,
+            capacity: Some(4),
+        };
+        assert_eq!(load.pending(), 5);
+        assert_eq!(load.utilization(), Some(0.75));
+        assert!(load.has_capacity());
+
+        assert_eq!(LoadMetric::default().utilization(), None);
+        let full = LoadMetric {
+            capacity: Some(0),
+            ..LoadMetric::default()
+        };
+        assert_eq!(full.utilization(), Some(1.0));
+        assert!(!full.has_capacity());
+    }
+
+    #[cfg(all(feature = "channel", not(loom)))]
+    #[tokio::test]
+    async fn channel_load_test() {
+        use thiserror::Error;
+
+        use crate::{channel::ChannelService, Service};
+
+        #[derive(Debug, Error)]
+        pub enum EmptyError {}
+
+        let (service, worker) = ChannelService::<u64, u64, EmptyError>::new(2);
+        let queued = async {
+            tokio::task::yield_now().await;
+            (service.load(), service.is_ready())
+        };
+
+        // The worker never runs, the requests stay in the channel
+        assert!(service.is_ready());
+        tokio::select! {
+            _ = async { tokio::join!(service.request(1), service.request(2)) } => unreachable!(),
+            (load, ready) = queued => assert_eq!((load.queued, ready), (2, false)),
+        }
+
+        // Never ready once the worker is gone
+        drop(worker);
+        assert!(!service.is_ready());
+        assert!(service.ready().await.is_err());
+    }
+}
@@ -0,0 +1,533 @@
+//! Request metadata shared by every layer of a stack.
+//!
+//! A [`WithMeta`] request carries a [`Meta`] next to the request itself:
+//! deadline, trace context, priority, idempotency and tenant keys. The
+//! outermost layer of the stack, a [`MetaService`], unwraps it and makes the
+//! metadata [`current`] while the rest of the stack runs, so every layer
+//! below it, and the leaf service, still take the bare request.
+//!
+//! The built-in middlewares read it transparently:
+//! - `Timeout` never waits past the deadline,
+//! - `Retry` doesn't start another attempt once the deadline has passed,
+//! - `DeadlineShed` rejects the requests that can't finish before it,
+//! - `KeyedLock` doesn't wait for its lock past the deadline,
+//! - `Retry` stops once the request is [cancelled](crate::cancel),
+//! - `ChannelService` (and so `spawn`) carries it over to its worker.
+//!
+//! The deadline doesn't have to come from the caller: a [`Deadline`] layer
+//! gives every request a budget from the time it reaches the layer, and the
+//! layers below share what's left of it instead of each starting its own
+//! timer. A request's own deadline is kept when it's earlier.
+//!
+//! Deadlines are compared against Tokio's clock when the `timeout` or
+//! `retry_wait` feature is enabled, and against the standard one otherwise.
+//! Both tell the same time unless Tokio's is paused, so it only matters in
+//! tests pausing time: since features are unified across a build, a
+//! dependency enabling one of them is enough to switch clocks, and such
+//! tests should build their deadlines from `tokio::time::Instant::now()`.
+//!
+//! Its [`Overrides`] change the stack's policies for a single request, so one
+//! stack serves every combination of them:
+//! - `Timeout` waits for [`Overrides::timeout`] instead of its own duration,
+//! - `Retry` makes at most [`Overrides::max_retries`] retries, waiting
+//!   [`Overrides::retry_wait`] between them,
+//! - `Dedup` lets the requests with [`Overrides::skip_dedup`] through.
+//!
+//! Anything else the application needs next to the request, like the
+//! authenticated user, goes in its [`Extensions`], a map holding one value
+//! per type, and is read the same way from any layer or the leaf service.
+//!
+//! The metadata is current while the request's future is polled, so tasks
+//! spawned from within the stack don't see it unless they use [`scope`].
+
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cancel::CancelToken,
+    describe::{Describe, FindLayer, Stack},
+    priority::{Level, Priority},
+    Middleware, Service,
+};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Meta {
+    /// When the caller stops waiting for the response.
+    pub deadline: Option<Instant>,
+    pub trace: Option<TraceContext>,
+    pub priority: Option<Level>,
+    /// Identifies retries of the same logical request to the service.
+    pub idempotency_key: Option<Arc<str>>,
+    /// Who the request is made for, in multi-tenant services.
+    pub tenant: Option<Arc<str>>,
+    pub overrides: Overrides,
+    /// Stands for the caller, see [`cancel`](crate::cancel).
+    pub cancel: Option<CancelToken>,
+    pub extensions: Extensions,
+}
+
+/// Policies of the stack changed for a single request, see the
+/// [module docs](self).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Overrides {
+    /// Replaces the duration of `Timeout`. The deadline still applies.
+    pub timeout: Option<Duration>,
+    /// Caps the retries of `Retry`, `Some(0)` for none at all.
+    pub max_retries: Option<usize>,
+    /// Replaces the wait of `Retry` between two attempts.
+    pub retry_wait: Option<Duration>,
+    /// Neither rejected as a duplicate nor answered with a replayed
+    /// response by `Dedup`.
+    pub skip_dedup: bool,
+}
+
+/// Values of any type attached to a request, one per type.
+///
+/// Cloning it is cheap, the clones sharing the values. Two maps are equal if
+/// they share the same values, not just equal ones.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: BTreeMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Attaches `value`, replacing the previous value of its type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> bool {
+        self.values.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl PartialEq for Extensions {
+    fn eq(&self, other: &Self) -> bool {
+        self.values.len() == other.values.len()
+            && self
+                .values
+                .iter()
+                .zip(&other.values)
+                .all(|(a, b)| a.0 == b.0 && Arc::ptr_eq(a.1, b.1))
+    }
+}
+
+/// The values can't be printed, only how many there are.
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// W3C trace context of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub parent_id: u64,
+    pub sampled: bool,
+}
+
+impl Meta {
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Level) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    pub fn with_idempotency_key(mut self, key: impl Into<Arc<str>>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: impl Into<Arc<str>>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    pub fn with_cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    pub fn with_extension<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.overrides.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> Self {
+        self.overrides.max_retries = Some(max_retries);
+        self
+    }
+
+    pub fn with_retry_wait(mut self, wait: Duration) -> Self {
+        self.overrides.retry_wait = Some(wait);
+        self
+    }
+
+    pub fn without_retries(self) -> Self {
+        self.with_max_retries(0)
+    }
+
+    pub fn skipping_dedup(mut self) -> Self {
+        self.overrides.skip_dedup = true;
+        self
+    }
+
+    /// Time left before the deadline as of `now`, zero once it has passed.
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+}
+
+/// A request along with its metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithMeta<R> {
+    pub request: R,
+    pub meta: Arc<Meta>,
+}
+
+impl<R> WithMeta<R> {
+    pub fn new(request: R, meta: Meta) -> Self {
+        WithMeta {
+            request,
+            meta: Arc::new(meta),
+        }
+    }
+}
+
+impl<R> Priority for WithMeta<R> {
+    fn priority(&self) -> Level {
+        self.meta.priority.unwrap_or_default()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Meta>>> = const { RefCell::new(None) };
+}
+
+/// Metadata of the request being processed, if any.
+pub fn current() -> Option<Arc<Meta>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// What every layer compares deadlines against, for all of them to agree on
+/// the time left. It's the clock of the system timers of `Timeout` and
+/// `Retry`, Tokio's when they're built, so pausing it in tests moves the
+/// deadlines along. There's no clock on wasm, so deadlines are ignored there.
+///
+/// Layers given another [`Clock`](crate::clock::Clock) only use it for their
+/// own timers. See the [module docs](self) for what the features change.
+fn now() -> Option<Instant> {
+    if cfg!(target_arch = "wasm32") {
+        return None;
+    }
+
+    #[cfg(any(feature = "timeout", feature = "retry_wait"))]
+    return crate::clock::TimeSource::System.now();
+    #[cfg(not(any(feature = "timeout", feature = "retry_wait")))]
+    return Some(Instant::now());
+}
+
+/// Time left before the deadline of the current request, if it has one.
+#[allow(dead_code)]
+pub(crate) fn remaining() -> Option<Duration> {
+    current()
+        .zip(now())
+        .and_then(|(meta, now)| meta.remaining(now))
+}
+
+/// Restores the previous metadata, even if polling panics.
+struct Enter {
+    previous: Option<Arc<Meta>>,
+}
+
+impl Enter {
+    fn new(meta: &Option<Arc<Meta>>) -> Self {
+        let previous = CURRENT.with(|current| current.replace(meta.clone()));
+        Enter { previous }
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// Runs `future` with `meta` as the [`current`] metadata.
+pub async fn scope<F: Future>(meta: Option<Arc<Meta>>, future: F) -> F::Output {
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        let _enter = Enter::new(&meta);
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Entry point of a stack taking [`WithMeta`] requests, see the
+/// [module docs](self).
+pub struct MetaService<R, T: Service<R>> {
+    inner: T,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> MetaService<R, T> {
+    pub fn new(service: T) -> Self {
+        MetaService {
+            inner: service,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R>> Service<WithMeta<R>> for MetaService<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: WithMeta<R>) -> Result<Self::Response, Self::Error> {
+        scope(Some(msg.meta), self.inner.request(msg.request)).await
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for MetaService<R, T> {
+    fn name(&self) -> &'static str {
+        "meta"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for MetaService<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Gives the requests a deadline for the layers below, see the
+/// [module docs](self).
+pub struct Deadline<R, T: Service<R>> {
+    inner: T,
+    budget: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> Deadline<R, T> {
+    /// Requests have `budget` from the time they reach this layer.
+    pub fn new(service: T, budget: Duration) -> Self {
+        Deadline {
+            inner: service,
+            budget,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for Deadline<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        // A budget too large for an `Instant` is no deadline at all
+        let Some(deadline) = now().and_then(|now| now.checked_add(self.budget)) else {
+            return self.inner.request(msg).await;
+        };
+
+        let mut meta = current().map_or_else(Meta::default, |meta| (*meta).clone());
+        meta.deadline = Some(meta.deadline.map_or(deadline, |own| own.min(deadline)));
+        scope(Some(Arc::new(meta)), self.inner.request(msg)).await
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for Deadline<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for Deadline<R, T> {
+    fn name(&self) -> &'static str {
+        "deadline"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("budget_ms", self.budget.as_millis().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Deadline<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestMetaService {}
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestMetaService {
+        type Response = Option<Arc<str>>;
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(current().and_then(|meta| meta.tenant.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn meta_test() {
+        let service = MetaService::new(TestMetaService {});
+
+        let request = WithMeta::new((), Meta::default().with_tenant("acme"));
+        assert_eq!(
+            service.request(request).await.unwrap().as_deref(),
+            Some("acme")
+        );
+
+        // Not leaked past the request
+        assert!(current().is_none());
+        assert_eq!(service.inner_service().request(()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn extensions_test() {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        struct UserId(u64);
+
+        let service = MetaService::new(crate::combinators::service_fn(|_msg: ()| async {
+            let user = current().and_then(|meta| meta.extensions.get::<UserId>().copied());
+            Ok::<_, EmptyError>(user)
+        }));
+
+        let meta = Meta::default().with_extension(UserId(7));
+        assert_eq!(meta.extensions.get::<u64>(), None);
+        assert_eq!(meta, meta.clone());
+        assert_ne!(meta, Meta::default().with_extension(UserId(7)));
+
+        let request = WithMeta::new((), meta);
+        assert_eq!(service.request(request).await.unwrap(), Some(UserId(7)));
+
+        let mut extensions = Extensions::default();
+        extensions.insert(UserId(1));
+        extensions.insert(UserId(2));
+        assert_eq!(extensions.get::<UserId>(), Some(&UserId(2)));
+        assert!(extensions.remove::<UserId>());
+        assert!(extensions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deadline_test() {
+        let service = MetaService::new(Deadline::new(
+            crate::combinators::service_fn(|_msg: ()| async {
+                Ok::<_, EmptyError>(current().and_then(|meta| meta.deadline))
+            }),
+            Duration::from_secs(10),
+        ));
+
+        let before = Instant::now();
+        let deadline = service.request(WithMeta::new((), Meta::default())).await;
+        let deadline = deadline.unwrap().unwrap();
+        assert!(deadline >= before + Duration::from_secs(10));
+        assert!(deadline <= Instant::now() + Duration::from_secs(10));
+
+        // An earlier deadline of the request is kept
+        let own = Instant::now() + Duration::from_secs(1);
+        let request = WithMeta::new((), Meta::default().with_deadline(own));
+        assert_eq!(service.request(request).await.unwrap(), Some(own));
+
+        // No deadline of its own with an unbounded budget
+        let service = MetaService::new(Deadline::new(
+            crate::combinators::service_fn(|_msg: ()| async {
+                Ok::<_, EmptyError>(current().and_then(|meta| meta.deadline))
+            }),
+            Duration::MAX,
+        ));
+        let request = WithMeta::new((), Meta::default());
+        assert_eq!(service.request(request).await.unwrap(), None);
+        let request = WithMeta::new((), Meta::default().with_deadline(own));
+        assert_eq!(service.request(request).await.unwrap(), Some(own));
+    }
+
+    #[cfg(feature = "timeout")]
+    #[tokio::test(start_paused = true)]
+    async fn deadline_paused_clock_test() {
+        use crate::timeout::{Timeout, TimeoutError};
+
+        let service = MetaService::new(Deadline::new(
+            Timeout::new(
+                crate::combinators::service_fn(|_msg: ()| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok::<_, EmptyError>(())
+                }),
+                Duration::from_secs(1),
+            ),
+            Duration::from_millis(50),
+        ));
+
+        // Both layers follow the paused clock, however far it is from the system's
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            service.request(WithMeta::new((), Meta::default())).await,
+            Err(TimeoutError::TimeoutError)
+        ));
+        assert_eq!(started.elapsed(), Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,451 @@
+//! Metrics of the built-in middlewares, emitted through the
+//! [`metrics`](::metrics) facade.
+//!
+//! Nothing is recorded unless a recorder (e.g. a Prometheus exporter) has been
+//! installed. Every metric has a `layer` label with the name of the middleware
+//! (`count_items`, `in_flight`, `keyed_lock`, `rate_limit`, `restart`,
+//! `retry` or `timeout`), or whatever name [`ClassifiedMetrics`] was given.
+//!
+//! There's no label for the stack itself, since a layer doesn't know which
+//! stack it's part of: give each stack a [`ClassifiedMetrics`] of its own
+//! name, or set a global label on the recorder for the whole process.
+//!
+//! | Name | Type | Extra labels | Description |
+//! |------|------|--------------|-------------|
+//! | `jenga_requests_total` | counter | `outcome`, `error_kind` or `class` | Requests that went through the layer |
+//! | `jenga_request_duration_seconds` | histogram | | Time spent in the layer, inner layers included |
+//! | `jenga_retries_total` | counter | | Attempts made by `Retry` after the first one |
+//! | `jenga_retries_exhausted_total` | counter | | Requests that failed after using every retry |
+//! | `jenga_retries_recovered_total` | counter | | Requests that succeeded thanks to a retry |
+//! | `jenga_retries_budget_exhausted_total` | counter | | Requests given up on because another attempt couldn't finish before the deadline |
+//! | `jenga_in_flight` | gauge | | Requests currently being processed by `RateLimit`, or anywhere below `InFlight` |
+//! | `jenga_restarts_total` | counter | `outcome` | Attempts made by `Restart` to replace its service |
+//! | `jenga_phase_duration_seconds` | histogram | `layer`, `phase` | Time spent by the layer in one phase of a request |
+//! | `jenga_stream_items_total` | counter | | Items of the response streams going through `CountItems` |
+//! | `jenga_keyed_lock_queue_length` | histogram | | Requests already holding or waiting for the lock of its key when a request reaches `KeyedLock` |
+//! | `jenga_keyed_lock_wait_seconds` | histogram | | Time requests waited for the lock of their key |
+//!
+//! `outcome` is either `ok` or `error`. `error_kind` is only set on errors, to
+//! `service` when the error comes from the inner service, or to the error of the
+//! layer itself (`rate_limited`, `restart_failed`, `timeout`).
+//!
+//! `phase` attributes the time spent in a layer: `inner` for each call of the
+//! inner service (so once per attempt for `Retry`), `backoff` for the waits of
+//! `Retry` between attempts and `restart` for `Restart` replacing its service.
+//! Whatever `jenga_request_duration_seconds` has on top of these phases is the
+//! overhead of the layer itself.
+//!
+//! The built-in layers only tell their own errors apart. On top of a stack
+//! whose error implements [`Classify`], [`ClassifiedMetrics`] records
+//! `jenga_requests_total` and `jenga_request_duration_seconds` for the whole
+//! stack, under the layer name it's given, with a `class` label on errors
+//! (`transient`, `permanent`, `throttled` or `cancelled`) instead of
+//! `error_kind`. [`ClassifiedMetrics::with_label`] adds a label of its own
+//! to the errors. Its values should come from a small set, like the class,
+//! rather than from the error's message, or every message becomes a new
+//! series.
+
+#[cfg(any(
+    feature = "rate_limit",
+    feature = "restart",
+    feature = "retry",
+    feature = "timeout"
+))]
+use {
+    ::metrics::{counter, histogram},
+    std::time::Instant,
+};
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use crate::{
+    classify::Classify,
+    describe::{Describe, FindLayer, Stack},
+    Middleware, Service,
+};
+
+pub const REQUESTS: &str = "jenga_requests_total";
+pub const REQUEST_DURATION: &str = "jenga_request_duration_seconds";
+pub const RETRIES: &str = "jenga_retries_total";
+pub const RETRIES_EXHAUSTED: &str = "jenga_retries_exhausted_total";
+pub const RETRIES_RECOVERED: &str = "jenga_retries_recovered_total";
+pub const RETRIES_BUDGET_EXHAUSTED: &str = "jenga_retries_budget_exhausted_total";
+pub const IN_FLIGHT: &str = "jenga_in_flight";
+pub const RESTARTS: &str = "jenga_restarts_total";
+pub const PHASE_DURATION: &str = "jenga_phase_duration_seconds";
+pub const STREAM_ITEMS: &str = "jenga_stream_items_total";
+pub const KEYED_LOCK_QUEUE: &str = "jenga_keyed_lock_queue_length";
+pub const KEYED_LOCK_WAIT: &str = "jenga_keyed_lock_wait_seconds";
+
+/// Records the time spent in a phase of a request when dropped, so phases
+/// cut short (e.g. by a timeout) are recorded too.
+#[cfg(any(
+    feature = "rate_limit",
+    feature = "restart",
+    feature = "retry",
+    feature = "timeout"
+))]
+pub(crate) struct Phase {
+    layer: &'static str,
+    phase: &'static str,
+    /// `None` on wasm, where there's no clock.
+    started: Option<Instant>,
+}
+
+#[cfg(any(
+    feature = "rate_limit",
+    feature = "restart",
+    feature = "retry",
+    feature = "timeout"
+))]
+impl Phase {
+    pub(crate) fn start(layer: &'static str, phase: &'static str) -> Self {
+        Phase {
+            layer,
+            phase,
+            started: (!cfg!(target_arch = "wasm32")).then(Instant::now),
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "rate_limit",
+    feature = "restart",
+    feature = "retry",
+    feature = "timeout"
+))]
+impl Drop for Phase {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            histogram!(PHASE_DURATION, "layer" => self.layer, "phase" => self.phase)
+                .record(started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Records the outcome and duration of a request that went through `layer`.
+/// The duration is left out without a clock to measure it, on wasm.
+#[cfg(any(
+    feature = "rate_limit",
+    feature = "restart",
+    feature = "retry",
+    feature = "timeout"
+))]
+pub(crate) fn record_request<T, E>(
+    layer: &'static str,
+    started: Option<Instant>,
+    res: &Result<T, E>,
+    error_kind: impl FnOnce(&E) -> &'static str,
+) {
+    match res {
+        Ok(_) => counter!(REQUESTS, "layer" => layer, "outcome" => "ok").increment(1),
+        Err(e) => counter!(
+            REQUESTS,
+            "layer" => layer,
+            "outcome" => "error",
+            "error_kind" => error_kind(e)
+        )
+        .increment(1),
+    }
+
+    if let Some(started) = started {
+        histogram!(REQUEST_DURATION, "layer" => layer).record(started.elapsed().as_secs_f64());
+    }
+}
+
+/// Key of a label, and how to get its value from an error.
+type ErrorLabel<E> = (&'static str, fn(&E) -> &'static str);
+
+/// Records the outcomes of the stack below by class of failure, see the
+/// [module docs](self).
+pub struct ClassifiedMetrics<R, T: Service<R>> {
+    inner: T,
+    layer: &'static str,
+    label: Option<ErrorLabel<T::Error>>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> ClassifiedMetrics<R, T> {
+    /// Records under the `layer` label `layer`, e.g. the name of the stack.
+    pub fn new(service: T, layer: &'static str) -> Self {
+        ClassifiedMetrics {
+            inner: service,
+            layer,
+            label: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Labels the errors with `key`, its value given by `value`.
+    pub fn with_label(self, key: &'static str, value: fn(&T::Error) -> &'static str) -> Self {
+        ClassifiedMetrics {
+            label: Some((key, value)),
+            ..self
+        }
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for ClassifiedMetrics<R, T>
+where
+    T::Error: Classify,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let started = (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
+        let res = self.inner.request(msg).await;
+
+        match &res {
+            Ok(_) => {
+                ::metrics::counter!(REQUESTS, "layer" => self.layer, "outcome" => "ok").increment(1)
+            }
+            Err(e) => {
+                let mut labels = vec![
+                    ("layer", self.layer),
+                    ("outcome", "error"),
+                    ("class", e.classify().as_str()),
+                ];
+                if let Some((key, value)) = self.label {
+                    labels.push((key, value(e)));
+                }
+                ::metrics::counter!(REQUESTS, &labels).increment(1);
+            }
+        }
+        if let Some(started) = started {
+            ::metrics::histogram!(REQUEST_DURATION, "layer" => self.layer)
+                .record(started.elapsed().as_secs_f64());
+        }
+
+        res
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for ClassifiedMetrics<R, T>
+where
+    T::Error: Classify,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for ClassifiedMetrics<R, T> {
+    fn name(&self) -> &'static str {
+        "classified_metrics"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("layer", self.layer.to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for ClassifiedMetrics<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use ::metrics::{
+        with_local_recorder, Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName,
+        Metadata, Recorder, SharedString, Unit,
+    };
+    use thiserror::Error;
+    use tokio::runtime::Builder;
+
+    use super::*;
+    use crate::classify::Class;
+
+    /// Only keeps track of counters and how many samples histograms got,
+    /// by name and labels
+    #[derive(Default)]
+    pub struct TestRecorder {
+        counters: Mutex<HashMap<String, Arc<TestCounter>>>,
+    }
+
+    #[derive(Default)]
+    pub struct TestCounter(Mutex<u64>);
+
+    impl CounterFn for TestCounter {
+        fn increment(&self, value: u64) {
+            *self.0.lock().unwrap() += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            *self.0.lock().unwrap() = value;
+        }
+    }
+
+    impl HistogramFn for TestCounter {
+        fn record(&self, _value: f64) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    impl TestRecorder {
+        fn entry(&self, key: &Key) -> Arc<TestCounter> {
+            let labels: Vec<String> = key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            let name = format!("{}{{{}}}", key.name(), labels.join(","));
+
+            self.counters
+                .lock()
+                .unwrap()
+                .entry(name)
+                .or_default()
+                .clone()
+        }
+
+        fn counter(&self, key: &str) -> u64 {
+            self.counters
+                .lock()
+                .unwrap()
+                .get(key)
+                .map_or(0, |counter| *counter.0.lock().unwrap())
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.entry(key))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.entry(key))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TestMetricsService {
+        counter: Mutex<usize>,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    impl Service<()> for TestMetricsService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            let mut counter_lock = self.counter.lock().unwrap();
+            *counter_lock += 1;
+            if *counter_lock < 3 {
+                Err(FakeError::Error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Classify for FakeError {
+        fn classify(&self) -> Class {
+            Class::Throttled
+        }
+    }
+
+    #[test]
+    fn classified_metrics_test() {
+        let recorder = TestRecorder::default();
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let service = ClassifiedMetrics::new(
+            TestMetricsService {
+                counter: Mutex::new(0),
+            },
+            "db",
+        )
+        .with_label("endpoint", |_| "users");
+
+        with_local_recorder(&recorder, || {
+            for _ in 0..3 {
+                let _ = runtime.block_on(service.request(()));
+            }
+        });
+
+        assert_eq!(
+            recorder.counter(
+                "jenga_requests_total{layer=db,outcome=error,class=throttled,endpoint=users}"
+            ),
+            2
+        );
+        assert_eq!(
+            recorder.counter("jenga_requests_total{layer=db,outcome=ok}"),
+            1
+        );
+        assert_eq!(
+            recorder.counter("jenga_request_duration_seconds{layer=db}"),
+            3
+        );
+    }
+
+    #[cfg(feature = "retry")]
+    #[test]
+    fn metrics_test() {
+        use crate::retry::Retry;
+
+        let recorder = TestRecorder::default();
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let service = Retry::<1, _, _>::instant(TestMetricsService {
+            counter: Mutex::new(0),
+        });
+
+        with_local_recorder(&recorder, || {
+            // Fails twice, then succeeds on the first try of the second request
+            assert!(runtime.block_on(service.request(())).is_err());
+            assert!(runtime.block_on(service.request(())).is_ok());
+        });
+
+        assert_eq!(recorder.counter("jenga_retries_total{layer=retry}"), 1);
+        assert_eq!(
+            recorder.counter("jenga_retries_exhausted_total{layer=retry}"),
+            1
+        );
+        assert_eq!(
+            recorder.counter("jenga_requests_total{layer=retry,outcome=ok}"),
+            1
+        );
+        assert_eq!(
+            recorder.counter("jenga_retries_recovered_total{layer=retry}"),
+            0
+        );
+        assert_eq!(
+            recorder.counter("jenga_requests_total{layer=retry,outcome=error,error_kind=service}"),
+            1
+        );
+
+        // One sample per attempt, and per wait in between
+        assert_eq!(
+            recorder.counter("jenga_phase_duration_seconds{layer=retry,phase=inner}"),
+            3
+        );
+        #[cfg(feature = "retry_wait")]
+        assert_eq!(
+            recorder.counter("jenga_phase_duration_seconds{layer=retry,phase=backoff}"),
+            1
+        );
+    }
+}
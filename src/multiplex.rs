@@ -0,0 +1,269 @@
+//! Concurrent requests over a single connection.
+//!
+//! Protocols like Redis, AMQP or STOMP send many requests over one
+//! connection and tag each response with the ID of its request. [`Multiplex`]
+//! gives every request a correlation ID and writes it on the connection,
+//! while [`Demux`] reads the responses and hands each one to the caller
+//! waiting for its ID. The connection itself is two services: a writer taking
+//! `(id, request)`, and a reader returning the next `(id, response)`, or
+//! `None` once the connection is closed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    sync::PoisonError,
+};
+
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromClosed, IntoFlat},
+    sync::{Arc, AtomicU64, Mutex, Ordering},
+    Service,
+};
+
+/// Callers waiting for a response, by correlation ID. `None` once the
+/// [`Demux`] stopped.
+type Waiting<Resp> = Mutex<Option<HashMap<u64, oneshot::Sender<Resp>>>>;
+
+/// Writing side of the connection, that tags requests with a correlation ID
+/// before sending them with the writer service `W`.
+pub struct Multiplex<R, Resp, W> {
+    writer: W,
+    next_id: AtomicU64,
+    waiting: Arc<Waiting<Resp>>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// Reading side of the connection, that hands responses to their callers.
+pub struct Demux<Resp> {
+    waiting: Arc<Waiting<Resp>>,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum MultiplexError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("connection is closed")]
+    Closed,
+}
+
+impl<E: core::error::Error + IntoFlat<F>, F: FromClosed> IntoFlat<F> for MultiplexError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            MultiplexError::ServiceError(e) => e.into_flat(),
+            MultiplexError::Closed => F::closed(),
+        }
+    }
+}
+
+impl<E: core::error::Error + Classify> Classify for MultiplexError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            MultiplexError::ServiceError(e) => e.classify(),
+            MultiplexError::Closed => Class::Permanent,
+        }
+    }
+}
+
+impl<R, Resp, W> Multiplex<R, Resp, W> {
+    /// Creates both sides of a connection. The [`Demux`] has to be
+    /// [run](Demux::run) for requests to get their responses.
+    pub fn new(writer: W) -> (Self, Demux<Resp>) {
+        let waiting = Arc::new(Mutex::new(Some(HashMap::new())));
+        let multiplex = Multiplex {
+            writer,
+            next_id: AtomicU64::new(0),
+            waiting: waiting.clone(),
+            phantom: PhantomData,
+        };
+        (multiplex, Demux { waiting })
+    }
+
+    /// The writer service.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Requests sent and waiting for their response.
+    pub fn waiting(&self) -> usize {
+        self.waiting
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, HashMap::len)
+    }
+}
+
+/// Stops waiting for the response of `id` if the caller gives up on it.
+struct Unregister<'a, Resp> {
+    waiting: &'a Waiting<Resp>,
+    id: u64,
+}
+
+impl<Resp> Drop for Unregister<'_, Resp> {
+    fn drop(&mut self) {
+        if let Some(waiting) = self
+            .waiting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_mut()
+        {
+            waiting.remove(&self.id);
+        }
+    }
+}
+
+impl<R, Resp, W: Service<(u64, R), Response = ()>> Service<R> for Multiplex<R, Resp, W> {
+    type Response = Resp;
+    type Error = MultiplexError<W::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+
+        self.waiting
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or(MultiplexError::Closed)?
+            .insert(id, sender);
+        let _unregister = Unregister {
+            waiting: &self.waiting,
+            id,
+        };
+
+        self.writer
+            .request((id, msg))
+            .await
+            .map_err(MultiplexError::ServiceError)?;
+
+        receiver.await.map_err(|_| MultiplexError::Closed)
+    }
+}
+
+impl<R, Resp, W: Describe> Describe for Multiplex<R, Resp, W> {
+    fn name(&self) -> &'static str {
+        "multiplex"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("waiting", self.waiting().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.writer.describe())
+    }
+}
+
+impl<R: 'static, Resp: 'static, W: FindLayer> FindLayer for Multiplex<R, Resp, W> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.writer)
+    }
+}
+
+impl<Resp> Demux<Resp> {
+    /// Reads responses with `reader` and hands them to their callers, until
+    /// the reader returns `None` or fails. Responses nobody is waiting for
+    /// anymore (e.g. the caller timed out) are dropped.
+    ///
+    /// The connection is closed once this returns: callers still waiting,
+    /// and later requests, fail with [`MultiplexError::Closed`].
+    pub async fn run<S>(self, reader: S) -> Result<(), S::Error>
+    where
+        S: Service<(), Response = Option<(u64, Resp)>>,
+    {
+        loop {
+            let Some((id, resp)) = reader.request(()).await? else {
+                return Ok(());
+            };
+
+            let sender = self
+                .waiting
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|waiting| waiting.remove(&id));
+            if let Some(sender) = sender {
+                let _ = sender.send(resp);
+            }
+        }
+    }
+}
+
+impl<Resp> Drop for Demux<Resp> {
+    fn drop(&mut self) {
+        self.waiting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use tokio::{join, sync::mpsc};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    pub struct TestWriter {
+        server: mpsc::UnboundedSender<(u64, u64)>,
+    }
+
+    impl Service<(u64, u64)> for TestWriter {
+        type Response = ();
+        type Error = EmptyError;
+
+        async fn request(&self, msg: (u64, u64)) -> Result<Self::Response, Self::Error> {
+            let _ = self.server.send(msg);
+            Ok(())
+        }
+    }
+
+    pub struct TestReader {
+        responses: tokio::sync::Mutex<mpsc::UnboundedReceiver<(u64, u64)>>,
+    }
+
+    impl Service<()> for TestReader {
+        type Response = Option<(u64, u64)>;
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(self.responses.lock().await.recv().await)
+        }
+    }
+
+    #[tokio::test]
+    async fn multiplex_test() {
+        let (server, mut requests) = mpsc::unbounded_channel();
+        let (responder, responses) = mpsc::unbounded_channel();
+
+        let (multiplex, demux) = Multiplex::new(TestWriter { server });
+        let demux = tokio::spawn(demux.run(TestReader {
+            responses: tokio::sync::Mutex::new(responses),
+        }));
+
+        // Answers both requests in reverse order, then closes the connection
+        let server = async move {
+            let first = requests.recv().await.unwrap();
+            let second = requests.recv().await.unwrap();
+            for (id, msg) in [second, first] {
+                responder.send((id, msg * 10)).unwrap();
+            }
+        };
+
+        let (a, b, ()) = join!(multiplex.request(1), multiplex.request(2), server);
+        assert_eq!(a, Ok(10));
+        assert_eq!(b, Ok(20));
+        assert_eq!(multiplex.waiting(), 0);
+
+        assert!(demux.await.unwrap().is_ok());
+        assert_eq!(multiplex.request(3).await, Err(MultiplexError::Closed));
+    }
+}
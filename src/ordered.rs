@@ -0,0 +1,207 @@
+//! Responses in the order of the requests.
+//!
+//! Concurrent requests to a service usually complete in any order. [`Ordered`]
+//! holds back the responses that arrive early until every request started
+//! before them has had its response, for consumers that need them in order
+//! (e.g. applying writes of a log).
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::poll_fn,
+    marker::PhantomData,
+    sync::PoisonError,
+    task::{Poll, Waker},
+};
+
+use crate::{
+    describe::{Describe, FindLayer, Stack},
+    sync::Mutex,
+    Middleware, Service,
+};
+
+/// Delivers the responses, errors included, in the order the requests
+/// started (i.e. in the order their futures were first polled).
+///
+/// A request dropped before its response is delivered gives up its turn, so
+/// it doesn't hold back the later ones.
+pub struct Ordered<R, T: Service<R>> {
+    inner: T,
+    turns: Mutex<Turns>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Default)]
+struct Turns {
+    /// Sequence number of the next request to start.
+    started: u64,
+    /// Sequence number of the next request to deliver.
+    next: u64,
+    /// Requests done past `next`, delivered or dropped.
+    done: BTreeSet<u64>,
+    /// Requests with a response, waiting for their turn.
+    waiting: HashMap<u64, Waker>,
+}
+
+impl Turns {
+    fn finish(&mut self, seq: u64) {
+        self.done.insert(seq);
+        while self.done.remove(&self.next) {
+            self.next += 1;
+        }
+        if let Some(waker) = self.waiting.remove(&self.next) {
+            waker.wake();
+        }
+    }
+}
+
+/// Ends the turn of a request when dropped, whether it was delivered or not.
+struct Turn<'a> {
+    turns: &'a Mutex<Turns>,
+    seq: u64,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut turns = self.turns.lock().unwrap_or_else(PoisonError::into_inner);
+        turns.waiting.remove(&self.seq);
+        turns.finish(self.seq);
+    }
+}
+
+impl<R, T: Service<R>> Ordered<R, T> {
+    pub fn new(service: T) -> Self {
+        Ordered {
+            inner: service,
+            turns: Mutex::new(Turns::default()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Responses held back until the requests before them are delivered.
+    pub fn buffered(&self) -> usize {
+        self.turns.lock().unwrap().waiting.len()
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for Ordered<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let seq = {
+            let mut turns = self.turns.lock().unwrap();
+            turns.started += 1;
+            turns.started - 1
+        };
+        let _turn = Turn {
+            turns: &self.turns,
+            seq,
+        };
+
+        let res = self.inner.request(msg).await;
+
+        poll_fn(|cx| {
+            let mut turns = self.turns.lock().unwrap();
+            if turns.next == seq {
+                Poll::Ready(())
+            } else {
+                turns.waiting.insert(seq, cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await;
+
+        res
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for Ordered<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for Ordered<R, T> {
+    fn name(&self) -> &'static str {
+        "ordered"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("buffered", self.buffered().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Ordered<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use thiserror::Error;
+    use tokio::{join, select, time::sleep};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestOrderedService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    /// Takes as many milliseconds as requested
+    impl Service<u64> for TestOrderedService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(msg)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ordered_test() {
+        let service = Ordered::new(TestOrderedService {});
+        let delivered = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let deliver = |msg| {
+            let (service, delivered) = (&service, delivered.clone());
+            async move {
+                let resp = service.request(msg).await.unwrap();
+                delivered.lock().unwrap().push(resp);
+            }
+        };
+
+        join!(deliver(30), deliver(10), deliver(20), async {
+            sleep(Duration::from_millis(25)).await;
+            assert_eq!(service.buffered(), 2);
+        });
+        assert_eq!(*delivered.lock().unwrap(), [30, 10, 20]);
+        assert_eq!(service.buffered(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ordered_dropped_test() {
+        let service = Ordered::new(TestOrderedService {});
+
+        // The first request is given up on, the second isn't held back
+        let (first, second) = join!(
+            async {
+                select! {
+                    resp = service.request(100) => Some(resp),
+                    _ = sleep(Duration::from_millis(20)) => None,
+                }
+            },
+            service.request(10)
+        );
+        assert_eq!(first, None);
+        assert_eq!(second, Ok(10));
+    }
+}
@@ -0,0 +1,308 @@
+//! At-least-once delivery through a durable outbox.
+//!
+//! [`Outbox`] writes each request to an [`OutboxStore`] (a database table, a
+//! file...) before sending it to the inner service, and marks it complete
+//! once the service succeeded. The requests left incomplete, because the
+//! service failed or the process died, are sent again by
+//! [`Outbox::replay`], typically on startup. A request can then be delivered
+//! more than once, so the service should be idempotent.
+
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    error::IntoFlat,
+    sync::Mutex,
+    Middleware, Service,
+};
+
+/// Where an [`Outbox`] keeps its requests until they're delivered.
+#[allow(async_fn_in_trait)]
+pub trait OutboxStore<R> {
+    type Id;
+    type Error: core::error::Error + 'static;
+
+    /// Durably stores the request, before it's sent.
+    async fn persist(&self, msg: &R) -> Result<Self::Id, Self::Error>;
+
+    /// Marks the request as delivered, so it's never sent again.
+    async fn complete(&self, id: Self::Id) -> Result<(), Self::Error>;
+
+    /// The requests not delivered yet, oldest first.
+    async fn incomplete(&self) -> Result<Vec<(Self::Id, R)>, Self::Error>;
+}
+
+/// Persists each request before sending it, for at-least-once delivery.
+pub struct Outbox<R, T: Service<R>, S> {
+    inner: T,
+    store: S,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum OutboxError<E: core::error::Error, S: core::error::Error> {
+    /// The request is still in the outbox, and will be replayed.
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    /// The request wasn't sent.
+    #[error("outbox store failed")]
+    StoreError(#[source] S),
+}
+
+impl<E: core::error::Error, S: core::error::Error> OutboxError<E, S> {
+    #[inline]
+    pub fn is_store_error(&self) -> bool {
+        matches!(self, OutboxError::StoreError(_))
+    }
+}
+
+impl<E, S, F> IntoFlat<F> for OutboxError<E, S>
+where
+    E: core::error::Error + IntoFlat<F>,
+    S: core::error::Error + IntoFlat<F>,
+{
+    fn into_flat(self) -> F {
+        match self {
+            OutboxError::ServiceError(e) => e.into_flat(),
+            OutboxError::StoreError(e) => e.into_flat(),
+        }
+    }
+}
+
+impl<E, S> Classify for OutboxError<E, S>
+where
+    E: core::error::Error + Classify,
+    S: core::error::Error + Classify,
+{
+    fn classify(&self) -> Class {
+        match self {
+            OutboxError::ServiceError(e) => e.classify(),
+            OutboxError::StoreError(e) => e.classify(),
+        }
+    }
+}
+
+/// What [`Outbox::replay`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Replayed {
+    pub delivered: usize,
+    /// Requests the service failed again, left in the outbox.
+    pub failed: usize,
+}
+
+impl<R, T: Service<R>, S: OutboxStore<R>> Outbox<R, T, S> {
+    pub fn new(service: T, store: S) -> Self {
+        Outbox {
+            inner: service,
+            store,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Sends the incomplete requests of the store again, one at a time.
+    ///
+    /// Call it before taking new requests, as requests sent concurrently are
+    /// incomplete too and would be sent twice.
+    pub async fn replay(&self) -> Result<Replayed, S::Error> {
+        let mut replayed = Replayed::default();
+        for (id, msg) in self.store.incomplete().await? {
+            match self.inner.request(msg).await {
+                Ok(_) => {
+                    self.store.complete(id).await?;
+                    replayed.delivered += 1;
+                }
+                Err(_) => replayed.failed += 1,
+            }
+        }
+        Ok(replayed)
+    }
+}
+
+impl<R, T: Service<R>, S: OutboxStore<R>> Service<R> for Outbox<R, T, S> {
+    type Response = T::Response;
+    type Error = OutboxError<T::Error, S::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let id = self
+            .store
+            .persist(&msg)
+            .await
+            .map_err(OutboxError::StoreError)?;
+
+        let resp = self
+            .inner
+            .request(msg)
+            .await
+            .map_err(OutboxError::ServiceError)?;
+
+        // Delivered either way: failing to record it only means it will be
+        // delivered again, which at-least-once allows
+        let _ = self.store.complete(id).await;
+        Ok(resp)
+    }
+}
+
+impl<R, T: Service<R>, S: OutboxStore<R>> Middleware<R, T> for Outbox<R, T, S> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe, S: OutboxStore<R>> Describe for Outbox<R, T, S> {
+    fn name(&self) -> &'static str {
+        "outbox"
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer, S: 'static> FindLayer for Outbox<R, T, S> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// An [`OutboxStore`] in memory, shared by its clones.
+///
+/// It doesn't survive the process, so it's meant for tests, or for replaying
+/// the requests that failed from time to time.
+pub struct MemoryOutbox<R> {
+    entries: Arc<Mutex<(u64, BTreeMap<u64, R>)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum MemoryOutboxError {}
+
+impl<R> MemoryOutbox<R> {
+    pub fn new() -> Self {
+        MemoryOutbox {
+            entries: Arc::new(Mutex::new((0, BTreeMap::new()))),
+        }
+    }
+
+    /// Requests not delivered yet.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().1.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R> Default for MemoryOutbox<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> Clone for MemoryOutbox<R> {
+    fn clone(&self) -> Self {
+        MemoryOutbox {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<R: Clone> OutboxStore<R> for MemoryOutbox<R> {
+    type Id = u64;
+    type Error = MemoryOutboxError;
+
+    async fn persist(&self, msg: &R) -> Result<u64, Self::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.0;
+        entries.0 += 1;
+        entries.1.insert(id, msg.clone());
+        Ok(id)
+    }
+
+    async fn complete(&self, id: u64) -> Result<(), Self::Error> {
+        self.entries.lock().unwrap().1.remove(&id);
+        Ok(())
+    }
+
+    async fn incomplete(&self) -> Result<Vec<(u64, R)>, Self::Error> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .1
+            .iter()
+            .map(|(id, msg)| (*id, msg.clone()))
+            .collect())
+    }
+}
+
+impl Classify for MemoryOutboxError {
+    fn classify(&self) -> Class {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    /// Fails every request while `down`
+    pub struct TestOutboxService {
+        down: bool,
+    }
+
+    impl Service<u64> for TestOutboxService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            if self.down {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn outbox_test() {
+        let store = MemoryOutbox::new();
+        let outbox = Outbox::new(TestOutboxService { down: false }, store.clone());
+        assert_eq!(outbox.request(1).await, Ok(1));
+        assert!(store.is_empty());
+
+        // The failed requests stay in the outbox
+        let outbox = Outbox::new(TestOutboxService { down: true }, store.clone());
+        assert!(outbox.request(2).await.is_err());
+        assert!(outbox.request(3).await.is_err());
+        assert_eq!(
+            outbox.replay().await,
+            Ok(Replayed {
+                delivered: 0,
+                failed: 2
+            })
+        );
+        assert_eq!(store.len(), 2);
+
+        // Until replayed on the next startup
+        let outbox = Outbox::new(TestOutboxService { down: false }, store.clone());
+        assert_eq!(
+            outbox.replay().await,
+            Ok(Replayed {
+                delivered: 2,
+                failed: 0
+            })
+        );
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+//! Opinionated stacks with sane defaults, for when you don't want to think
+//! about the order of the layers.
+//!
+//! [`resilient_client`] is the stack to put in front of a remote service:
+//!
+//! `RateLimit` → `Retry` → `Timeout` → service
+//!
+//! - `Timeout` is inside `Retry`, so a hung attempt is abandoned and retried,
+//!   instead of the whole request timing out on its first attempt.
+//! - `Retry` is inside `RateLimit`, so a request keeps its slot while it's
+//!   retried and retries can't push the service past the limit.
+//!
+//! Retry counts and limits are const generics, so they're given at the call
+//! site: `resilient_client::<3, 64, _, _>(service, ClientConfig::default())`.
+//!
+//! [`Timeouts`] sets both the timeout of each attempt and of the whole call,
+//! which two `Timeout` layers around a `Retry` get wrong easily: an outer
+//! timeout shorter than the attempts leaves no room to retry, and one longer
+//! than all of them never fires. It builds a [`TimedRetry`]:
+//!
+//! `Deadline` → `Retry` → `Timeout` → service
+//!
+//! The overall timeout is the deadline of the request, so attempts are cut
+//! short when it's near and no attempt starts that couldn't finish before it.
+
+use std::{marker::PhantomData, time::Duration};
+
+use crate::{
+    layer::Layer, meta::Deadline, rate_limit::RateLimit, retry::Retry, timeout::Timeout, Service,
+};
+
+pub type ResilientClient<const RETRY_COUNT: usize, const LIMIT: usize, R, T> =
+    RateLimit<LIMIT, R, Retry<RETRY_COUNT, R, Timeout<R, T>>>;
+
+/// Durations of a [`ResilientClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    /// How long each attempt can take. Defaults to 2 seconds.
+    pub attempt_timeout: Duration,
+    /// How long to wait between attempts. Defaults to 100 milliseconds.
+    pub backoff: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            attempt_timeout: Duration::from_secs(2),
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Wraps `service` in a [`ResilientClient`], see the [module docs](self).
+pub fn resilient_client<const RETRY_COUNT: usize, const LIMIT: usize, R: Clone, T: Service<R>>(
+    service: T,
+    config: ClientConfig,
+) -> ResilientClient<RETRY_COUNT, LIMIT, R, T> {
+    let timeout = Timeout::new(service, config.attempt_timeout);
+    let retry = Retry::with_wait(timeout, config.backoff);
+    RateLimit::new(retry)
+}
+
+pub type TimedRetry<const RETRY_COUNT: usize, R, T> =
+    Deadline<R, Retry<RETRY_COUNT, R, Timeout<R, T>>>;
+
+/// [`Layer`] making a [`TimedRetry`], see the [module docs](self).
+pub struct Timeouts<const RETRY_COUNT: usize, R> {
+    overall: Duration,
+    attempt: Option<Duration>,
+    wait: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<const RETRY_COUNT: usize, R> Timeouts<RETRY_COUNT, R> {
+    /// Every attempt and the waits between them fit in `overall`, each
+    /// attempt getting an equal share of it unless [`Timeouts::per_attempt`]
+    /// says otherwise.
+    pub fn new(overall: Duration) -> Self {
+        Timeouts {
+            overall,
+            attempt: None,
+            wait: Duration::ZERO,
+            phantom: PhantomData,
+        }
+    }
+
+    /// How long each attempt can take, at most the overall timeout. The
+    /// last attempts may not happen if it's more than their share.
+    pub fn per_attempt(self, attempt: Duration) -> Self {
+        Timeouts {
+            attempt: Some(attempt.min(self.overall)),
+            ..self
+        }
+    }
+
+    /// How long to wait between attempts, taken from the overall timeout.
+    pub fn with_wait(self, wait: Duration) -> Self {
+        Timeouts { wait, ..self }
+    }
+
+    pub fn overall_timeout(&self) -> Duration {
+        self.overall
+    }
+
+    /// The timeout of each attempt.
+    pub fn attempt_timeout(&self) -> Duration {
+        self.attempt.unwrap_or_else(|| {
+            let waits = self.wait.saturating_mul(RETRY_COUNT as u32);
+            self.overall.saturating_sub(waits) / (RETRY_COUNT as u32 + 1)
+        })
+    }
+}
+
+impl<const RETRY_COUNT: usize, R: Clone, T: Service<R>> Layer<T> for Timeouts<RETRY_COUNT, R> {
+    type Service = TimedRetry<RETRY_COUNT, R, T>;
+
+    fn layer(self, inner: T) -> Self::Service {
+        let timeout = Timeout::new(inner, self.attempt_timeout());
+        Deadline::new(Retry::with_wait(timeout, self.wait), self.overall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use thiserror::Error;
+    use tokio::time::{sleep, Instant};
+
+    use super::*;
+
+    /// The first attempt hangs, the next ones are instant
+    #[derive(Debug)]
+    pub struct TestHangingService {
+        attempts: AtomicUsize,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestHangingService {
+        type Response = usize;
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if attempt == 0 {
+                sleep(Duration::from_secs(3600)).await;
+            }
+            Ok(attempt)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resilient_client_retries_hung_attempts() {
+        let client = resilient_client::<2, 8, _, _>(
+            TestHangingService {
+                attempts: AtomicUsize::new(0),
+            },
+            ClientConfig::default(),
+        );
+
+        let started = Instant::now();
+        assert_eq!(client.request(()).await.unwrap(), 1);
+        assert_eq!(
+            started.elapsed(),
+            Duration::from_secs(2) + Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn timeouts_attempt_timeout() {
+        let timeouts = Timeouts::<2, ()>::new(Duration::from_secs(3));
+        assert_eq!(timeouts.attempt_timeout(), Duration::from_secs(1));
+
+        let timeouts = timeouts.with_wait(Duration::from_millis(600));
+        assert_eq!(timeouts.attempt_timeout(), Duration::from_millis(600));
+
+        // Never longer than the whole call
+        let timeouts = timeouts.per_attempt(Duration::from_secs(5));
+        assert_eq!(timeouts.attempt_timeout(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_retry_hung_attempts() {
+        let client = Timeouts::<2, _>::new(Duration::from_secs(3)).layer(TestHangingService {
+            attempts: AtomicUsize::new(0),
+        });
+
+        let started = Instant::now();
+        assert_eq!(client.request(()).await.unwrap(), 1);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+}
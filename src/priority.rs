@@ -0,0 +1,85 @@
+//! Priority of requests, shared by the middlewares that order or shed them.
+//!
+//! Requests implement [`Priority`] once, and every priority-aware layer reads
+//! the same [`Level`]. Levels are plain numbers, higher is more important:
+//! the named ones are spread out so numeric priorities from elsewhere (e.g.
+//! a header) can be mapped in between.
+
+use std::{rc::Rc, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Level(pub u8);
+
+impl Level {
+    /// Work nobody is waiting on, first to be shed.
+    pub const BACKGROUND: Level = Level(0);
+    pub const LOW: Level = Level(64);
+    /// Level of requests that don't say otherwise.
+    pub const NORMAL: Level = Level(128);
+    pub const HIGH: Level = Level(192);
+    /// Last to be shed, e.g. health checks.
+    pub const CRITICAL: Level = Level(255);
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Level::NORMAL
+    }
+}
+
+pub trait Priority {
+    fn priority(&self) -> Level;
+}
+
+impl<T: Priority + ?Sized> Priority for &T {
+    fn priority(&self) -> Level {
+        (**self).priority()
+    }
+}
+
+impl<T: Priority + ?Sized> Priority for Box<T> {
+    fn priority(&self) -> Level {
+        (**self).priority()
+    }
+}
+
+impl<T: Priority + ?Sized> Priority for Rc<T> {
+    fn priority(&self) -> Level {
+        (**self).priority()
+    }
+}
+
+impl<T: Priority + ?Sized> Priority for Arc<T> {
+    fn priority(&self) -> Level {
+        (**self).priority()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub struct TestRequest {
+        /// 0 to 9, like some queues do
+        urgency: u8,
+    }
+
+    impl Priority for TestRequest {
+        fn priority(&self) -> Level {
+            Level(self.urgency * 28)
+        }
+    }
+
+    #[test]
+    fn priority_levels() {
+        assert!(Level::BACKGROUND < Level::LOW);
+        assert!(Level::LOW < Level::default());
+        assert!(Level::NORMAL < Level::HIGH);
+        assert!(Level::HIGH < Level::CRITICAL);
+
+        let request = Arc::new(TestRequest { urgency: 9 });
+        assert!(request.priority() > Level::HIGH);
+        let request = TestRequest { urgency: 0 };
+        assert_eq!(Priority::priority(&&request), Level::BACKGROUND);
+    }
+}
@@ -9,55 +9,99 @@
 //! and call that. Only one restart attempt is made, after that it will
 //! fail normally and return [`RestartError::ServiceError`]. If restarting
 //! fails, then [`RestartError::RestartingFailed`] is returned instead.
+//! The second call starts over from scratch, unless the restart is
+//! [`Restart::resumable`].
+//!
+//! Requests don't wait on each other: each one calls a snapshot of the current
+//! S, so healthy requests run concurrently. Only replacing S is serialized, and
+//! requests failing at the same time trigger a single restart.
 
-use std::{marker::PhantomData, ops::DerefMut};
+use std::{
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
 
 use thiserror::Error;
 use tokio::sync::Mutex;
 
-use crate::Service;
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack, StackStats},
+    error::IntoFlat,
+    events::{self, StackEvent},
+    resume::Resumable,
+    shutdown::Shutdown,
+    sync::{AtomicU64, Ordering},
+    Service,
+};
 
 #[derive(Debug, Error)]
 pub enum RestartError<SE: core::error::Error, GE: core::error::Error> {
-    #[error("{0}")]
-    ServiceError(SE),
-    #[error("Could not restart failed service: {0}. Original error: {1}")]
-    RestartingFailed(GE, SE),
+    #[error("inner service failed")]
+    ServiceError(#[source] SE),
+    #[error("Could not restart failed service. Original error: {1}")]
+    RestartingFailed(#[source] GE, SE),
+}
+
+/// A failed restart flattens to the generator's error, the most recent one.
+impl<SE, GE, F> IntoFlat<F> for RestartError<SE, GE>
+where
+    SE: core::error::Error + IntoFlat<F>,
+    GE: core::error::Error + IntoFlat<F>,
+{
+    fn into_flat(self) -> F {
+        match self {
+            RestartError::ServiceError(e) => e.into_flat(),
+            RestartError::RestartingFailed(e, _) => e.into_flat(),
+        }
+    }
 }
 
 pub struct Restart<
     SR: Clone,
     SResp,
-    SE: core::error::Error,
+    SE: core::error::Error + 'static,
     S: Service<SR, Response = SResp, Error = SE>,
     GR: Clone,
-    GE: core::error::Error,
+    GE: core::error::Error + 'static,
     G: Service<GR, Response = S, Error = GE>,
 > {
-    service: Mutex<S>,
+    service: RwLock<Arc<S>>,
+    should_restart: fn(&SE) -> bool,
+    resume: fn(&mut SR),
+    shutdown: Option<Shutdown>,
+    restarting: Mutex<()>,
+    restarts: AtomicU64,
+    failed_restarts: AtomicU64,
     generator: G,
-    r: PhantomData<SR>,
+    r: PhantomData<fn(SR) -> SR>,
     g_r: GR,
-    s_resp: PhantomData<SResp>,
-    e: PhantomData<SE>,
-    g_e: PhantomData<GE>,
+    s_resp: PhantomData<fn() -> SResp>,
+    e: PhantomData<fn() -> SE>,
+    g_e: PhantomData<fn() -> GE>,
 }
 
 impl<
         SR: Clone,
         SResp,
-        SE: core::error::Error,
+        SE: core::error::Error + 'static,
         S: Service<SR, Response = SResp, Error = SE>,
         GR: Clone,
-        GE: core::error::Error,
+        GE: core::error::Error + 'static,
         G: Service<GR, Response = S, Error = GE>,
     > Restart<SR, SResp, SE, S, GR, GE, G>
 {
     pub async fn new(generator: G, generator_msg: GR) -> Result<Self, GE> {
-        let service = Mutex::new(generator.request(generator_msg.clone()).await?);
+        let service = RwLock::new(Arc::new(generator.request(generator_msg.clone()).await?));
 
         Ok(Self {
             service,
+            should_restart: |_| true,
+            resume: |_| {},
+            shutdown: None,
+            restarting: Mutex::new(()),
+            restarts: AtomicU64::new(0),
+            failed_restarts: AtomicU64::new(0),
             generator,
             r: PhantomData,
             g_r: generator_msg,
@@ -67,18 +111,125 @@ impl<
         })
     }
 
-    pub fn get_service(&self) -> &Mutex<S> {
-        &self.service
+    /// Only restarts on errors that are [service failures](Class::is_service_failure),
+    /// returning the others as they are.
+    pub fn classified(self) -> Self
+    where
+        SE: Classify,
+    {
+        Restart {
+            should_restart: |e| e.classify().is_service_failure(),
+            ..self
+        }
+    }
+
+    /// Resumes the call to the new service from the checkpoint the failed
+    /// call reached.
+    pub fn resumable(self) -> Self
+    where
+        SR: Resumable,
+    {
+        Restart {
+            resume: Resumable::resume,
+            ..self
+        }
+    }
+
+    /// Stops restarting once `shutdown` is triggered, failures are then
+    /// returned as they are.
+    pub fn until(self, shutdown: Shutdown) -> Self {
+        Restart {
+            shutdown: Some(shutdown),
+            ..self
+        }
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(Shutdown::is_triggered)
+    }
+
+    /// Services replaced since this instance was created.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Restarts that failed to create a new service.
+    pub fn failed_restarts(&self) -> u64 {
+        self.failed_restarts.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of the current service. It won't be affected by later restarts.
+    pub fn get_service(&self) -> Arc<S> {
+        self.service.read().unwrap().clone()
+    }
+
+    async fn restarting_request(&self, mut msg: SR) -> Result<SResp, RestartError<SE, GE>> {
+        let service = self.get_service();
+        match Self::call(&service, msg.clone()).await {
+            Err(e1) if (self.should_restart)(&e1) && !self.is_shut_down() => {
+                let new_service = {
+                    #[cfg(feature = "metrics")]
+                    let _phase = crate::metrics::Phase::start("restart", "restart");
+                    self.restart(&service).await
+                }
+                .map_err(|e2| RestartError::<SE, GE>::RestartingFailed(e2, e1))?;
+
+                (self.resume)(&mut msg);
+                let resp = Self::call(&new_service, msg)
+                    .await
+                    .map_err(RestartError::<SE, GE>::ServiceError)?;
+
+                Ok(resp)
+            }
+            ok => ok.map_err(RestartError::<SE, GE>::ServiceError),
+        }
+    }
+
+    async fn call(service: &S, msg: SR) -> Result<SResp, SE> {
+        #[cfg(feature = "metrics")]
+        let _phase = crate::metrics::Phase::start("restart", "inner");
+        service.request(msg).await
+    }
+
+    /// Replaces `failed` with a new service, unless another request already did.
+    async fn restart(&self, failed: &Arc<S>) -> Result<Arc<S>, GE> {
+        let _restarting = self.restarting.lock().await;
+
+        let current = self.get_service();
+        if !Arc::ptr_eq(&current, failed) {
+            return Ok(current);
+        }
+
+        let new_service = self.generator.request(self.g_r.clone()).await;
+        events::emit(StackEvent::Restarted {
+            succeeded: new_service.is_ok(),
+        });
+        match new_service {
+            Ok(_) => self.restarts.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.failed_restarts.fetch_add(1, Ordering::Relaxed),
+        };
+
+        #[cfg(feature = "metrics")]
+        {
+            let outcome = if new_service.is_ok() { "ok" } else { "error" };
+            ::metrics::counter!(crate::metrics::RESTARTS, "layer" => "restart", "outcome" => outcome)
+                .increment(1);
+        }
+
+        let new_service = Arc::new(new_service?);
+        *self.service.write().unwrap() = new_service.clone();
+
+        Ok(new_service)
     }
 }
 
 impl<
         SR: Clone,
         SResp,
-        SE: core::error::Error,
+        SE: core::error::Error + 'static,
         S: Service<SR, Response = SResp, Error = SE>,
         GR: Clone,
-        GE: core::error::Error,
+        GE: core::error::Error + 'static,
         G: Service<GR, Response = S, Error = GE>,
     > Service<SR> for Restart<SR, SResp, SE, S, GR, GE, G>
 {
@@ -86,34 +237,79 @@ impl<
     type Error = RestartError<SE, GE>;
 
     async fn request(&self, msg: SR) -> Result<Self::Response, Self::Error> {
-        let mut lock = self.service.lock().await;
-        match lock.request(msg.clone()).await {
-            Err(e1) => {
-                let new_service = self
-                    .generator
-                    .request(self.g_r.clone())
-                    .await
-                    .map_err(|e2| RestartError::<SE, GE>::RestartingFailed(e2, e1))?;
+        #[cfg(feature = "metrics")]
+        let started = (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
 
-                let _ = std::mem::replace(lock.deref_mut(), new_service);
+        let res = self.restarting_request(msg).await;
 
-                let resp = lock
-                    .request(msg)
-                    .await
-                    .map_err(|e| RestartError::<SE, GE>::ServiceError(e))?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_request("restart", started, &res, |e| match e {
+            RestartError::ServiceError(_) => "service",
+            RestartError::RestartingFailed(_, _) => "restart_failed",
+        });
 
-                Ok(resp)
-            }
-            ok => ok.map_err(|e| RestartError::<SE, GE>::ServiceError(e)),
+        res
+    }
+}
+
+/// A failed restart is permanent, the generator already had its chance.
+impl<SE: core::error::Error + Classify, GE: core::error::Error> Classify for RestartError<SE, GE> {
+    fn classify(&self) -> Class {
+        match self {
+            RestartError::ServiceError(e) => e.classify(),
+            RestartError::RestartingFailed(_, _) => Class::Permanent,
         }
     }
 }
 
+impl<
+        SR: Clone,
+        SResp,
+        SE: core::error::Error + 'static,
+        S: Service<SR, Response = SResp, Error = SE> + Describe,
+        GR: Clone,
+        GE: core::error::Error + 'static,
+        G: Service<GR, Response = S, Error = GE>,
+    > Describe for Restart<SR, SResp, SE, S, GR, GE, G>
+{
+    fn name(&self) -> &'static str {
+        "restart"
+    }
+
+    /// Describes the current service, a restart may replace it right after.
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.get_service().describe())
+    }
+}
+
+/// The current service can be replaced at any time, so it can't be
+/// borrowed and the lookup stops here.
+impl<
+        SR: Clone + 'static,
+        SResp: 'static,
+        SE: core::error::Error + 'static,
+        S: Service<SR, Response = SResp, Error = SE> + 'static,
+        GR: Clone + 'static,
+        GE: core::error::Error + 'static,
+        G: Service<GR, Response = S, Error = GE> + 'static,
+    > FindLayer for Restart<SR, SResp, SE, S, GR, GE, G>
+{
+    fn record_stats(&self, stats: &mut StackStats) {
+        StackStats::add(&mut stats.restarts, self.restarts());
+        StackStats::add(&mut stats.failed_restarts, self.failed_restarts());
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::{
+        join,
+        time::{sleep, Instant},
     };
 
     use super::*;
@@ -177,21 +373,164 @@ mod tests {
         );
 
         let restart = Restart::new(generator, 2).await.unwrap();
-        assert_eq!(restart.service.lock().await.id, 1);
+        assert_eq!(restart.get_service().id, 1);
         assert!(restart.request(2).await.is_ok(), "Value is OK");
         assert_eq!(
-            restart.service.lock().await.id,
+            restart.get_service().id,
             1,
             "OK value did not cause a restart"
         );
 
         match restart.request(3).await.unwrap_err() {
             RestartError::ServiceError(_) => {
-                assert_eq!(restart.service.lock().await.id, 2, "At this point the service should have restarted and inner id should be 2 instead of 1");
+                assert_eq!(restart.get_service().id, 2, "At this point the service should have restarted and inner id should be 2 instead of 1");
             }
             RestartError::RestartingFailed(_, _) => {
                 panic!("Restart service failed and did not restart")
             }
         };
     }
+
+    /// Always throttled, which isn't the service's fault
+    impl Classify for FakeError {
+        fn classify(&self) -> Class {
+            Class::Throttled
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_classified() {
+        let generator = TestGeneratorService {
+            counter: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let restart = Restart::new(generator, 2).await.unwrap().classified();
+        assert!(matches!(
+            restart.request(3).await,
+            Err(RestartError::ServiceError(FakeError::Error))
+        ));
+        assert_eq!(restart.get_service().id, 1, "Throttling isn't restarted");
+    }
+
+    #[tokio::test]
+    async fn test_restart_until_shutdown() {
+        let generator = TestGeneratorService {
+            counter: Arc::new(AtomicUsize::new(0)),
+        };
+        let shutdown = Shutdown::new();
+
+        let restart = Restart::new(generator, 2)
+            .await
+            .unwrap()
+            .until(shutdown.clone());
+        shutdown.trigger();
+        assert!(matches!(
+            restart.request(3).await,
+            Err(RestartError::ServiceError(FakeError::Error))
+        ));
+        assert_eq!(restart.get_service().id, 1, "Shut down, so not restarted");
+    }
+
+    #[derive(Debug)]
+    pub struct TestSlowService {}
+
+    impl Service<u64> for TestSlowService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(100)).await;
+            if msg == 0 {
+                Err(FakeError::Error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TestSlowGeneratorService {
+        counter: AtomicUsize,
+    }
+
+    impl Service<()> for TestSlowGeneratorService {
+        type Response = TestSlowService;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            self.counter.fetch_add(1, Ordering::SeqCst);
+            Ok(TestSlowService {})
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restart_concurrent_requests() {
+        let generator = TestSlowGeneratorService {
+            counter: AtomicUsize::new(0),
+        };
+        let restart = Restart::new(generator, ()).await.unwrap();
+
+        // Healthy requests don't wait for each other
+        let started = Instant::now();
+        let (a, b) = join!(restart.request(1), restart.request(1));
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(started.elapsed(), Duration::from_millis(100));
+
+        // Requests failing together only restart the service once
+        let (a, b) = join!(restart.request(0), restart.request(0));
+        assert!(matches!(a, Err(RestartError::ServiceError(_))));
+        assert!(matches!(b, Err(RestartError::ServiceError(_))));
+        assert_eq!(restart.generator.counter.load(Ordering::SeqCst), 2);
+    }
+
+    /// Counted from `start`, the service only gets so far on every call
+    #[derive(Debug, Clone)]
+    pub struct Count {
+        start: u64,
+        reached: Arc<AtomicUsize>,
+    }
+
+    impl Resumable for Count {
+        type Checkpoint = u64;
+
+        fn checkpoint(&self) -> Option<u64> {
+            Some(self.reached.load(Ordering::SeqCst) as u64)
+        }
+
+        fn resume_from(&mut self, checkpoint: u64) {
+            self.start = checkpoint;
+        }
+    }
+
+    impl Service<Count> for TestSlowService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: Count) -> Result<Self::Response, Self::Error> {
+            msg.reached.store(msg.start as usize + 5, Ordering::SeqCst);
+            if msg.start + 5 < 10 {
+                Err(FakeError::Error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restart_resumable() {
+        let count = || Count {
+            start: 0,
+            reached: Arc::default(),
+        };
+        let generator = || TestSlowGeneratorService {
+            counter: AtomicUsize::new(0),
+        };
+
+        // Starting over gets as far as the first call
+        let restart = Restart::new(generator(), ()).await.unwrap();
+        assert!(restart.request(count()).await.is_err());
+
+        let restart = Restart::new(generator(), ()).await.unwrap().resumable();
+        assert!(restart.request(count()).await.is_ok());
+    }
 }
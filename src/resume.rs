@@ -0,0 +1,30 @@
+//! Requests that can pick up where a failed attempt left off.
+//!
+//! Retrying a large upload or download from scratch can cost more than the
+//! failure itself. Requests implementing [`Resumable`] let
+//! [`Retry::resumable`](crate::retry::Retry) and
+//! [`Restart::resumable`](crate::restart::Restart) start the next attempt
+//! from the last checkpoint instead.
+
+/// A request whose attempts make progress that can be resumed.
+///
+/// Every attempt gets its own clone of the request, so the progress has to
+/// be shared between the clones (e.g. an `Arc<AtomicU64>` byte offset the
+/// service advances), for the original request to see how far the failed
+/// attempt got.
+pub trait Resumable {
+    type Checkpoint;
+
+    /// How far the attempts at this request got, if anywhere.
+    fn checkpoint(&self) -> Option<Self::Checkpoint>;
+
+    /// Makes the next attempts start from `checkpoint`.
+    fn resume_from(&mut self, checkpoint: Self::Checkpoint);
+
+    /// Moves to the latest checkpoint, if there's one.
+    fn resume(&mut self) {
+        if let Some(checkpoint) = self.checkpoint() {
+            self.resume_from(checkpoint);
+        }
+    }
+}
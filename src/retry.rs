@@ -1,28 +1,138 @@
-use std::marker::PhantomData;
+use std::{collections::BTreeMap, marker::PhantomData};
 
 #[cfg(feature = "retry_wait")]
+use std::sync::Arc;
 use std::time::Duration;
 
-#[cfg(feature = "retry_wait")]
-use tokio::time::sleep;
+#[cfg(feature = "metrics")]
+use ::metrics::counter;
 
-use crate::{Middleware, Service};
+#[cfg(feature = "retry_wait")]
+use crate::classify::Class;
+#[cfg(feature = "retry_wait")]
+use crate::clock::{Clock, TimeSource};
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::{
+    classify::Classify,
+    describe::{Describe, FindLayer, Stack, StackStats},
+    events::{self, StackEvent},
+    layer::Layer,
+    resume::Resumable,
+    sync::{AtomicU64, Ordering},
+    Middleware, Service,
+};
 
 /// Service that retries the request a certain
 /// amount of times before failing.
-pub struct Retry<const RETRY_COUNT: usize, R: Clone, T: Service<R>> {
+///
+/// Every attempt gets its own clone of the request. For large payloads,
+/// implement the inner service over a borrowed request (`&Payload`) or
+/// a shared one (`Arc<Payload>`) so retrying only copies a pointer.
+///
+/// Every error is retried, unless the retry is [`Retry::classified`].
+/// Retries start over from scratch, unless the retry is [`Retry::resumable`].
+pub struct Retry<const RETRY_COUNT: usize, R, T: Service<R>> {
     inner: T,
+    should_retry: fn(&T::Error) -> bool,
+    resume: fn(&mut R),
+    stats: Counters,
     #[cfg(feature = "retry_wait")]
     duration: Duration,
-    phantom: PhantomData<R>,
+    #[cfg(feature = "retry_wait")]
+    time_source: TimeSource,
+    #[cfg(feature = "retry_wait")]
+    backoff: Option<ClassBackoff<T::Error>>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// How long to wait before a retry, see [`Retry::backoff_by_class`].
+#[cfg(feature = "retry_wait")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Waits the same time before every retry.
+    Fixed(Duration),
+    /// Waits `base` before the first retry, then twice as long before each
+    /// retry, up to `max`.
+    Exponential { base: Duration, max: Duration },
+}
+
+#[cfg(feature = "retry_wait")]
+impl Backoff {
+    /// Retries right away.
+    pub const NONE: Backoff = Backoff::Fixed(Duration::ZERO);
+
+    /// Wait before the given retry, the first retry being number 1.
+    pub fn wait(&self, retry: usize) -> Duration {
+        match *self {
+            Backoff::Fixed(wait) => wait,
+            Backoff::Exponential { base, max } => {
+                let factor = 1u32.checked_shl(retry.saturating_sub(1) as u32);
+                factor.map_or(max, |factor| base.saturating_mul(factor).min(max))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "retry_wait")]
+struct ClassBackoff<E> {
+    class_of: fn(&E) -> Class,
+    backoff: fn(Class) -> Backoff,
+}
+
+/// Snapshot of what a [`Retry`] did since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryStats {
+    pub requests: u64,
+    /// Calls to the inner service, so `requests` plus the retries.
+    pub attempts: u64,
+    /// Requests that failed at first, then succeeded on a retry.
+    pub recovered: u64,
+    /// Requests that still failed after every retry.
+    pub exhausted: u64,
+    /// Requests given up on before using every retry, because another
+    /// attempt couldn't finish before their deadline.
+    pub budget_exhausted: u64,
+    /// Time spent waiting between attempts.
+    pub backoff: Duration,
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    attempts: AtomicU64,
+    recovered: AtomicU64,
+    exhausted: AtomicU64,
+    budget_exhausted: AtomicU64,
+    backoff_nanos: AtomicU64,
 }
 
-impl<const RETRY_COUNT: usize, R: Clone, T: Service<R>> Retry<RETRY_COUNT, R, T> {
+impl<const RETRY_COUNT: usize, R, T: Service<R>> Retry<RETRY_COUNT, R, T> {
+    /// Counters of this instance. They're updated with relaxed atomics,
+    /// so a snapshot taken while requests are running may be slightly off.
+    pub fn stats(&self) -> RetryStats {
+        RetryStats {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            attempts: self.stats.attempts.load(Ordering::Relaxed),
+            recovered: self.stats.recovered.load(Ordering::Relaxed),
+            exhausted: self.stats.exhausted.load(Ordering::Relaxed),
+            budget_exhausted: self.stats.budget_exhausted.load(Ordering::Relaxed),
+            backoff: Duration::from_nanos(self.stats.backoff_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
     pub fn instant(service: T) -> Retry<RETRY_COUNT, R, T> {
         Retry {
             inner: service,
+            should_retry: |_| true,
+            resume: |_| {},
+            stats: Counters::default(),
             #[cfg(feature = "retry_wait")]
             duration: Duration::ZERO,
+            #[cfg(feature = "retry_wait")]
+            time_source: TimeSource::System,
+            #[cfg(feature = "retry_wait")]
+            backoff: None,
             phantom: PhantomData,
         }
     }
@@ -31,36 +141,248 @@ impl<const RETRY_COUNT: usize, R: Clone, T: Service<R>> Retry<RETRY_COUNT, R, T>
     pub fn with_wait(service: T, duration: Duration) -> Retry<RETRY_COUNT, R, T> {
         Retry {
             inner: service,
+            should_retry: |_| true,
+            resume: |_| {},
+            stats: Counters::default(),
+            duration,
+            time_source: TimeSource::System,
+            backoff: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Like [`Retry::with_wait`], waiting with the given clock
+    /// instead of the system timers.
+    #[cfg(feature = "retry_wait")]
+    pub fn with_wait_and_clock(
+        service: T,
+        duration: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Retry<RETRY_COUNT, R, T> {
+        Retry {
+            inner: service,
+            should_retry: |_| true,
+            resume: |_| {},
+            stats: Counters::default(),
+            duration,
+            time_source: TimeSource::Clock(clock),
+            backoff: None,
+            phantom: PhantomData,
+        }
+    }
+
+    fn now(&self) -> Option<std::time::Instant> {
+        #[cfg(feature = "retry_wait")]
+        return self.time_source.now();
+        #[cfg(not(feature = "retry_wait"))]
+        return (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
+    }
+
+    /// How long to wait before the given retry, after failing with `err`.
+    #[cfg(feature = "retry_wait")]
+    fn wait(&self, err: &T::Error, retry: usize) -> Duration {
+        let overridden = crate::meta::current().and_then(|meta| meta.overrides.retry_wait);
+        overridden.unwrap_or_else(|| match &self.backoff {
+            Some(by_class) => (by_class.backoff)((by_class.class_of)(err)).wait(retry),
+            None => self.duration,
+        })
+    }
+
+    #[cfg(not(feature = "retry_wait"))]
+    fn wait(&self, _err: &T::Error, _retry: usize) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Whether the deadline of the current request leaves no time to wait
+    /// and make another attempt, expected to take as long as the last one.
+    fn out_of_time(&self, wait: Duration, last_attempt: Duration) -> bool {
+        crate::meta::remaining()
+            .is_some_and(|remaining| remaining <= wait.saturating_add(last_attempt))
+    }
+
+    /// Waits before each retry as `backoff` says for the class of the
+    /// error, e.g. retrying dropped connections right away but backing
+    /// off exponentially when throttled. The wait given at construction is
+    /// no longer used.
+    ///
+    /// ```ignore
+    /// let retry = Retry::<5, _, _>::instant(service).backoff_by_class(|class| match class {
+    ///     Class::Throttled => Backoff::Exponential {
+    ///         base: Duration::from_millis(500),
+    ///         max: Duration::from_secs(30),
+    ///     },
+    ///     _ => Backoff::NONE,
+    /// });
+    /// ```
+    #[cfg(feature = "retry_wait")]
+    pub fn backoff_by_class(self, backoff: fn(Class) -> Backoff) -> Self
+    where
+        T::Error: Classify,
+    {
+        Retry {
+            backoff: Some(ClassBackoff {
+                class_of: Classify::classify,
+                backoff,
+            }),
+            ..self
+        }
+    }
+
+    /// Only retries the errors that are [retryable](crate::classify::Class::is_retryable),
+    /// failing right away on the others.
+    pub fn classified(self) -> Self
+    where
+        T::Error: Classify,
+    {
+        Retry {
+            should_retry: |e| e.classify().is_retryable(),
+            ..self
+        }
+    }
+
+    /// Resumes retries from the checkpoint the failed attempt reached.
+    pub fn resumable(self) -> Self
+    where
+        R: Resumable,
+    {
+        Retry {
+            resume: Resumable::resume,
+            ..self
+        }
+    }
+}
+
+/// [`Layer`] making a [`Retry`].
+pub struct RetryLayer<const RETRY_COUNT: usize, R> {
+    #[cfg(feature = "retry_wait")]
+    duration: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<const RETRY_COUNT: usize, R> RetryLayer<RETRY_COUNT, R> {
+    /// See [`Retry::instant`].
+    pub fn instant() -> Self {
+        RetryLayer {
+            #[cfg(feature = "retry_wait")]
+            duration: Duration::ZERO,
+            phantom: PhantomData,
+        }
+    }
+
+    /// See [`Retry::with_wait`].
+    #[cfg(feature = "retry_wait")]
+    pub fn with_wait(duration: Duration) -> Self {
+        RetryLayer {
             duration,
             phantom: PhantomData,
         }
     }
 }
 
+impl<const RETRY_COUNT: usize, R, S: Service<R>> Layer<S> for RetryLayer<RETRY_COUNT, R> {
+    type Service = Retry<RETRY_COUNT, R, S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        #[cfg(feature = "retry_wait")]
+        return Retry::with_wait(inner, self.duration);
+        #[cfg(not(feature = "retry_wait"))]
+        return Retry::instant(inner);
+    }
+}
+
 impl<const RETRY_COUNT: usize, R: Clone, T: Service<R>> Service<R> for Retry<RETRY_COUNT, R, T> {
     type Response = T::Response;
     type Error = T::Error;
-    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
-        let mut retries_left = RETRY_COUNT;
-        loop {
-            match self.inner.request(msg.clone()).await {
-                Ok(ok) => return Ok(ok),
+    async fn request(&self, mut msg: R) -> Result<Self::Response, Self::Error> {
+        #[cfg(feature = "metrics")]
+        let started = (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
+
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+
+        let retries = crate::meta::current()
+            .and_then(|meta| meta.overrides.max_retries)
+            .map_or(RETRY_COUNT, |max_retries| max_retries.min(RETRY_COUNT));
+        let mut retries_left = retries;
+        let res = loop {
+            self.stats.attempts.fetch_add(1, Ordering::Relaxed);
+            let started_attempt = self.now();
+            let attempt = {
+                #[cfg(feature = "metrics")]
+                let _phase = metrics::Phase::start("retry", "inner");
+                self.inner.request(msg.clone()).await
+            };
+
+            match attempt {
+                Ok(ok) => {
+                    if retries_left < retries {
+                        self.stats.recovered.fetch_add(1, Ordering::Relaxed);
+
+                        #[cfg(feature = "metrics")]
+                        counter!(metrics::RETRIES_RECOVERED, "layer" => "retry").increment(1);
+                    }
+
+                    break Ok(ok);
+                }
+                Err(err) if !(self.should_retry)(&err) => break Err(err),
+                // Nobody is waiting for the response anymore
+                Err(err) if crate::cancel::is_cancelled() => break Err(err),
                 Err(err) => {
+                    let last_attempt = started_attempt
+                        .zip(self.now())
+                        .map_or(Duration::ZERO, |(started, now)| {
+                            now.saturating_duration_since(started)
+                        });
+                    let wait = self.wait(&err, retries - retries_left + 1);
+
                     if retries_left == 0 {
-                        return Err(err);
+                        self.stats.exhausted.fetch_add(1, Ordering::Relaxed);
+                        events::emit(StackEvent::RetriesExhausted);
+
+                        #[cfg(feature = "metrics")]
+                        counter!(metrics::RETRIES_EXHAUSTED, "layer" => "retry").increment(1);
+
+                        break Err(err);
+                    } else if self.out_of_time(wait, last_attempt) {
+                        self.stats.budget_exhausted.fetch_add(1, Ordering::Relaxed);
+
+                        #[cfg(feature = "metrics")]
+                        counter!(metrics::RETRIES_BUDGET_EXHAUSTED, "layer" => "retry")
+                            .increment(1);
+
+                        break Err(err);
                     } else {
                         retries_left -= 1;
+                        events::emit(StackEvent::RetryAttempted {
+                            attempt: retries - retries_left,
+                        });
+
+                        #[cfg(feature = "dead_letter")]
+                        crate::dead_letter::record(&err);
+
+                        #[cfg(feature = "metrics")]
+                        counter!(metrics::RETRIES, "layer" => "retry").increment(1);
 
                         #[cfg(feature = "retry_wait")]
                         {
-                            sleep(self.duration).await;
+                            #[cfg(feature = "metrics")]
+                            let _phase = metrics::Phase::start("retry", "backoff");
+                            self.time_source.sleep(wait).await;
+
+                            let nanos = u64::try_from(wait.as_nanos()).unwrap_or(u64::MAX);
+                            self.stats.backoff_nanos.fetch_add(nanos, Ordering::Relaxed);
                         }
 
+                        (self.resume)(&mut msg);
                         continue;
                     }
                 }
             };
-        }
+        };
+
+        #[cfg(feature = "metrics")]
+        metrics::record_request("retry", started, &res, |_| "service");
+
+        res
     }
 }
 
@@ -72,14 +394,56 @@ impl<const RETRY_COUNT: usize, R: Clone, T: Service<R>> Middleware<R, T>
     }
 }
 
+impl<const RETRY_COUNT: usize, R, T: Service<R> + Describe> Describe for Retry<RETRY_COUNT, R, T> {
+    fn name(&self) -> &'static str {
+        "retry"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        #[allow(unused_mut)]
+        let mut params = BTreeMap::from([("count", RETRY_COUNT.to_string())]);
+        #[cfg(feature = "retry_wait")]
+        match self.backoff {
+            Some(_) => params.insert("backoff", "by_class".to_string()),
+            None => params.insert("wait_ms", self.duration.as_millis().to_string()),
+        };
+        params
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<const RETRY_COUNT: usize, R: 'static, T: Service<R> + FindLayer> FindLayer
+    for Retry<RETRY_COUNT, R, T>
+{
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+
+    fn record_stats(&self, stats: &mut StackStats) {
+        let snapshot = self.stats();
+        let retries = snapshot.attempts.saturating_sub(snapshot.requests);
+        StackStats::add(&mut stats.retries, retries);
+        StackStats::add(&mut stats.retries_exhausted, snapshot.exhausted);
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use std::sync::Mutex;
+    use std::{
+        cell::Cell,
+        rc::Rc,
+        sync::{Arc, Mutex},
+    };
 
     use thiserror::Error;
 
     use super::*;
+    use crate::classify::Class;
+    use crate::meta::{Meta, MetaService, WithMeta};
 
     #[derive(Debug)]
     pub struct TestRetryService {
@@ -133,4 +497,396 @@ mod tests {
             assert!(retry_service.request(()).await.is_err());
         }
     }
+
+    /// Not `Send`, so it can only be used on a single thread
+    #[derive(Debug)]
+    pub struct TestLocalRetryService {
+        counter: Rc<Cell<usize>>,
+    }
+
+    impl Service<()> for TestLocalRetryService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            self.counter.set(self.counter.get() + 1);
+            if self.counter.get() < 3 {
+                Err(FakeError::Error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_local_test() {
+        let counter = Rc::new(Cell::new(0));
+        let retry_service = Retry::<3, _, _>::instant(TestLocalRetryService {
+            counter: counter.clone(),
+        });
+
+        let local = tokio::task::LocalSet::new();
+        let task = local.spawn_local(async move { retry_service.request(()).await });
+        let res = local.run_until(task).await.unwrap();
+
+        assert!(res.is_ok());
+        assert_eq!(counter.get(), 3);
+    }
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    #[derive(Debug)]
+    pub struct TestRcRetryService {}
+
+    impl Service<Rc<()>> for TestRcRetryService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: Rc<()>) -> Result<Self::Response, Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retry_non_send_request_test() {
+        // Rc isn't Send nor Sync, but Retry never stores a request
+        let service = Retry::<3, Rc<()>, _>::instant(TestRcRetryService {});
+        assert_send_sync(&service);
+    }
+
+    /// Deliberately not `Clone`
+    #[derive(Debug)]
+    pub struct Payload {
+        bytes: Vec<u8>,
+    }
+
+    #[derive(Debug)]
+    pub struct TestBorrowedRetryService {
+        attempts: Mutex<usize>,
+    }
+
+    impl<'a> Service<&'a Payload> for TestBorrowedRetryService {
+        type Response = usize;
+        type Error = FakeError;
+
+        async fn request(&self, msg: &'a Payload) -> Result<Self::Response, Self::Error> {
+            let mut attempts = self.attempts.lock().unwrap();
+            *attempts += 1;
+            if *attempts < 3 {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg.bytes.len())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_borrowed_request_test() {
+        let payload = Payload {
+            bytes: vec![0; 1 << 20],
+        };
+        let retry_service = Retry::<3, &Payload, _>::instant(TestBorrowedRetryService {
+            attempts: Mutex::new(0),
+        });
+
+        assert_eq!(retry_service.request(&payload).await.unwrap(), 1 << 20);
+        assert_eq!(*retry_service.inner_service().attempts.lock().unwrap(), 3);
+    }
+
+    #[derive(Debug, Error)]
+    pub enum ClassifiedError {
+        #[error("transient")]
+        Transient,
+        #[error("permanent")]
+        Permanent,
+    }
+
+    impl Classify for ClassifiedError {
+        fn classify(&self) -> Class {
+            match self {
+                ClassifiedError::Transient => Class::Transient,
+                ClassifiedError::Permanent => Class::Permanent,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TestClassifiedService {
+        attempts: Mutex<usize>,
+    }
+
+    impl Service<bool> for TestClassifiedService {
+        type Response = ();
+        type Error = ClassifiedError;
+
+        async fn request(&self, permanent: bool) -> Result<Self::Response, Self::Error> {
+            *self.attempts.lock().unwrap() += 1;
+            if permanent {
+                Err(ClassifiedError::Permanent)
+            } else {
+                Err(ClassifiedError::Transient)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_classified_test() {
+        let retry_service = Retry::<3, _, _>::instant(TestClassifiedService {
+            attempts: Mutex::new(0),
+        })
+        .classified();
+        let attempts = || *retry_service.inner_service().attempts.lock().unwrap();
+
+        assert!(retry_service.request(false).await.is_err());
+        assert_eq!(attempts(), 4);
+
+        assert!(retry_service.request(true).await.is_err());
+        assert_eq!(attempts(), 5);
+    }
+
+    #[tokio::test]
+    async fn retry_deadline_test() {
+        let service = MetaService::new(Retry::<3, _, _>::instant(TestRetryService {
+            counter: Mutex::new(0),
+            limit: 3,
+        }));
+
+        // Already past its deadline, so only tried once
+        let meta = Meta::default().with_deadline(tokio::time::Instant::now().into_std());
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+        assert_eq!(*service.inner_service().inner.counter.lock().unwrap(), 1);
+    }
+
+    #[cfg(feature = "retry_wait")]
+    #[tokio::test]
+    async fn retry_deadline_long_wait_test() {
+        let service = MetaService::new(Retry::<3, _, _>::instant(TestRetryService {
+            counter: Mutex::new(0),
+            limit: 3,
+        }));
+
+        // A wait that can't fit in any deadline
+        let meta = Meta::default()
+            .with_deadline(tokio::time::Instant::now().into_std() + Duration::from_secs(60))
+            .with_retry_wait(Duration::MAX);
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+        assert_eq!(*service.inner_service().inner.counter.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_cancel_test() {
+        let service = MetaService::new(Retry::<3, _, _>::instant(TestRetryService {
+            counter: Mutex::new(0),
+            limit: 3,
+        }));
+
+        // Cancelled, so not retried
+        let token = crate::cancel::CancelToken::new();
+        token.cancel();
+        let meta = Meta::default().with_cancel_token(token);
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+        assert_eq!(*service.inner_service().inner.counter.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_override_test() {
+        let service = MetaService::new(Retry::<3, _, _>::instant(TestRetryService {
+            counter: Mutex::new(0),
+            limit: 5,
+        }));
+
+        let meta = Meta::default().without_retries();
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+        assert_eq!(*service.inner_service().inner.counter.lock().unwrap(), 1);
+
+        // More retries than configured aren't made either
+        let meta = Meta::default().with_max_retries(10);
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+        assert_eq!(service.inner_service().stats().attempts, 5);
+    }
+
+    /// Fails after 40ms
+    #[cfg(feature = "retry_wait")]
+    #[derive(Debug)]
+    pub struct TestSlowRetryService {}
+
+    #[cfg(feature = "retry_wait")]
+    impl Service<()> for TestSlowRetryService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            Err(FakeError::Error)
+        }
+    }
+
+    #[cfg(feature = "retry_wait")]
+    #[tokio::test(start_paused = true)]
+    async fn retry_budget_test() {
+        let service = MetaService::new(Retry::<5, _, _>::with_wait(
+            TestSlowRetryService {},
+            Duration::from_millis(10),
+        ));
+
+        // The second attempt ends 20ms before the deadline, too late for a third
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(110);
+        let meta = Meta::default().with_deadline(deadline.into_std());
+        assert!(service.request(WithMeta::new((), meta)).await.is_err());
+
+        let stats = service.inner_service().stats();
+        assert_eq!(stats.attempts, 2);
+        assert_eq!(stats.budget_exhausted, 1);
+        assert_eq!(stats.exhausted, 0);
+    }
+
+    /// Throttles the first two requests, then drops the connection once
+    #[cfg(feature = "retry_wait")]
+    #[derive(Debug, Default)]
+    pub struct TestThrottledService {
+        calls: Mutex<usize>,
+    }
+
+    #[cfg(feature = "retry_wait")]
+    #[derive(Debug, Error)]
+    pub enum ThrottledError {
+        #[error("throttled")]
+        Throttled,
+        #[error("connection reset")]
+        Reset,
+    }
+
+    #[cfg(feature = "retry_wait")]
+    impl Classify for ThrottledError {
+        fn classify(&self) -> Class {
+            match self {
+                ThrottledError::Throttled => Class::Throttled,
+                ThrottledError::Reset => Class::Transient,
+            }
+        }
+    }
+
+    #[cfg(feature = "retry_wait")]
+    impl Service<()> for TestThrottledService {
+        type Response = ();
+        type Error = ThrottledError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            match *calls {
+                1 | 2 => Err(ThrottledError::Throttled),
+                3 => Err(ThrottledError::Reset),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[cfg(feature = "retry_wait")]
+    #[tokio::test(start_paused = true)]
+    async fn retry_backoff_by_class_test() {
+        let service = Retry::<3, _, _>::with_wait(TestThrottledService::default(), Duration::ZERO)
+            .backoff_by_class(|class| match class {
+                Class::Throttled => Backoff::Exponential {
+                    base: Duration::from_secs(1),
+                    max: Duration::from_secs(60),
+                },
+                _ => Backoff::NONE,
+            });
+
+        // 1s and 2s after being throttled, nothing after the reset
+        let started = tokio::time::Instant::now();
+        assert!(service.request(()).await.is_ok());
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        assert_eq!(service.stats().backoff, Duration::from_secs(3));
+
+        let backoff = Backoff::Exponential {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        };
+        assert_eq!(backoff.wait(3), Duration::from_secs(4));
+        assert_eq!(backoff.wait(100), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn retry_stats_test() {
+        let retry_service = Retry::<3, _, _>::instant(TestRetryService {
+            counter: Mutex::new(0),
+            limit: 2,
+        });
+
+        // Fails twice then succeeds, twice in a row
+        assert!(retry_service.request(()).await.is_ok());
+        assert!(retry_service.request(()).await.is_ok());
+
+        let stats = retry_service.stats();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.attempts, 6);
+        assert_eq!(stats.recovered, 2);
+        assert_eq!(stats.exhausted, 0);
+        assert_eq!(stats.backoff, Duration::ZERO);
+    }
+
+    /// Upload of `len` bytes, sent from `start` by chunks of 10
+    #[derive(Debug, Clone)]
+    pub struct Upload {
+        start: u64,
+        len: u64,
+        sent: Arc<AtomicU64>,
+    }
+
+    impl Resumable for Upload {
+        type Checkpoint = u64;
+
+        fn checkpoint(&self) -> Option<u64> {
+            Some(self.sent.load(Ordering::SeqCst))
+        }
+
+        fn resume_from(&mut self, checkpoint: u64) {
+            self.start = checkpoint;
+        }
+    }
+
+    /// Drops the connection once halfway through the first upload
+    #[derive(Debug, Default)]
+    pub struct TestUploadService {
+        dropped: Mutex<bool>,
+        bytes_sent: AtomicU64,
+    }
+
+    impl Service<Upload> for TestUploadService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: Upload) -> Result<Self::Response, Self::Error> {
+            for offset in (msg.start..msg.len).step_by(10) {
+                let mut dropped = self.dropped.lock().unwrap();
+                if offset == msg.len / 2 && !*dropped {
+                    *dropped = true;
+                    return Err(FakeError::Error);
+                }
+                self.bytes_sent.fetch_add(10, Ordering::SeqCst);
+                msg.sent.store(offset + 10, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_resumable_test() {
+        let upload = || Upload {
+            start: 0,
+            len: 100,
+            sent: Arc::default(),
+        };
+
+        let retry_service = Retry::<1, _, _>::instant(TestUploadService::default());
+        assert!(retry_service.request(upload()).await.is_ok());
+        assert_eq!(retry_service.inner.bytes_sent.load(Ordering::SeqCst), 150);
+
+        // Only the second half is sent again
+        let retry_service = Retry::<1, _, _>::instant(TestUploadService::default()).resumable();
+        assert!(retry_service.request(upload()).await.is_ok());
+        assert_eq!(retry_service.inner.bytes_sent.load(Ordering::SeqCst), 100);
+    }
 }
@@ -0,0 +1,373 @@
+//! Retrying failed requests in the background.
+//!
+//! `Retry` keeps its caller waiting for every attempt, which is the right
+//! thing for interactive requests. Workloads that don't wait for an answer
+//! (notifications, webhooks, syncing to a third party...) can instead hand
+//! the requests that failed to a [`RetryQueue`]. Its [`RetryQueueWorker`]
+//! owns the actual service and retries each request on its own schedule, with
+//! an exponential backoff, while the queue tells what's pending and can
+//! cancel it.
+
+use std::{
+    collections::BTreeMap,
+    future::{pending, poll_fn, Future},
+    pin::pin,
+    sync::Arc,
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::Notify,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    sync::{AtomicU64, AtomicUsize, Mutex, Ordering},
+    Service,
+};
+
+/// A request waiting in a [`RetryQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending {
+    pub id: u64,
+    /// Background attempts made so far.
+    pub attempts: usize,
+    pub next_attempt: Instant,
+}
+
+struct Item<R> {
+    msg: R,
+    attempts: usize,
+    next_attempt: Instant,
+    /// Being attempted by the worker.
+    running: bool,
+}
+
+struct Shared<R> {
+    items: Mutex<BTreeMap<u64, Item<R>>>,
+    next_id: AtomicU64,
+    /// Queues left, the worker stops once they're all gone.
+    queues: AtomicUsize,
+    changed: Notify,
+    backoff: Duration,
+    max_attempts: usize,
+    delivered: AtomicU64,
+    abandoned: AtomicU64,
+}
+
+impl<R> Shared<R> {
+    /// When to try again after `attempts` attempts. `Instant` has no maximum,
+    /// so backoffs too long for one wait a century instead.
+    fn next_attempt(&self, attempts: usize) -> Instant {
+        const CENTURY: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+        let factor = 2u32.saturating_pow(attempts.try_into().unwrap_or(u32::MAX));
+        let now = Instant::now();
+        now.checked_add(self.backoff.saturating_mul(factor))
+            .unwrap_or_else(|| now + CENTURY)
+    }
+}
+
+/// Takes requests to retry in the background. Cloning it is cheap, and all
+/// the clones feed the same worker.
+pub struct RetryQueue<R> {
+    shared: Arc<Shared<R>>,
+}
+
+/// Retries the requests of a [`RetryQueue`] with the actual service.
+pub struct RetryQueueWorker<R> {
+    shared: Arc<Shared<R>>,
+}
+
+impl<R> RetryQueue<R> {
+    /// Retries each request up to `max_attempts` times, waiting `backoff`
+    /// before the first attempt and twice as long before each of the next
+    /// ones.
+    pub fn new(backoff: Duration, max_attempts: usize) -> (Self, RetryQueueWorker<R>) {
+        let shared = Arc::new(Shared {
+            items: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            queues: AtomicUsize::new(1),
+            changed: Notify::new(),
+            backoff,
+            max_attempts,
+            delivered: AtomicU64::new(0),
+            abandoned: AtomicU64::new(0),
+        });
+        let worker = RetryQueueWorker {
+            shared: shared.clone(),
+        };
+        (RetryQueue { shared }, worker)
+    }
+
+    /// Queues `msg`, to be retried once the first backoff has passed.
+    /// Returns the ID to [`cancel`](RetryQueue::cancel) it with.
+    pub fn push(&self, msg: R) -> u64 {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        self.shared.items.lock().unwrap().insert(
+            id,
+            Item {
+                msg,
+                attempts: 0,
+                next_attempt: self.shared.next_attempt(0),
+                running: false,
+            },
+        );
+        self.shared.changed.notify_one();
+        id
+    }
+
+    /// Removes the request from the queue. An attempt already running
+    /// finishes, but its request isn't retried again.
+    pub fn cancel(&self, id: u64) -> bool {
+        let cancelled = self.shared.items.lock().unwrap().remove(&id).is_some();
+        self.shared.changed.notify_one();
+        cancelled
+    }
+
+    /// The requests not delivered yet, oldest first.
+    pub fn pending(&self) -> Vec<Pending> {
+        self.shared
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, item)| Pending {
+                id,
+                attempts: item.attempts,
+                next_attempt: item.next_attempt,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Requests that eventually succeeded.
+    pub fn delivered(&self) -> u64 {
+        self.shared.delivered.load(Ordering::Relaxed)
+    }
+
+    /// Requests dropped after failing every attempt.
+    pub fn abandoned(&self) -> u64 {
+        self.shared.abandoned.load(Ordering::Relaxed)
+    }
+}
+
+impl<R> Clone for RetryQueue<R> {
+    fn clone(&self) -> Self {
+        self.shared.queues.fetch_add(1, Ordering::Relaxed);
+        RetryQueue {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<R> Drop for RetryQueue<R> {
+    fn drop(&mut self) {
+        self.shared.queues.fetch_sub(1, Ordering::AcqRel);
+        self.shared.changed.notify_one();
+    }
+}
+
+/// What the worker has to do next.
+enum Next<R> {
+    Attempt(u64, R),
+    SleepUntil(Instant),
+    Wait,
+    Stop,
+}
+
+impl<R: Clone> RetryQueueWorker<R> {
+    fn next(&self) -> Next<R> {
+        let now = Instant::now();
+        let mut items = self.shared.items.lock().unwrap();
+        if items.is_empty() && self.shared.queues.load(Ordering::Acquire) == 0 {
+            return Next::Stop;
+        }
+
+        let due = items
+            .iter_mut()
+            .filter(|(_, item)| !item.running)
+            .min_by_key(|(_, item)| item.next_attempt);
+        match due {
+            Some((&id, item)) if item.next_attempt <= now => {
+                item.running = true;
+                Next::Attempt(id, item.msg.clone())
+            }
+            Some((_, item)) => Next::SleepUntil(item.next_attempt),
+            None => Next::Wait,
+        }
+    }
+
+    /// Retries the queued requests one at a time with `service`, until every
+    /// [`RetryQueue`] is dropped and nothing is left to retry.
+    pub async fn run<S: Service<R>>(self, service: S) {
+        self.run_until(service, pending()).await
+    }
+
+    /// Like [`RetryQueueWorker::run`], but also stops once `signal`
+    /// resolves, after the attempt running at that point. The requests left
+    /// stay in the queue.
+    pub async fn run_until<S, F>(self, service: S, signal: F)
+    where
+        S: Service<R>,
+        F: Future<Output = ()>,
+    {
+        let mut signal = pin!(signal);
+        loop {
+            if poll_fn(|cx| Poll::Ready(signal.as_mut().poll(cx).is_ready())).await {
+                return;
+            }
+
+            let changed = self.shared.changed.notified();
+            let (id, msg) = match self.next() {
+                Next::Attempt(id, msg) => (id, msg),
+                Next::SleepUntil(at) => {
+                    select! {
+                        _ = sleep_until(at) => {}
+                        _ = changed => {}
+                        _ = signal.as_mut() => return,
+                    }
+                    continue;
+                }
+                Next::Wait => {
+                    select! {
+                        _ = changed => {}
+                        _ = signal.as_mut() => return,
+                    }
+                    continue;
+                }
+                Next::Stop => return,
+            };
+
+            let res = service.request(msg).await;
+
+            let mut items = self.shared.items.lock().unwrap();
+            // Cancelled while it was running
+            let Some(item) = items.get_mut(&id) else {
+                continue;
+            };
+            item.attempts += 1;
+            item.running = false;
+
+            if res.is_ok() {
+                items.remove(&id);
+                self.shared.delivered.fetch_add(1, Ordering::Relaxed);
+            } else if item.attempts >= self.shared.max_attempts {
+                items.remove(&id);
+                self.shared.abandoned.fetch_add(1, Ordering::Relaxed);
+            } else {
+                item.next_attempt = self.shared.next_attempt(item.attempts);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    /// Succeeds once a request was attempted as many times as it says
+    #[derive(Debug, Default)]
+    pub struct TestFlakyService {
+        attempts: Mutex<BTreeMap<u64, u64>>,
+        log: Arc<Mutex<Vec<(u64, Instant)>>>,
+    }
+
+    impl Service<u64> for TestFlakyService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            self.log.lock().unwrap().push((msg, Instant::now()));
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempts = attempts.entry(msg).or_default();
+            *attempts += 1;
+            if *attempts >= msg {
+                Ok(())
+            } else {
+                Err(FakeError::Error)
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_queue_test() {
+        let (queue, worker) = RetryQueue::new(Duration::from_secs(1), 3);
+        let service = TestFlakyService::default();
+        let log = service.log.clone();
+        let worker = tokio::spawn(worker.run(service));
+        let started = Instant::now();
+
+        queue.push(2);
+        queue.push(5);
+        let cancelled = queue.push(3);
+        tokio::task::yield_now().await;
+        assert_eq!(queue.len(), 3);
+        assert!(queue.cancel(cancelled));
+        assert!(!queue.cancel(cancelled));
+
+        // Retried after 1s, then 2s, then 4s, until abandoned
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(queue.is_empty());
+        assert_eq!(queue.delivered(), 1);
+        assert_eq!(queue.abandoned(), 1);
+
+        let log: Vec<_> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&(msg, at)| (msg, (at - started).as_secs()))
+            .collect();
+        assert_eq!(log, [(2, 1), (5, 1), (2, 3), (5, 3), (5, 7)]);
+
+        // The worker stops with the last queue
+        drop(queue);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_queue_shutdown_test() {
+        let (queue, worker) = RetryQueue::new(Duration::from_secs(1), 3);
+        let shutdown = crate::shutdown::Shutdown::new();
+        let worker = tokio::spawn(worker.run_until(TestFlakyService::default(), shutdown.signal()));
+
+        queue.push(5);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        shutdown.shutdown().await;
+        worker.await.unwrap();
+
+        // Left in the queue, with the attempt made before the shutdown
+        assert_eq!(queue.pending()[0].attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_queue_long_backoff_test() {
+        let (queue, worker) = RetryQueue::new(Duration::MAX, 3);
+        let worker = tokio::spawn(worker.run(TestFlakyService::default()));
+
+        // Waits a century instead of overflowing the `Instant`
+        let id = queue.push(1);
+        tokio::time::sleep(Duration::from_secs(365 * 24 * 60 * 60)).await;
+        assert_eq!(queue.pending()[0].attempts, 0);
+
+        assert!(queue.cancel(id));
+        drop(queue);
+        worker.await.unwrap();
+    }
+}
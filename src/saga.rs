@@ -0,0 +1,312 @@
+//! Multi-step workflows that undo the steps already done when one fails.
+//!
+//! Each step of a [`Saga`] is a service paired with a compensation service.
+//! The response of a step is the request of the next one, and is what its
+//! compensation gets to undo it (e.g. the ID of a reservation to cancel).
+//! When step N fails, the compensations of steps N-1 down to 0 run, in that
+//! order, and the [`SagaError`] tells how that went.
+//!
+//! ```ignore
+//! let booking = Saga::new(reserve_flight, cancel_flight)
+//!     .then(reserve_hotel, cancel_hotel)
+//!     .then(charge_card, refund_card);
+//! ```
+
+use core::error::Error;
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use thiserror::Error;
+
+use crate::{
+    describe::{Describe, FindLayer},
+    Service,
+};
+
+/// A step failed. The steps before it were compensated, except those in
+/// `failed_compensations`.
+#[derive(Debug, Error)]
+#[error("saga failed at step {step}")]
+pub struct SagaError<E: Error> {
+    /// Index of the step that failed, starting from 0.
+    pub step: usize,
+    #[source]
+    pub error: E,
+    /// Compensations that failed too, by index of their step, latest step
+    /// first. Every compensation runs, even after one of them failed.
+    pub failed_compensations: Vec<(usize, E)>,
+}
+
+impl<E: Error> SagaError<E> {
+    /// Whether every step before the failed one was undone.
+    pub fn is_compensated(&self) -> bool {
+        self.failed_compensations.is_empty()
+    }
+}
+
+/// Steps of a [`Saga`], built with [`Saga::new`] and [`Saga::then`].
+#[allow(async_fn_in_trait)]
+pub trait SagaSteps<R> {
+    type Response;
+    type Error: Error + 'static;
+    /// Responses of every step, for their compensations.
+    type Done;
+
+    /// How many steps there are.
+    const LEN: usize;
+
+    /// Runs every step, compensating the ones done if one of them fails.
+    async fn run(&self, msg: R) -> Result<(Self::Response, Self::Done), SagaError<Self::Error>>;
+
+    /// Undoes every step, latest first, recording the compensations that
+    /// failed in `failed`.
+    async fn compensate(&self, done: Self::Done, failed: &mut Vec<(usize, Self::Error)>);
+}
+
+/// The first step of a [`Saga`].
+pub struct Step<T, C> {
+    step: T,
+    compensation: C,
+}
+
+/// Steps `P`, followed by one more.
+pub struct Then<P, T, C> {
+    previous: P,
+    step: T,
+    compensation: C,
+}
+
+impl<R, E, T, C> SagaSteps<R> for Step<T, C>
+where
+    E: Error + 'static,
+    T: Service<R, Error = E>,
+    T::Response: Clone,
+    C: Service<T::Response, Error = E>,
+{
+    type Response = T::Response;
+    type Error = E;
+    type Done = T::Response;
+
+    const LEN: usize = 1;
+
+    async fn run(&self, msg: R) -> Result<(Self::Response, Self::Done), SagaError<E>> {
+        match self.step.request(msg).await {
+            Ok(resp) => Ok((resp.clone(), resp)),
+            Err(error) => Err(SagaError {
+                step: 0,
+                error,
+                failed_compensations: Vec::new(),
+            }),
+        }
+    }
+
+    async fn compensate(&self, done: Self::Done, failed: &mut Vec<(usize, E)>) {
+        if let Err(e) = self.compensation.request(done).await {
+            failed.push((0, e));
+        }
+    }
+}
+
+impl<R, E, P, T, C> SagaSteps<R> for Then<P, T, C>
+where
+    E: Error + 'static,
+    P: SagaSteps<R, Error = E>,
+    T: Service<P::Response, Error = E>,
+    T::Response: Clone,
+    C: Service<T::Response, Error = E>,
+{
+    type Response = T::Response;
+    type Error = E;
+    type Done = (P::Done, T::Response);
+
+    const LEN: usize = P::LEN + 1;
+
+    async fn run(&self, msg: R) -> Result<(Self::Response, Self::Done), SagaError<E>> {
+        let (previous, previous_done) = self.previous.run(msg).await?;
+
+        match self.step.request(previous).await {
+            Ok(resp) => Ok((resp.clone(), (previous_done, resp))),
+            Err(error) => {
+                let mut failed_compensations = Vec::new();
+                self.previous
+                    .compensate(previous_done, &mut failed_compensations)
+                    .await;
+
+                Err(SagaError {
+                    step: P::LEN,
+                    error,
+                    failed_compensations,
+                })
+            }
+        }
+    }
+
+    async fn compensate(&self, (previous_done, done): Self::Done, failed: &mut Vec<(usize, E)>) {
+        if let Err(e) = self.compensation.request(done).await {
+            failed.push((P::LEN, e));
+        }
+        self.previous.compensate(previous_done, failed).await;
+    }
+}
+
+/// Runs its steps one after the other, sending the response of each step to
+/// the next one and responding with the response of the last one.
+pub struct Saga<R, S> {
+    steps: S,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T, C> Saga<R, Step<T, C>> {
+    /// Starts a saga with `step`, undone by `compensation`.
+    pub fn new(step: T, compensation: C) -> Self {
+        Saga {
+            steps: Step { step, compensation },
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, S> Saga<R, S> {
+    /// Adds `step` after the others, undone by `compensation`.
+    pub fn then<T, C>(self, step: T, compensation: C) -> Saga<R, Then<S, T, C>> {
+        Saga {
+            steps: Then {
+                previous: self.steps,
+                step,
+                compensation,
+            },
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, S: SagaSteps<R>> Service<R> for Saga<R, S> {
+    type Response = S::Response;
+    type Error = SagaError<S::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.steps.run(msg).await.map(|(resp, _)| resp)
+    }
+}
+
+/// The steps don't make a single stack, so this is a leaf.
+impl<R, S: SagaSteps<R>> Describe for Saga<R, S> {
+    fn name(&self) -> &'static str {
+        "saga"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("steps", S::LEN.to_string())])
+    }
+}
+
+impl<R: 'static, S: 'static> FindLayer for Saga<R, S> {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("step {0} failed")]
+        Error(&'static str),
+    }
+
+    /// What the steps and compensations did, in order
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Appends its name to the request, failing if it's `fail`
+    pub struct TestStep {
+        name: &'static str,
+        fail: bool,
+        log: Log,
+    }
+
+    impl Service<String> for TestStep {
+        type Response = String;
+        type Error = FakeError;
+
+        async fn request(&self, msg: String) -> Result<Self::Response, Self::Error> {
+            if self.fail {
+                return Err(FakeError::Error(self.name));
+            }
+            let resp = format!("{msg}{}", self.name);
+            self.log.lock().unwrap().push(format!("do {resp}"));
+            Ok(resp)
+        }
+    }
+
+    fn step(name: &'static str, fail: bool, log: &Log) -> TestStep {
+        TestStep {
+            name,
+            fail,
+            log: log.clone(),
+        }
+    }
+
+    /// Undoes what the step did, sometimes failing at it
+    pub struct TestCompensation {
+        fail: bool,
+        log: Log,
+    }
+
+    impl Service<String> for TestCompensation {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, msg: String) -> Result<Self::Response, Self::Error> {
+            if self.fail {
+                return Err(FakeError::Error("compensation"));
+            }
+            self.log.lock().unwrap().push(format!("undo {msg}"));
+            Ok(())
+        }
+    }
+
+    fn compensation(fail: bool, log: &Log) -> TestCompensation {
+        TestCompensation {
+            fail,
+            log: log.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn saga_test() {
+        let log = Log::default();
+        let saga = Saga::new(step("a", false, &log), compensation(false, &log))
+            .then(step("b", false, &log), compensation(false, &log))
+            .then(step("c", false, &log), compensation(false, &log));
+        assert_eq!(saga.request(String::new()).await.unwrap(), "abc");
+        assert_eq!(*log.lock().unwrap(), ["do a", "do ab", "do abc"]);
+        assert_eq!(saga.params()["steps"], "3");
+
+        // The last step fails, the first two are undone in reverse
+        let log = Log::default();
+        let saga = Saga::new(step("a", false, &log), compensation(false, &log))
+            .then(step("b", false, &log), compensation(false, &log))
+            .then(step("c", true, &log), compensation(false, &log));
+        let err = saga.request(String::new()).await.unwrap_err();
+        assert_eq!(err.step, 2);
+        assert_eq!(err.error, FakeError::Error("c"));
+        assert!(err.is_compensated());
+        assert_eq!(*log.lock().unwrap(), ["do a", "do ab", "undo ab", "undo a"]);
+    }
+
+    #[tokio::test]
+    async fn saga_failed_compensation_test() {
+        let log = Log::default();
+        let saga = Saga::new(step("a", false, &log), compensation(false, &log))
+            .then(step("b", false, &log), compensation(true, &log))
+            .then(step("c", true, &log), compensation(false, &log));
+
+        // Compensating b failed, a was still compensated
+        let err = saga.request(String::new()).await.unwrap_err();
+        assert!(!err.is_compensated());
+        assert_eq!(
+            err.failed_compensations,
+            [(1, FakeError::Error("compensation"))]
+        );
+        assert_eq!(*log.lock().unwrap(), ["do a", "do ab", "undo a"]);
+    }
+}
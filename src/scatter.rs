@@ -0,0 +1,224 @@
+//! Redundant requests, for when a few confirmations are enough.
+//!
+//! [`Scatter`] sends a request to all of its services at once, and responds
+//! as soon as `K` of them succeeded. The requests still running then are
+//! cancelled, by dropping them.
+
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    task::Poll,
+};
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer},
+    Service,
+};
+
+/// Sends each request to every service, responding with the first `K`
+/// successful responses, in the order they arrived.
+pub struct Scatter<const K: usize, R, T> {
+    services: Vec<T>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+/// Too many services failed to get `K` responses. Every error is kept, in
+/// the order they happened.
+#[derive(Debug, PartialEq)]
+pub struct ScatterError<E> {
+    pub needed: usize,
+    pub errors: Vec<E>,
+}
+
+impl<E> fmt::Display for ScatterError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} services failed, {} successful responses were needed",
+            self.errors.len(),
+            self.needed
+        )
+    }
+}
+
+/// The source is the last error, which made getting `K` responses impossible.
+impl<E: Error + 'static> Error for ScatterError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.errors.last().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+/// Classified like the last error. Without any, there were fewer services
+/// than responses needed, which won't change on another attempt.
+impl<E: Classify> Classify for ScatterError<E> {
+    fn classify(&self) -> Class {
+        self.errors
+            .last()
+            .map_or(Class::Permanent, Classify::classify)
+    }
+}
+
+impl<const K: usize, R, T> Scatter<K, R, T> {
+    pub fn new(services: Vec<T>) -> Self {
+        Scatter {
+            services,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn services(&self) -> &[T] {
+        &self.services
+    }
+}
+
+impl<const K: usize, R: Clone, T: Service<R>> Service<R> for Scatter<K, R, T> {
+    type Response = Vec<T::Response>;
+    type Error = ScatterError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let mut responses = Vec::with_capacity(K);
+        let mut errors = Vec::new();
+        if K == 0 {
+            return Ok(responses);
+        }
+
+        let mut running: Vec<_> = self
+            .services
+            .iter()
+            .map(|service| Some(Box::pin(service.request(msg.clone()))))
+            .collect();
+
+        poll_fn(|cx| {
+            for slot in running.iter_mut() {
+                let Some(request) = slot else {
+                    continue;
+                };
+                let Poll::Ready(res) = request.as_mut().poll(cx) else {
+                    continue;
+                };
+
+                *slot = None;
+                match res {
+                    Ok(resp) => responses.push(resp),
+                    Err(e) => errors.push(e),
+                }
+                if responses.len() == K || self.services.len() - errors.len() < K {
+                    return Poll::Ready(());
+                }
+            }
+
+            if self.services.len() < K {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        if responses.len() == K {
+            Ok(responses)
+        } else {
+            Err(ScatterError { needed: K, errors })
+        }
+    }
+}
+
+/// The services don't make a single stack, so this is a leaf.
+impl<const K: usize, R, T> Describe for Scatter<K, R, T> {
+    fn name(&self) -> &'static str {
+        "scatter"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("needed", K.to_string()),
+            ("services", self.services.len().to_string()),
+        ])
+    }
+}
+
+impl<const K: usize, R: 'static, T: 'static> FindLayer for Scatter<K, R, T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use thiserror::Error;
+    use tokio::time::{sleep, Instant};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    /// Responds with its ID after its delay, or fails
+    pub struct TestReplica {
+        id: u64,
+        delay_ms: u64,
+        fail: bool,
+    }
+
+    impl Service<()> for TestReplica {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            sleep(Duration::from_millis(self.delay_ms)).await;
+            if self.fail {
+                Err(FakeError::Error)
+            } else {
+                Ok(self.id)
+            }
+        }
+    }
+
+    fn replicas(replicas: &[(u64, bool)]) -> Vec<TestReplica> {
+        replicas
+            .iter()
+            .enumerate()
+            .map(|(id, &(delay_ms, fail))| TestReplica {
+                id: id as u64,
+                delay_ms,
+                fail,
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scatter_test() {
+        let scatter = Scatter::<2, _, _>::new(replicas(&[
+            (30, false),
+            (10, true),
+            (20, false),
+            (100, false),
+        ]));
+
+        // The slowest replica isn't waited for
+        let started = Instant::now();
+        assert_eq!(scatter.request(()).await, Ok(vec![2, 0]));
+        assert_eq!(started.elapsed(), Duration::from_millis(30));
+
+        // Gives up as soon as too many failed
+        let scatter = Scatter::<2, _, _>::new(replicas(&[(10, true), (20, true), (100, false)]));
+        let started = Instant::now();
+        let err = scatter.request(()).await.unwrap_err();
+        assert_eq!(err.errors, [FakeError::Error, FakeError::Error]);
+        assert_eq!(started.elapsed(), Duration::from_millis(20));
+
+        let scatter = Scatter::<2, _, _>::new(replicas(&[(10, false)]));
+        assert_eq!(
+            scatter.request(()).await,
+            Err(ScatterError {
+                needed: 2,
+                errors: vec![]
+            })
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Load shedding of requests that can't finish in time.
+//!
+//! A request whose [deadline](crate::meta::Meta::deadline) is closer than the
+//! service can possibly answer is doomed: the caller will have given up by
+//! the time the response comes. [`DeadlineShed`] rejects those right away
+//! instead of spending the service's capacity on them.
+
+use std::{collections::BTreeMap, marker::PhantomData, time::Duration};
+
+use thiserror::Error;
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack, StackStats},
+    error::{FromTimeout, IntoFlat},
+    events::{self, StackEvent},
+    sync::{AtomicU64, Ordering},
+    Middleware, Service,
+};
+
+/// Rejects requests whose remaining time before the deadline is below the
+/// minimum latency of the service. Requests without a deadline always go
+/// through.
+pub struct DeadlineShed<R, T: Service<R>> {
+    inner: T,
+    min_latency: Duration,
+    shed: AtomicU64,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ShedError<E: core::error::Error> {
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
+    #[error("not enough time left before the deadline")]
+    Shed,
+}
+
+impl<E: core::error::Error> ShedError<E> {
+    #[inline]
+    pub fn is_shed(&self) -> bool {
+        matches!(self, ShedError::Shed)
+    }
+}
+
+/// A shed request is as good as timed out.
+impl<E: core::error::Error + IntoFlat<F>, F: FromTimeout> IntoFlat<F> for ShedError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            ShedError::ServiceError(e) => e.into_flat(),
+            ShedError::Shed => F::timed_out(),
+        }
+    }
+}
+
+/// The caller stops waiting before any attempt could finish.
+impl<E: core::error::Error + Classify> Classify for ShedError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            ShedError::ServiceError(e) => e.classify(),
+            ShedError::Shed => Class::Cancelled,
+        }
+    }
+}
+
+impl<R, T: Service<R>> DeadlineShed<R, T> {
+    /// Sheds the requests with less than `min_latency` left.
+    pub fn new(service: T, min_latency: Duration) -> Self {
+        DeadlineShed {
+            inner: service,
+            min_latency,
+            shed: AtomicU64::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Requests rejected so far.
+    pub fn shed_count(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    fn is_doomed(&self) -> bool {
+        crate::meta::remaining().is_some_and(|remaining| remaining < self.min_latency)
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for DeadlineShed<R, T> {
+    type Response = T::Response;
+    type Error = ShedError<T::Error>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        if self.is_doomed() {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            events::emit(StackEvent::Shed);
+            return Err(ShedError::Shed);
+        }
+
+        self.inner
+            .request(msg)
+            .await
+            .map_err(ShedError::ServiceError)
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for DeadlineShed<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for DeadlineShed<R, T> {
+    fn name(&self) -> &'static str {
+        "deadline_shed"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("min_latency_ms", self.min_latency.as_millis().to_string()),
+            ("shed", self.shed_count().to_string()),
+        ])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for DeadlineShed<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+
+    fn record_stats(&self, stats: &mut StackStats) {
+        StackStats::add(&mut stats.shed, self.shed_count());
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::meta::{Meta, MetaService, WithMeta};
+
+    #[derive(Debug)]
+    pub struct TestShedService {}
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    impl Service<u64> for TestShedService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_shed_test() {
+        let service = MetaService::new(DeadlineShed::new(
+            TestShedService {},
+            Duration::from_millis(100),
+        ));
+        let in_ms = |ms| Meta::default().with_deadline(Instant::now() + Duration::from_millis(ms));
+
+        assert_eq!(
+            service.request(WithMeta::new(1, in_ms(60_000))).await,
+            Ok(1)
+        );
+        assert_eq!(
+            service.request(WithMeta::new(2, in_ms(10))).await,
+            Err(ShedError::Shed)
+        );
+        assert_eq!(service.inner_service().shed_count(), 1);
+
+        // No deadline, nothing to shed for
+        assert_eq!(
+            service.request(WithMeta::new(3, Meta::default())).await,
+            Ok(3)
+        );
+        assert_eq!(
+            DeadlineShed::new(TestShedService {}, Duration::from_secs(1))
+                .request(4)
+                .await,
+            Ok(4)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Stopping the background work of a whole stack at once.
+//!
+//! Some layers run work outside of requests (channel workers, retry queues,
+//! restarts...). A [`Shutdown`] is shared by all of them: each background
+//! task takes a [`Signal`], a future resolving once the shutdown is
+//! triggered, and counts as running until it drops it. Triggering the
+//! shutdown then tells every task to stop, and [`Shutdown::quiesced`]
+//! resolves once they all did.
+//!
+//! ```ignore
+//! let shutdown = Shutdown::new();
+//! tokio::spawn(worker.run_until(service, shutdown.signal()));
+//! tokio::spawn(queue_worker.run_until(other, shutdown.signal()));
+//! let stack = Restart::new(generator, ()).await?.until(shutdown.clone());
+//!
+//! // Later on
+//! shutdown.shutdown().await;
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::PoisonError,
+    task::{Context, Poll, Waker},
+};
+
+use crate::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    triggered: bool,
+    /// Signals not dropped yet.
+    running: usize,
+    next_id: u64,
+    /// Signals waiting for the trigger.
+    signals: HashMap<u64, Waker>,
+    /// Futures waiting for every signal to be dropped.
+    quiesced: HashMap<u64, Waker>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// Shutdown of the background tasks of a stack. Cloning it is cheap, and
+/// all the clones trigger the same shutdown.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<Mutex<State>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A future resolving once the shutdown is triggered, for a background
+    /// task to stop on. The task counts as running until it drops it.
+    pub fn signal(&self) -> Signal {
+        let mut state = self.state.lock().unwrap();
+        state.running += 1;
+        Signal {
+            state: self.state.clone(),
+            id: state.next_id(),
+        }
+    }
+
+    /// Tells every task to stop, without waiting for them.
+    pub fn trigger(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.triggered = true;
+        for (_, waker) in state.signals.drain() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.state.lock().unwrap().triggered
+    }
+
+    /// Signals still held by a task.
+    pub fn running(&self) -> usize {
+        self.state.lock().unwrap().running
+    }
+
+    /// Resolves once every signal has been dropped.
+    pub async fn quiesced(&self) {
+        let id = self.state.lock().unwrap().next_id();
+        let _waiting = Waiting {
+            state: &self.state,
+            id,
+        };
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.running == 0 {
+                Poll::Ready(())
+            } else {
+                state.quiesced.insert(id, cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Triggers the shutdown, and resolves once every task stopped.
+    pub async fn shutdown(&self) {
+        self.trigger();
+        self.quiesced().await
+    }
+}
+
+/// Unregisters a [`Shutdown::quiesced`] future that was dropped.
+struct Waiting<'a> {
+    state: &'a Mutex<State>,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .quiesced
+            .remove(&self.id);
+    }
+}
+
+/// Resolves once its [`Shutdown`] is triggered. The task holding it counts
+/// as running until it's dropped.
+pub struct Signal {
+    state: Arc<Mutex<State>>,
+    id: u64,
+}
+
+impl Signal {
+    pub fn is_triggered(&self) -> bool {
+        self.state.lock().unwrap().triggered
+    }
+}
+
+impl Future for Signal {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.triggered {
+            Poll::Ready(())
+        } else {
+            state.signals.insert(self.id, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Signal {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.signals.remove(&self.id);
+        state.running -= 1;
+        if state.running == 0 {
+            for (_, waker) in state.quiesced.drain() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_test() {
+        let shutdown = Shutdown::new();
+        let fast = shutdown.signal();
+        let slow = shutdown.signal();
+        let stopped = tokio::spawn(async move {
+            fast.await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            slow.await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        assert_eq!(shutdown.running(), 2);
+
+        let started = tokio::time::Instant::now();
+        shutdown.shutdown().await;
+        assert!(shutdown.is_triggered());
+        assert_eq!(shutdown.running(), 0);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+        stopped.await.unwrap();
+
+        // Signals taken afterwards resolve right away
+        shutdown.signal().await;
+        shutdown.quiesced().await;
+    }
+}
@@ -0,0 +1,256 @@
+//! Moves a service into its own worker and hands out cheap handles to it.
+//!
+//! The request futures of a generic service can't be required to be `Send`,
+//! so instead of `tokio::spawn`, [`spawn_service`] starts a dedicated thread
+//! running a current-thread Tokio runtime. Only the service itself has to
+//! be `Send`, which means `!Sync` services (e.g. holding a `Cell`) work too.
+//!
+//! Worker threads are named (`jenga-worker-N` unless a name is given), so they
+//! can be told apart in debuggers and profilers, and [`workers`] lists the
+//! ones currently running.
+
+use core::error::Error;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+};
+
+use tokio::{runtime::Builder, sync::watch};
+
+use crate::{
+    channel::{ChannelError, ChannelService},
+    describe::{Describe, FindLayer},
+    Service,
+};
+
+/// How many requests can be queued for the worker before callers wait.
+const BUFFER: usize = 32;
+
+static NEXT_WORKER_ID: AtomicUsize = AtomicUsize::new(0);
+static WORKERS: Mutex<Vec<(usize, Arc<str>)>> = Mutex::new(Vec::new());
+
+/// Cloneable handle to a service running in its own worker.
+pub struct Handle<R, Resp, E> {
+    service: ChannelService<R, Resp, E>,
+    shutdown: Arc<watch::Sender<bool>>,
+    name: Arc<str>,
+}
+
+/// Keeps a worker listed in [`workers`] for as long as it's alive.
+struct Registration {
+    id: usize,
+}
+
+impl Registration {
+    fn new(id: usize, name: Arc<str>) -> Self {
+        WORKERS.lock().unwrap().push((id, name));
+        Registration { id }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        WORKERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Names of the workers that are currently running.
+pub fn workers() -> Vec<Arc<str>> {
+    WORKERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// Spawns `service` into a dedicated worker, returning a handle to it.
+///
+/// The worker runs until [`Handle::shutdown`] is called, or until
+/// every handle has been dropped.
+pub fn spawn_service<R, S>(service: S) -> Handle<R, S::Response, S::Error>
+where
+    R: Send + 'static,
+    S: Service<R> + Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    let id = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+    spawn_worker(id, format!("jenga-worker-{id}").into(), service)
+}
+
+/// Like [`spawn_service`], with the given name for the worker.
+pub fn spawn_named_service<R, S>(
+    name: impl Into<Arc<str>>,
+    service: S,
+) -> Handle<R, S::Response, S::Error>
+where
+    R: Send + 'static,
+    S: Service<R> + Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    let id = NEXT_WORKER_ID.fetch_add(1, Ordering::Relaxed);
+    spawn_worker(id, name.into(), service)
+}
+
+fn spawn_worker<R, S>(id: usize, name: Arc<str>, service: S) -> Handle<R, S::Response, S::Error>
+where
+    R: Send + 'static,
+    S: Service<R> + Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    let (channel, worker) = ChannelService::new(BUFFER);
+    let (shutdown, mut stop) = watch::channel(false);
+
+    // Registered before the thread starts, so the worker is listed right away
+    let registration = Registration::new(id, name.clone());
+
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let runtime = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("could not build the worker runtime");
+
+            runtime.block_on(async {
+                let signal = async {
+                    // An error means every handle is gone, which stops the worker anyway
+                    let _ = stop.wait_for(|stopped| *stopped).await;
+                };
+                worker.run_until(service, signal).await;
+            });
+
+            // Only now can `Handle::shutdown` resolve
+            drop(registration);
+            drop(stop);
+        })
+        .expect("could not spawn the worker thread");
+
+    Handle {
+        service: channel,
+        shutdown: Arc::new(shutdown),
+        name,
+    }
+}
+
+impl<R, Resp, E> Handle<R, Resp, E> {
+    /// Name of the worker, which is also the name of its thread.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gracefully stops the worker: new requests are rejected with
+    /// [`ChannelError::Closed`], and this resolves once the requests
+    /// that were already queued have been processed.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.shutdown.closed().await;
+    }
+}
+
+impl<R, Resp, E> Clone for Handle<R, Resp, E> {
+    fn clone(&self) -> Self {
+        Handle {
+            service: self.service.clone(),
+            shutdown: self.shutdown.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl<R, Resp, E: Error + 'static> Service<R> for Handle<R, Resp, E> {
+    type Response = Resp;
+    type Error = ChannelError<E>;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.service.request(msg).await
+    }
+}
+
+impl<R, Resp, E> Describe for Handle<R, Resp, E> {
+    fn name(&self) -> &'static str {
+        "spawn"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        let mut params = self.service.params();
+        params.insert("worker", self.name.to_string());
+        params
+    }
+}
+
+impl<R: 'static, Resp: 'static, E: 'static> FindLayer for Handle<R, Resp, E> {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use thiserror::Error;
+
+    use super::*;
+
+    /// Not `Sync`, so it couldn't be shared across tasks directly
+    #[derive(Debug)]
+    pub struct TestSpawnService {
+        counter: Cell<u64>,
+    }
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum EmptyError {}
+
+    impl Service<u64> for TestSpawnService {
+        type Response = u64;
+        type Error = EmptyError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            self.counter.set(self.counter.get() + msg);
+            Ok(self.counter.get())
+        }
+    }
+
+    #[tokio::test]
+    async fn spawn_test() {
+        let handle = spawn_service(TestSpawnService {
+            counter: Cell::new(0),
+        });
+        let other_handle = handle.clone();
+
+        assert_eq!(handle.request(1).await, Ok(1));
+        assert_eq!(other_handle.request(2).await, Ok(3));
+
+        handle.shutdown().await;
+
+        assert_eq!(handle.request(1).await, Err(ChannelError::Closed));
+        assert_eq!(other_handle.request(1).await, Err(ChannelError::Closed));
+    }
+
+    #[tokio::test]
+    async fn spawn_named_test() {
+        let handle = spawn_named_service(
+            "test-spawn-named",
+            TestSpawnService {
+                counter: Cell::new(0),
+            },
+        );
+        assert_eq!(handle.name(), "test-spawn-named");
+        assert!(workers().iter().any(|name| &**name == "test-spawn-named"));
+
+        handle.shutdown().await;
+        assert!(!workers().iter().any(|name| &**name == "test-spawn-named"));
+
+        let handle = spawn_service(TestSpawnService {
+            counter: Cell::new(0),
+        });
+        assert!(handle.name().starts_with("jenga-worker-"));
+    }
+}
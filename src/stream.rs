@@ -0,0 +1,779 @@
+//! Services whose response is a [`Stream`] of items.
+//!
+//! The other middlewares treat a stream like any other response: a
+//! [`Timeout`](crate::timeout::Timeout) only covers opening it, and a
+//! [`Retry`](crate::retry::Retry) can't do anything about a failure halfway
+//! through. The middlewares in here look at the items instead:
+//! - [`ItemTimeout`] fails the stream when the next item takes too long.
+//! - [`StreamRetry`] reopens the stream from the last checkpoint of a
+//!   [`Resumable`] request when an item is an error.
+//! - [`CountItems`] counts the items going through.
+//!
+//! The other way around, [`call_all`] turns a stream of requests into the
+//! stream of their results, to plug a stack into a stream pipeline, e.g. a
+//! stream of jobs into a rate limited service.
+//!
+//! They rely on Tokio for their timers.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+pub use futures_core::Stream;
+use thiserror::Error;
+use tokio::time::{sleep, Instant, Sleep};
+
+use crate::{
+    classify::{Class, Classify},
+    describe::{Describe, FindLayer, Stack},
+    resume::Resumable,
+    sync::{AtomicU64, Ordering},
+    Middleware, Service,
+};
+
+/// A service responding with a [`Stream`], implemented for all of them.
+pub trait StreamingService<R>: Service<R, Response: Stream> {}
+
+impl<R, T: Service<R, Response: Stream>> StreamingService<R> for T {}
+
+/// Item of the streams of [`ItemTimeout`] that took too long.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("stream item timed out")]
+pub struct ItemTimedOut;
+
+impl Classify for ItemTimedOut {
+    fn classify(&self) -> Class {
+        Class::Transient
+    }
+}
+
+/// Waits at most a given duration for each item of the response stream.
+///
+/// A stream that times out yields [`ItemTimedOut`], then ends. Only the
+/// time spent waiting on the stream counts, not the time the consumer takes
+/// between items. Opening the stream isn't timed, wrap the service in a
+/// [`Timeout`](crate::timeout::Timeout) for that.
+pub struct ItemTimeout<R, T: Service<R>> {
+    inner: T,
+    timeout_duration: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> ItemTimeout<R, T> {
+    pub fn new(service: T, timeout_duration: Duration) -> Self {
+        ItemTimeout {
+            inner: service,
+            timeout_duration,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T: StreamingService<R>> Service<R> for ItemTimeout<R, T> {
+    type Response = TimeoutStream<T::Response>;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let stream = self.inner.request(msg).await?;
+        Ok(TimeoutStream {
+            stream: Some(Box::pin(stream)),
+            sleep: Box::pin(sleep(self.timeout_duration)),
+            armed: false,
+            timeout_duration: self.timeout_duration,
+        })
+    }
+}
+
+impl<R, T: StreamingService<R>> Middleware<R, T> for ItemTimeout<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for ItemTimeout<R, T> {
+    fn name(&self) -> &'static str {
+        "item_timeout"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("duration_ms", self.timeout_duration.as_millis().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for ItemTimeout<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Stream of an [`ItemTimeout`].
+pub struct TimeoutStream<S> {
+    /// `None` once the stream ended or timed out.
+    stream: Option<Pin<Box<S>>>,
+    sleep: Pin<Box<Sleep>>,
+    /// Whether `sleep` runs for the awaited item, it's reset once the stream
+    /// is first found pending.
+    armed: bool,
+    timeout_duration: Duration,
+}
+
+impl<S: Stream> Stream for TimeoutStream<S> {
+    type Item = Result<S::Item, ItemTimedOut>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let Some(stream) = &mut this.stream else {
+            return Poll::Ready(None);
+        };
+
+        match stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                this.armed = false;
+                Poll::Ready(Some(Ok(item)))
+            }
+            Poll::Ready(None) => {
+                this.stream = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                if !this.armed {
+                    let deadline = Instant::now() + this.timeout_duration;
+                    this.sleep.as_mut().reset(deadline);
+                    this.armed = true;
+                }
+                ready!(this.sleep.as_mut().poll(cx));
+                this.stream = None;
+                Poll::Ready(Some(Err(ItemTimedOut)))
+            }
+        }
+    }
+}
+
+/// Reopens the response stream when one of its items is an error, up to
+/// `RETRY_COUNT` times per request.
+///
+/// The request is [resumed](Resumable::resume) first, so the new stream
+/// picks up where the failed one left off. Opening the stream the first
+/// time isn't retried, wrap the service in a [`Retry`](crate::retry::Retry)
+/// for that.
+///
+/// Reopening needs a future that outlives the call to `request()`, so the
+/// service is kept in an [`Arc`] and the streams aren't `Send`.
+pub struct StreamRetry<const RETRY_COUNT: usize, R, T: Service<R>> {
+    inner: Arc<T>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<const RETRY_COUNT: usize, R, T: Service<R>> StreamRetry<RETRY_COUNT, R, T> {
+    pub fn new(service: T) -> Self {
+        StreamRetry {
+            inner: Arc::new(service),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<const RETRY_COUNT: usize, R, T, I, E> Service<R> for StreamRetry<RETRY_COUNT, R, T>
+where
+    R: Resumable + Clone + 'static,
+    T: Service<R, Error = E> + 'static,
+    T::Response: Stream<Item = Result<I, E>>,
+    E: core::error::Error + 'static,
+{
+    type Response = ResumingStream<R, T>;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let stream = self.inner.request(msg.clone()).await?;
+        Ok(ResumingStream {
+            service: self.inner.clone(),
+            msg,
+            retries_left: RETRY_COUNT,
+            state: State::Streaming(Box::pin(stream)),
+        })
+    }
+}
+
+impl<const RETRY_COUNT: usize, R, T, I, E> Middleware<R, T> for StreamRetry<RETRY_COUNT, R, T>
+where
+    R: Resumable + Clone + 'static,
+    T: Service<R, Error = E> + 'static,
+    T::Response: Stream<Item = Result<I, E>>,
+    E: core::error::Error + 'static,
+{
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<const RETRY_COUNT: usize, R, T: Service<R> + Describe> Describe
+    for StreamRetry<RETRY_COUNT, R, T>
+{
+    fn name(&self) -> &'static str {
+        "stream_retry"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("count", RETRY_COUNT.to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<const RETRY_COUNT: usize, R: 'static, T: Service<R> + FindLayer> FindLayer
+    for StreamRetry<RETRY_COUNT, R, T>
+{
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&*self.inner)
+    }
+}
+
+type Reopening<T, E> = Pin<Box<dyn Future<Output = Result<T, E>>>>;
+
+enum State<S, E> {
+    Streaming(Pin<Box<S>>),
+    Reopening(Reopening<S, E>),
+    Done,
+}
+
+/// Stream of a [`StreamRetry`].
+pub struct ResumingStream<R, T: Service<R>> {
+    service: Arc<T>,
+    msg: R,
+    retries_left: usize,
+    state: State<T::Response, T::Error>,
+}
+
+// The request is never pinned, only the inner streams and futures are
+impl<R, T: Service<R>> Unpin for ResumingStream<R, T> {}
+
+impl<R, T, I, E> ResumingStream<R, T>
+where
+    R: Resumable + Clone + 'static,
+    T: Service<R, Error = E> + 'static,
+    T::Response: Stream<Item = Result<I, E>>,
+    E: core::error::Error + 'static,
+{
+    /// Takes a retry if there's one left and reopens the stream from the
+    /// last checkpoint, or gives up.
+    fn retry_or_fail(&mut self, err: E) -> Option<E> {
+        if self.retries_left == 0 {
+            self.state = State::Done;
+            return Some(err);
+        }
+        self.retries_left -= 1;
+
+        self.msg.resume();
+        let service = self.service.clone();
+        let msg = self.msg.clone();
+        self.state = State::Reopening(Box::pin(async move { service.request(msg).await }));
+        None
+    }
+}
+
+impl<R, T, I, E> Stream for ResumingStream<R, T>
+where
+    R: Resumable + Clone + 'static,
+    T: Service<R, Error = E> + 'static,
+    T::Response: Stream<Item = Result<I, E>>,
+    E: core::error::Error + 'static,
+{
+    type Item = Result<I, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let err = match &mut this.state {
+                State::Streaming(stream) => match ready!(stream.as_mut().poll_next(cx)) {
+                    Some(Ok(item)) => return Poll::Ready(Some(Ok(item))),
+                    Some(Err(err)) => err,
+                    None => {
+                        this.state = State::Done;
+                        return Poll::Ready(None);
+                    }
+                },
+                State::Reopening(reopening) => match ready!(reopening.as_mut().poll(cx)) {
+                    Ok(stream) => {
+                        this.state = State::Streaming(Box::pin(stream));
+                        continue;
+                    }
+                    Err(err) => err,
+                },
+                State::Done => return Poll::Ready(None),
+            };
+
+            if let Some(err) = this.retry_or_fail(err) {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+    }
+}
+
+/// Counts the items of the response streams.
+pub struct CountItems<R, T: Service<R>> {
+    inner: T,
+    items: Arc<AtomicU64>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> CountItems<R, T> {
+    pub fn new(service: T) -> Self {
+        CountItems {
+            inner: service,
+            items: Arc::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Items yielded by every stream so far, finished or not.
+    pub fn items(&self) -> u64 {
+        self.items.load(Ordering::Relaxed)
+    }
+}
+
+impl<R, T: StreamingService<R>> Service<R> for CountItems<R, T> {
+    type Response = CountedStream<T::Response>;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let stream = self.inner.request(msg).await?;
+        Ok(CountedStream {
+            stream: Box::pin(stream),
+            items: self.items.clone(),
+        })
+    }
+}
+
+impl<R, T: StreamingService<R>> Middleware<R, T> for CountItems<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<R, T: Service<R> + Describe> Describe for CountItems<R, T> {
+    fn name(&self) -> &'static str {
+        "count_items"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        BTreeMap::from([("items", self.items().to_string())])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for CountItems<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
+/// Stream of a [`CountItems`].
+pub struct CountedStream<S> {
+    stream: Pin<Box<S>>,
+    items: Arc<AtomicU64>,
+}
+
+impl<S: Stream> Stream for CountedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.stream.as_mut().poll_next(cx));
+        if item.is_some() {
+            self.items.fetch_add(1, Ordering::Relaxed);
+
+            #[cfg(feature = "metrics")]
+            ::metrics::counter!(crate::metrics::STREAM_ITEMS, "layer" => "count_items")
+                .increment(1);
+        }
+        Poll::Ready(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+/// Sends the requests of `requests` to `service`, yielding their results.
+/// An error doesn't end the stream.
+///
+/// Requests are sent one at a time by default, see
+/// [`CallAll::concurrency`] to send more, and their results come in the
+/// order of the requests unless [`CallAll::unordered`].
+///
+/// Like [`StreamRetry`], the service is shared through an [`Arc`] for the
+/// futures to outlive the calls, so the stream isn't `Send`.
+pub fn call_all<R, T, St>(service: Arc<T>, requests: St) -> CallAll<T, St>
+where
+    R: 'static,
+    T: Service<R> + 'static,
+    St: Stream<Item = R>,
+{
+    CallAll {
+        service,
+        requests: Box::pin(requests),
+        pending: VecDeque::new(),
+        concurrency: 1,
+        ordered: true,
+        exhausted: false,
+    }
+}
+
+type Pending<T, E> = Pin<Box<dyn Future<Output = Result<T, E>>>>;
+
+enum Call<T, E> {
+    Running(Pending<T, E>),
+    /// Waiting for the calls before it, when ordered.
+    Done(Result<T, E>),
+}
+
+/// Stream of [`call_all`].
+pub struct CallAll<T, St: Stream>
+where
+    T: Service<St::Item>,
+{
+    service: Arc<T>,
+    requests: Pin<Box<St>>,
+    /// In the order of the requests.
+    pending: VecDeque<Call<T::Response, T::Error>>,
+    concurrency: usize,
+    ordered: bool,
+    exhausted: bool,
+}
+
+impl<T, St> CallAll<T, St>
+where
+    St: Stream,
+    T: Service<St::Item>,
+{
+    /// Sends up to `concurrency` requests at a time.
+    pub fn concurrency(self, concurrency: usize) -> Self {
+        CallAll {
+            concurrency: concurrency.max(1),
+            ..self
+        }
+    }
+
+    /// Yields the results as soon as they're ready, in any order.
+    pub fn unordered(self) -> Self {
+        CallAll {
+            ordered: false,
+            ..self
+        }
+    }
+}
+
+// Only the boxed calls and requests are ever pinned
+impl<T: Service<St::Item>, St: Stream> Unpin for CallAll<T, St> {}
+
+impl<T, St> Stream for CallAll<T, St>
+where
+    St: Stream,
+    St::Item: 'static,
+    T: Service<St::Item> + 'static,
+{
+    type Item = Result<T::Response, T::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while !this.exhausted && this.pending.len() < this.concurrency {
+            match this.requests.as_mut().poll_next(cx) {
+                Poll::Ready(Some(msg)) => {
+                    let service = this.service.clone();
+                    let call = Box::pin(async move { service.request(msg).await });
+                    this.pending.push_back(Call::Running(call));
+                }
+                Poll::Ready(None) => this.exhausted = true,
+                Poll::Pending => break,
+            }
+        }
+
+        for call in &mut this.pending {
+            if let Call::Running(running) = call {
+                if let Poll::Ready(res) = running.as_mut().poll(cx) {
+                    *call = Call::Done(res);
+                }
+            }
+        }
+
+        let done = if this.ordered {
+            matches!(this.pending.front(), Some(Call::Done(_))).then_some(0)
+        } else {
+            this.pending
+                .iter()
+                .position(|call| matches!(call, Call::Done(_)))
+        };
+        match done.and_then(|i| this.pending.remove(i)) {
+            Some(Call::Done(res)) => Poll::Ready(Some(res)),
+            _ if this.exhausted && this.pending.is_empty() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{collections::VecDeque, future::poll_fn};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    /// Yields each item after its delay
+    pub struct TestStream {
+        items: VecDeque<(Duration, Result<u64, FakeError>)>,
+        sleep: Option<Pin<Box<Sleep>>>,
+    }
+
+    impl Stream for TestStream {
+        type Item = Result<u64, FakeError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let Some(&(delay, _)) = self.items.front() else {
+                return Poll::Ready(None);
+            };
+            let sleep = self.sleep.get_or_insert_with(|| Box::pin(sleep(delay)));
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+            Poll::Ready(self.items.pop_front().map(|(_, item)| item))
+        }
+    }
+
+    async fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    /// Streams `0..len` from `start`, one item every 10ms
+    #[derive(Debug, Clone)]
+    pub struct Range {
+        start: u64,
+        len: u64,
+        /// Items sent so far, shared by every attempt
+        sent: Arc<AtomicU64>,
+    }
+
+    impl Resumable for Range {
+        type Checkpoint = u64;
+
+        fn checkpoint(&self) -> Option<u64> {
+            Some(self.sent.load(Ordering::SeqCst))
+        }
+
+        fn resume_from(&mut self, checkpoint: u64) {
+            self.start = checkpoint;
+        }
+    }
+
+    /// Drops the stream on item 2 and 5, the first time they're sent
+    #[derive(Debug, Default)]
+    pub struct TestStreamService {
+        dropped: std::sync::Mutex<Vec<u64>>,
+    }
+
+    impl Service<Range> for TestStreamService {
+        type Response = Counted;
+        type Error = FakeError;
+
+        async fn request(&self, msg: Range) -> Result<Self::Response, Self::Error> {
+            let mut dropped = self.dropped.lock().unwrap();
+            let items = (msg.start..msg.len)
+                .map_while(|i| {
+                    if [2, 5].contains(&i) && !dropped.contains(&i) {
+                        dropped.push(i);
+                        None
+                    } else {
+                        Some((Duration::from_millis(10), Ok(i)))
+                    }
+                })
+                .collect::<VecDeque<_>>();
+            let failed = msg.start + (items.len() as u64) < msg.len;
+
+            let mut items = items;
+            if failed {
+                items.push_back((Duration::from_millis(10), Err(FakeError::Error)));
+            }
+            Ok(Counted {
+                stream: TestStream { items, sleep: None },
+                sent: msg.sent,
+            })
+        }
+    }
+
+    /// Keeps the checkpoint of the request up to date
+    pub struct Counted {
+        stream: TestStream,
+        sent: Arc<AtomicU64>,
+    }
+
+    impl Stream for Counted {
+        type Item = Result<u64, FakeError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let item = ready!(Pin::new(&mut self.stream).poll_next(cx));
+            if let Some(Ok(i)) = item {
+                self.sent.store(i + 1, Ordering::SeqCst);
+            }
+            Poll::Ready(item)
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct TestSlowStreamService {}
+
+    impl Service<Vec<u64>> for TestSlowStreamService {
+        type Response = TestStream;
+        type Error = FakeError;
+
+        async fn request(&self, delays_ms: Vec<u64>) -> Result<Self::Response, Self::Error> {
+            let items = delays_ms
+                .into_iter()
+                .map(|ms| (Duration::from_millis(ms), Ok(ms)))
+                .collect();
+            Ok(TestStream { items, sleep: None })
+        }
+    }
+
+    pub struct Iter<I>(I);
+
+    impl<I: Iterator + Unpin> Stream for Iter<I> {
+        type Item = I::Item;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    async fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+        let mut all = Vec::new();
+        while let Some(item) = next(&mut stream).await {
+            all.push(item);
+        }
+        all
+    }
+
+    #[tokio::test]
+    async fn call_all_test() {
+        let service = crate::combinators::service_fn(|msg: u64| async move {
+            if msg == 2 {
+                Err(FakeError::Error)
+            } else {
+                Ok(msg * 10)
+            }
+        });
+
+        let results = collect(call_all(Arc::new(service), Iter(0..4))).await;
+        assert_eq!(results, [Ok(0), Ok(10), Err(FakeError::Error), Ok(30)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn call_all_concurrency_test() {
+        // The later requests are the quicker ones
+        let service = Arc::new(crate::combinators::service_fn(|msg: u64| async move {
+            sleep(Duration::from_millis(40 - 10 * msg)).await;
+            Ok::<_, FakeError>(msg)
+        }));
+
+        let started = Instant::now();
+        let results = collect(call_all(service.clone(), Iter(0..4)).concurrency(4)).await;
+        assert_eq!(results, [Ok(0), Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(started.elapsed(), Duration::from_millis(40));
+
+        let results = call_all(service.clone(), Iter(0..4))
+            .concurrency(4)
+            .unordered();
+        assert_eq!(collect(results).await, [Ok(3), Ok(2), Ok(1), Ok(0)]);
+
+        // Two at a time
+        let started = Instant::now();
+        let results = call_all(service, Iter(0..4)).concurrency(2).unordered();
+        assert_eq!(collect(results).await, [Ok(1), Ok(0), Ok(2), Ok(3)]);
+        assert_eq!(started.elapsed(), Duration::from_millis(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn item_timeout_test() {
+        let service = ItemTimeout::new(TestSlowStreamService {}, Duration::from_millis(50));
+
+        // The whole stream takes longer than the timeout, but each item doesn't
+        let mut stream = service.request(vec![40, 40, 40]).await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(next(&mut stream).await, Some(Ok(Ok(40))));
+        }
+        assert_eq!(next(&mut stream).await, None);
+
+        let mut stream = service.request(vec![10, 60, 10]).await.unwrap();
+        assert_eq!(next(&mut stream).await, Some(Ok(Ok(10))));
+        assert_eq!(next(&mut stream).await, Some(Err(ItemTimedOut)));
+        assert_eq!(next(&mut stream).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn item_timeout_slow_consumer_test() {
+        let service = ItemTimeout::new(TestSlowStreamService {}, Duration::from_millis(50));
+
+        // The consumer taking longer than the timeout isn't the stream's fault
+        let mut stream = service.request(vec![10, 10, 60]).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        for _ in 0..2 {
+            assert_eq!(next(&mut stream).await, Some(Ok(Ok(10))));
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(next(&mut stream).await, Some(Err(ItemTimedOut)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_retry_test() {
+        let range = || Range {
+            start: 0,
+            len: 8,
+            sent: Arc::default(),
+        };
+
+        // Every item is received once, in order
+        let service = StreamRetry::<2, _, _>::new(TestStreamService::default());
+        let mut stream = service.request(range()).await.unwrap();
+        for i in 0..8 {
+            assert_eq!(next(&mut stream).await, Some(Ok(i)));
+        }
+        assert_eq!(next(&mut stream).await, None);
+
+        // Out of retries
+        let service = StreamRetry::<1, _, _>::new(TestStreamService::default());
+        let mut stream = service.request(range()).await.unwrap();
+        for i in 0..5 {
+            assert_eq!(next(&mut stream).await, Some(Ok(i)));
+        }
+        assert_eq!(next(&mut stream).await, Some(Err(FakeError::Error)));
+        assert_eq!(next(&mut stream).await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn count_items_test() {
+        let service = CountItems::new(TestSlowStreamService {});
+
+        let mut a = service.request(vec![1, 2]).await.unwrap();
+        let mut b = service.request(vec![3]).await.unwrap();
+        while next(&mut a).await.is_some() {}
+        assert_eq!(service.items(), 2);
+        while next(&mut b).await.is_some() {}
+        assert_eq!(service.items(), 3);
+    }
+}
@@ -0,0 +1,18 @@
+//! Synchronization primitives used by the lock-free parts of the middlewares.
+//!
+//! Under `--cfg loom` these are [loom](https://docs.rs/loom)'s model-checked
+//! versions instead of the standard ones, so the concurrency invariants of
+//! the middlewares can be checked. Middlewares outside of jenga can use them
+//! the same way.
+
+#[cfg(loom)]
+pub use loom::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+#[cfg(not(loom))]
+pub use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
@@ -0,0 +1,19 @@
+//! Test doubles for exercising middlewares and stacks.
+//!
+//! These are meant for the test suites of code using jenga, and rely on Tokio
+//! timers so they play well with `tokio::time::pause`.
+
+mod flaky;
+mod latency;
+#[cfg(feature = "proptest")]
+pub mod laws;
+mod rng;
+mod scripted;
+mod simulation;
+mod spy;
+
+pub use flaky::FlakyService;
+pub use latency::{Latency, LatencyService};
+pub use scripted::{ScriptedService, Step};
+pub use simulation::{simulate, FaultSchedule, Report, Workload};
+pub use spy::{Call, Outcome, SpyService};
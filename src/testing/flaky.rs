@@ -0,0 +1,183 @@
+use std::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::time::sleep;
+
+use super::{rng::Rng, Latency};
+use crate::Service;
+
+/// A leaf service failing at random, to see how a stack copes with it.
+///
+/// Each request fails with probability `failure_rate`, and a failure may
+/// start a burst of consecutive failures, like a real outage would. The
+/// response (or error) comes after a delay drawn from its [`Latency`]. The
+/// same seed always gives the same sequence of failures and delays, so a
+/// failing scenario can be replayed.
+pub struct FlakyService<R, F, G> {
+    respond: F,
+    fail: G,
+    failure_rate: f64,
+    burst: usize,
+    latency: Latency,
+    rng: Rng,
+    /// Failures left in the current burst.
+    burst_left: AtomicUsize,
+    calls: AtomicU64,
+    failures: AtomicU64,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, Resp, E, F, G> FlakyService<R, F, G>
+where
+    F: Fn(R) -> Resp,
+    G: Fn() -> E,
+{
+    /// Responds with `respond` and fails with the errors of `fail`, never
+    /// failing and without delay until configured otherwise.
+    pub fn new(respond: F, fail: G) -> Self {
+        FlakyService {
+            respond,
+            fail,
+            failure_rate: 0.0,
+            burst: 1,
+            latency: Latency::Fixed(Duration::ZERO),
+            rng: Rng::new(0),
+            burst_left: AtomicUsize::new(0),
+            calls: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Probability, from 0 to 1, of a request starting a failure.
+    pub fn failure_rate(self, failure_rate: f64) -> Self {
+        FlakyService {
+            failure_rate,
+            ..self
+        }
+    }
+
+    /// Each failure is followed by `burst - 1` more.
+    pub fn in_bursts(self, burst: usize) -> Self {
+        FlakyService {
+            burst: burst.max(1),
+            ..self
+        }
+    }
+
+    pub fn latency(self, latency: Latency) -> Self {
+        FlakyService { latency, ..self }
+    }
+
+    pub fn seed(self, seed: u64) -> Self {
+        FlakyService {
+            rng: Rng::new(seed),
+            ..self
+        }
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn next_fails(&self) -> bool {
+        let in_burst = self
+            .burst_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if in_burst {
+            return true;
+        }
+
+        let fails = self.rng.next_f64() < self.failure_rate;
+        if fails {
+            self.burst_left.store(self.burst - 1, Ordering::Relaxed);
+        }
+        fails
+    }
+}
+
+impl<R, Resp, E, F, G> Service<R> for FlakyService<R, F, G>
+where
+    F: Fn(R) -> Resp,
+    G: Fn() -> E,
+    E: core::error::Error + 'static,
+{
+    type Response = Resp;
+    type Error = E;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        // Drawn before waiting, so the sequence doesn't depend on timing
+        let fails = self.next_fails();
+        sleep(self.latency.sample(&self.rng)).await;
+
+        if fails {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            Err((self.fail)())
+        } else {
+            Ok((self.respond)(msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    async fn outcomes<F: Fn(u64) -> u64, G: Fn() -> FakeError>(
+        service: &FlakyService<u64, F, G>,
+    ) -> Vec<bool> {
+        let mut outcomes = Vec::new();
+        for i in 0..20 {
+            outcomes.push(service.request(i).await.is_ok());
+        }
+        outcomes
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flaky_test() {
+        let flaky = || {
+            FlakyService::new(|msg| msg, || FakeError::Error)
+                .failure_rate(0.2)
+                .in_bursts(3)
+        };
+
+        // Same seed, same failures
+        let a = flaky().seed(7);
+        let b = flaky().seed(7);
+        let failures = outcomes(&a).await;
+        assert_eq!(failures, outcomes(&b).await);
+        assert_eq!(a.calls(), 20);
+
+        // Failures come in bursts of 3
+        let runs: Vec<usize> = failures
+            .split(|ok| *ok)
+            .map(<[bool]>::len)
+            .filter(|len| *len > 0)
+            .collect();
+        assert!(!runs.is_empty());
+        assert!(runs[..runs.len() - 1].iter().all(|len| len % 3 == 0));
+
+        let never = FlakyService::new(|msg: u64| msg, || FakeError::Error);
+        assert_eq!(never.request(4).await, Ok(4));
+        assert_eq!(never.failures(), 0);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{marker::PhantomData, time::Duration};
+
+use tokio::time::sleep;
+
+use super::rng::Rng;
+use crate::{Middleware, Service};
+
+/// How long a [`LatencyService`] waits before each request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Uniformly distributed between `min` and `max`
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Log-normally distributed: usually close to `median`, with a long tail
+    /// of slow requests that gets longer as `sigma` grows
+    LogNormal {
+        median: Duration,
+        sigma: f64,
+    },
+}
+
+impl Latency {
+    pub(crate) fn sample(&self, rng: &Rng) -> Duration {
+        match *self {
+            Latency::Fixed(duration) => duration,
+            Latency::Uniform { min, max } => {
+                min + (max.saturating_sub(min)).mul_f64(rng.next_f64())
+            }
+            Latency::LogNormal { median, sigma } => {
+                median.mul_f64((sigma * rng.next_normal()).exp())
+            }
+        }
+    }
+}
+
+/// A service that waits for some time before calling the inner service.
+///
+/// Delays use Tokio timers, so under `tokio::time::pause` they're deterministic,
+/// and with a given seed the random ones are always the same sequence.
+pub struct LatencyService<R, T: Service<R>> {
+    inner: T,
+    latency: Latency,
+    rng: Rng,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>> LatencyService<R, T> {
+    pub fn new(service: T, latency: Latency) -> Self {
+        Self::with_seed(service, latency, 0)
+    }
+
+    pub fn with_seed(service: T, latency: Latency, seed: u64) -> Self {
+        LatencyService {
+            inner: service,
+            latency,
+            rng: Rng::new(seed),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<R, T: Service<R>> Service<R> for LatencyService<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        sleep(self.latency.sample(&self.rng)).await;
+        self.inner.request(msg).await
+    }
+}
+
+impl<R, T: Service<R>> Middleware<R, T> for LatencyService<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestLatencyService {}
+
+    #[derive(Debug, Error)]
+    pub enum EmptyError {}
+
+    impl Service<()> for TestLatencyService {
+        type Response = ();
+        type Error = EmptyError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(())
+        }
+    }
+
+    async fn measure<T: Service<()>>(service: &T) -> Duration {
+        let started = Instant::now();
+        let _ = service.request(()).await;
+        started.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latency_test() {
+        let fixed = LatencyService::new(
+            TestLatencyService {},
+            Latency::Fixed(Duration::from_millis(30)),
+        );
+        assert_eq!(measure(&fixed).await, Duration::from_millis(30));
+
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+        let uniform = LatencyService::new(TestLatencyService {}, Latency::Uniform { min, max });
+        for _ in 0..20 {
+            let elapsed = measure(&uniform).await;
+            assert!(elapsed >= min && elapsed <= max);
+        }
+
+        // Same seed, same delays
+        let latency = Latency::LogNormal {
+            median: Duration::from_millis(50),
+            sigma: 0.5,
+        };
+        let a = LatencyService::with_seed(TestLatencyService {}, latency, 42);
+        let b = LatencyService::with_seed(TestLatencyService {}, latency, 42);
+        for _ in 0..5 {
+            assert_eq!(measure(&a).await, measure(&b).await);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Property-based checks of the laws every middleware should follow, so that
+//! authors of third-party middlewares get a conformance suite for free.
+//!
+//! Each check builds the middleware around a leaf provided by this module,
+//! sends it the requests generated by a proptest [`Strategy`], and panics
+//! with the failing input if a law is broken.
+
+use std::fmt::Debug;
+
+use proptest::{
+    strategy::Strategy,
+    test_runner::{TestCaseError, TestRunner},
+};
+use thiserror::Error;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{Middleware, Service};
+
+/// Leaf service responding with the request it received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Echo {
+    pub id: u64,
+}
+
+/// Leaf service failing every request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlwaysFail {}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum InjectedError {
+    #[error("injected failure")]
+    Injected,
+}
+
+impl<R> Service<R> for Echo {
+    type Response = R;
+    type Error = InjectedError;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        Ok(msg)
+    }
+}
+
+impl<R> Service<R> for AlwaysFail {
+    type Response = ();
+    type Error = InjectedError;
+
+    async fn request(&self, _msg: R) -> Result<Self::Response, Self::Error> {
+        Err(InjectedError::Injected)
+    }
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not build the test runtime")
+}
+
+/// When nothing fails, responses go through the middleware unchanged.
+#[track_caller]
+pub fn assert_passthrough<R, M>(requests: impl Strategy<Value = R>, middleware: impl Fn(Echo) -> M)
+where
+    R: Clone + Debug + PartialEq,
+    M: Service<R, Response = R>,
+{
+    let runtime = runtime();
+    let result = TestRunner::default().run(&requests, |msg| {
+        let service = middleware(Echo { id: 0 });
+        match runtime.block_on(service.request(msg.clone())) {
+            Ok(resp) if resp == msg => Ok(()),
+            Ok(resp) => Err(TestCaseError::fail(format!(
+                "response {resp:?} differs from request {msg:?}"
+            ))),
+            Err(e) => Err(TestCaseError::fail(format!(
+                "request {msg:?} failed with no fault: {e}"
+            ))),
+        }
+    });
+
+    if let Err(e) = result {
+        panic!("passthrough law broken: {e}");
+    }
+}
+
+/// When the inner service fails, the middleware fails too.
+#[track_caller]
+pub fn assert_errors_propagate<R, M>(
+    requests: impl Strategy<Value = R>,
+    middleware: impl Fn(AlwaysFail) -> M,
+) where
+    R: Clone + Debug,
+    M: Service<R>,
+{
+    let runtime = runtime();
+    let result = TestRunner::default().run(&requests, |msg| {
+        let service = middleware(AlwaysFail {});
+        match runtime.block_on(service.request(msg.clone())) {
+            Ok(_) => Err(TestCaseError::fail(format!(
+                "the error of request {msg:?} was swallowed"
+            ))),
+            Err(_) => Ok(()),
+        }
+    });
+
+    if let Err(e) = result {
+        panic!("error propagation law broken: {e}");
+    }
+}
+
+/// [`Middleware::inner_service`] returns the service the middleware was built with.
+#[track_caller]
+pub fn assert_inner_roundtrip<R, M>(middleware: impl Fn(Echo) -> M)
+where
+    M: Middleware<R, Echo>,
+{
+    let result = TestRunner::default().run(&proptest::num::u64::ANY, |id| {
+        let service = middleware(Echo { id });
+        if service.inner_service().id == id {
+            Ok(())
+        } else {
+            Err(TestCaseError::fail(
+                "inner_service isn't the wrapped service",
+            ))
+        }
+    });
+
+    if let Err(e) = result {
+        panic!("inner service law broken: {e}");
+    }
+}
+
+#[cfg(all(test, feature = "retry", feature = "timeout"))]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::any;
+
+    use crate::{retry::Retry, timeout::Timeout};
+
+    use super::*;
+
+    #[test]
+    fn laws_test() {
+        assert_passthrough(any::<String>(), Retry::<2, _, _>::instant);
+        assert_errors_propagate(any::<u8>(), Retry::<2, _, _>::instant);
+        assert_inner_roundtrip::<u8, _>(Retry::<2, _, _>::instant);
+
+        let timeout = Duration::from_secs(1);
+        assert_passthrough(any::<Vec<u8>>(), |service| Timeout::new(service, timeout));
+        assert_errors_propagate(any::<u8>(), |service| Timeout::new(service, timeout));
+        assert_inner_roundtrip::<u8, _>(|service| Timeout::new(service, timeout));
+    }
+
+    /// Doesn't forward responses as they are
+    pub struct Broken<T>(T);
+
+    impl<T: Service<u8, Response = u8>> Service<u8> for Broken<T> {
+        type Response = u8;
+        type Error = T::Error;
+
+        async fn request(&self, msg: u8) -> Result<Self::Response, Self::Error> {
+            self.0.request(msg).await.map(|resp| resp.saturating_add(1))
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "passthrough law broken")]
+    fn broken_law_test() {
+        assert_passthrough(any::<u8>(), Broken);
+    }
+}
@@ -0,0 +1,124 @@
+use core::error::Error;
+use std::{collections::VecDeque, future::pending, sync::Mutex, time::Duration};
+
+use tokio::time::sleep;
+
+use crate::Service;
+
+/// What a [`ScriptedService`] does for one request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step<Resp, E> {
+    Ok(Resp),
+    Err(E),
+    /// Waits for the given duration, then responds
+    DelayThenOk(Duration, Resp),
+    Panic,
+    /// Never responds
+    Hang,
+}
+
+/// A service that goes through a predefined sequence of steps,
+/// consuming one step per request.
+///
+/// It panics if it receives more requests than it has steps.
+#[derive(Debug)]
+pub struct ScriptedService<Resp, E> {
+    steps: Mutex<VecDeque<Step<Resp, E>>>,
+    total: usize,
+}
+
+impl<Resp, E> ScriptedService<Resp, E> {
+    pub fn new(steps: impl IntoIterator<Item = Step<Resp, E>>) -> Self {
+        let steps: VecDeque<_> = steps.into_iter().collect();
+        ScriptedService {
+            total: steps.len(),
+            steps: Mutex::new(steps),
+        }
+    }
+
+    /// How many steps were consumed so far.
+    pub fn consumed(&self) -> usize {
+        self.total - self.remaining()
+    }
+
+    /// How many steps are left.
+    pub fn remaining(&self) -> usize {
+        self.steps.lock().unwrap().len()
+    }
+
+    #[track_caller]
+    pub fn assert_consumed(&self, expected: usize) {
+        assert_eq!(
+            self.consumed(),
+            expected,
+            "unexpected amount of consumed steps"
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_exhausted(&self) {
+        assert_eq!(self.remaining(), 0, "not every step was consumed");
+    }
+}
+
+impl<R, Resp, E: Error + 'static> Service<R> for ScriptedService<Resp, E> {
+    type Response = Resp;
+    type Error = E;
+
+    async fn request(&self, _msg: R) -> Result<Self::Response, Self::Error> {
+        let step = self.steps.lock().unwrap().pop_front();
+
+        match step {
+            Some(Step::Ok(resp)) => Ok(resp),
+            Some(Step::Err(err)) => Err(err),
+            Some(Step::DelayThenOk(duration, resp)) => {
+                sleep(duration).await;
+                Ok(resp)
+            }
+            Some(Step::Panic) => panic!("scripted panic"),
+            Some(Step::Hang) => pending().await,
+            None => panic!("ScriptedService received more requests than it has steps"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("")]
+        Error,
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scripted_test() {
+        let service = ScriptedService::new([
+            Step::Ok(1),
+            Step::Err(FakeError::Error),
+            Step::DelayThenOk(Duration::from_secs(1), 2),
+            Step::Hang,
+        ]);
+
+        assert_eq!(service.request(()).await, Ok(1));
+        assert_eq!(service.request(()).await, Err(FakeError::Error));
+        service.assert_consumed(2);
+
+        assert_eq!(service.request(()).await, Ok(2));
+        assert!(timeout(Duration::from_secs(60), service.request(()))
+            .await
+            .is_err());
+        service.assert_exhausted();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "more requests than it has steps")]
+    async fn scripted_exhausted_test() {
+        let service = ScriptedService::<(), FakeError>::new([]);
+        let _ = service.request(()).await;
+    }
+}
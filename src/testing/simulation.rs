@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+    marker::PhantomData,
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::time::{sleep_until, Instant};
+
+use crate::{Middleware, Service};
+
+/// Requests to send to a stack, along with when to send them.
+#[derive(Debug, Clone)]
+pub struct Workload<R> {
+    arrivals: Vec<(Duration, R)>,
+}
+
+impl<R> Workload<R> {
+    pub fn new() -> Self {
+        Workload {
+            arrivals: Vec::new(),
+        }
+    }
+
+    /// Sends `request` at `offset` since the start of the simulation.
+    pub fn at(mut self, offset: Duration, request: R) -> Self {
+        self.arrivals.push((offset, request));
+        self
+    }
+
+    /// Sends `count` copies of `request`, one every `interval`, starting at `offset`.
+    pub fn every(mut self, offset: Duration, interval: Duration, count: u32, request: R) -> Self
+    where
+        R: Clone,
+    {
+        for i in 0..count {
+            self.arrivals.push((offset + interval * i, request.clone()));
+        }
+        self
+    }
+}
+
+impl<R> Default for Workload<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What happened during a simulation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub successes: usize,
+    /// Amount of failed requests, by error message
+    pub errors: BTreeMap<String, usize>,
+    /// Latency of every request, sorted from fastest to slowest
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    pub fn total(&self) -> usize {
+        self.latencies.len()
+    }
+
+    pub fn failures(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    /// Latency under which `percent`% of the requests completed.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = ((percent / 100.0) * last as f64).round() as usize;
+        self.latencies.get(index.min(last)).copied()
+    }
+}
+
+/// Runs `workload` against `service`, every request being sent concurrently
+/// at its scheduled time, and reports on the outcome.
+///
+/// Meant to run under `tokio::time::pause`, where the whole simulation takes
+/// no real time no matter how long the timeouts and backoffs of the stack are.
+pub async fn simulate<R, S: Service<R>>(service: &S, workload: Workload<R>) -> Report {
+    let start = Instant::now();
+
+    let mut requests: Vec<_> = workload
+        .arrivals
+        .into_iter()
+        .map(|(offset, msg)| {
+            Box::pin(async move {
+                sleep_until(start + offset).await;
+                let sent = Instant::now();
+                let res = service.request(msg).await;
+                (sent.elapsed(), res.map(|_| ()).map_err(|e| e.to_string()))
+            })
+        })
+        .map(Some)
+        .collect();
+
+    let mut report = Report::default();
+
+    poll_fn(|cx| {
+        let mut pending = false;
+
+        for slot in requests.iter_mut() {
+            let Some(request) = slot else { continue };
+
+            match Pin::new(request).poll(cx) {
+                Poll::Ready((latency, res)) => {
+                    *slot = None;
+                    report.latencies.push(latency);
+                    match res {
+                        Ok(()) => report.successes += 1,
+                        Err(e) => *report.errors.entry(e).or_default() += 1,
+                    }
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+
+    report.latencies.sort();
+    report
+}
+
+/// A service that fails every request received during its outage windows,
+/// measured from when the schedule was created.
+pub struct FaultSchedule<R, T: Service<R>, F> {
+    inner: T,
+    start: Instant,
+    outages: Vec<(Duration, Duration)>,
+    fault: F,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R, T: Service<R>, F: Fn() -> T::Error> FaultSchedule<R, T, F> {
+    /// `fault` creates the error returned during outages.
+    pub fn new(service: T, fault: F) -> Self {
+        FaultSchedule {
+            inner: service,
+            start: Instant::now(),
+            outages: Vec::new(),
+            fault,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Adds an outage of `duration`, starting at `offset`.
+    pub fn outage(mut self, offset: Duration, duration: Duration) -> Self {
+        self.outages.push((offset, offset + duration));
+        self
+    }
+}
+
+impl<R, T: Service<R>, F: Fn() -> T::Error> Service<R> for FaultSchedule<R, T, F> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let now = self.start.elapsed();
+        if self
+            .outages
+            .iter()
+            .any(|(from, to)| (*from..*to).contains(&now))
+        {
+            return Err((self.fault)());
+        }
+
+        self.inner.request(msg).await
+    }
+}
+
+impl<R, T: Service<R>, F: Fn() -> T::Error> Middleware<R, T> for FaultSchedule<R, T, F> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thiserror::Error;
+
+    use crate::testing::{Latency, LatencyService};
+
+    use super::*;
+
+    #[derive(Debug)]
+    pub struct TestSimulationService {}
+
+    #[derive(Debug, Error)]
+    pub enum FakeError {
+        #[error("outage")]
+        Outage,
+    }
+
+    impl Service<()> for TestSimulationService {
+        type Response = ();
+        type Error = FakeError;
+
+        async fn request(&self, _msg: ()) -> Result<Self::Response, Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulation_test() {
+        let service = FaultSchedule::new(
+            LatencyService::new(
+                TestSimulationService {},
+                Latency::Fixed(Duration::from_millis(100)),
+            ),
+            || FakeError::Outage,
+        )
+        .outage(Duration::from_secs(10), Duration::from_secs(5));
+
+        // One request per second for 20 seconds, 5 of them during the outage
+        let workload = Workload::new().every(Duration::ZERO, Duration::from_secs(1), 20, ());
+        let report = simulate(&service, workload).await;
+
+        assert_eq!(report.total(), 20);
+        assert_eq!(report.successes, 15);
+        assert_eq!(report.errors.get("outage"), Some(&5));
+        assert_eq!(report.percentile(0.0), Some(Duration::ZERO));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,135 @@
+use std::{marker::PhantomData, sync::Mutex};
+
+use tokio::time::Instant;
+
+use crate::{Middleware, Service};
+
+/// How a recorded request ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Ok,
+    /// The error, formatted with `Display`
+    Err(String),
+}
+
+/// A request that went through a [`SpyService`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call<R> {
+    pub request: R,
+    pub started: Instant,
+    /// Only `None` while the request is in progress,
+    /// or if it was dropped before finishing
+    pub finished: Option<(Instant, Outcome)>,
+}
+
+/// A service recording every request passed through it, and their outcome.
+///
+/// Timestamps come from Tokio, so they follow `tokio::time::pause`.
+pub struct SpyService<R, T: Service<R>> {
+    inner: T,
+    calls: Mutex<Vec<Call<R>>>,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R: Clone, T: Service<R>> SpyService<R, T> {
+    pub fn new(service: T) -> Self {
+        SpyService {
+            inner: service,
+            calls: Mutex::new(Vec::new()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Every call recorded so far, in the order they were made.
+    pub fn calls(&self) -> Vec<Call<R>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Every request received so far, in the order they were received.
+    pub fn requests(&self) -> Vec<R> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|call| call.request.clone())
+            .collect()
+    }
+
+    pub fn call_count(&self) -> usize {
+        self.calls.lock().unwrap().len()
+    }
+}
+
+impl<R: Clone, T: Service<R>> Service<R> for SpyService<R, T> {
+    type Response = T::Response;
+    type Error = T::Error;
+
+    async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
+        let index = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(Call {
+                request: msg.clone(),
+                started: Instant::now(),
+                finished: None,
+            });
+            calls.len() - 1
+        };
+
+        let res = self.inner.request(msg).await;
+
+        let outcome = match &res {
+            Ok(_) => Outcome::Ok,
+            Err(e) => Outcome::Err(e.to_string()),
+        };
+        self.calls.lock().unwrap()[index].finished = Some((Instant::now(), outcome));
+
+        res
+    }
+}
+
+impl<R: Clone, T: Service<R>> Middleware<R, T> for SpyService<R, T> {
+    fn inner_service(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use thiserror::Error;
+
+    use crate::testing::{ScriptedService, Step};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Error)]
+    pub enum FakeError {
+        #[error("fake error")]
+        Error,
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spy_test() {
+        let service = SpyService::new(ScriptedService::new([
+            Step::Err(FakeError::Error),
+            Step::DelayThenOk(Duration::from_secs(1), ()),
+        ]));
+
+        assert!(service.request("first").await.is_err());
+        assert!(service.request("second").await.is_ok());
+
+        assert_eq!(service.call_count(), 2);
+        assert_eq!(service.requests(), vec!["first", "second"]);
+
+        let calls = service.calls();
+        assert_eq!(
+            calls[0].finished.as_ref().unwrap().1,
+            Outcome::Err("fake error".to_string())
+        );
+
+        let (finished, outcome) = calls[1].finished.clone().unwrap();
+        assert_eq!(outcome, Outcome::Ok);
+        assert_eq!(finished - calls[1].started, Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,73 @@
+//! Timer primitives used by the time-dependent middlewares.
+//!
+//! On native targets this is a thin re-export of Tokio's timer. Tokio's time
+//! driver isn't available on `wasm32-unknown-unknown`, so with the `wasm`
+//! feature the browser timers from `gloo-timers` are used instead.
+
+#[cfg(feature = "timeout")]
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+};
+
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+pub(crate) use tokio::time::sleep;
+#[cfg(all(
+    feature = "timeout",
+    not(all(target_arch = "wasm32", feature = "wasm"))
+))]
+pub(crate) use tokio::time::timeout;
+
+#[cfg(all(feature = "retry_wait", target_arch = "wasm32", feature = "wasm"))]
+pub(crate) use wasm::sleep;
+#[cfg(all(feature = "timeout", target_arch = "wasm32", feature = "wasm"))]
+pub(crate) use wasm::timeout;
+
+#[cfg(feature = "timeout")]
+#[derive(Debug)]
+pub(crate) struct Elapsed;
+
+/// Resolves to the output of `future`, unless `delay` finishes first.
+#[cfg(feature = "timeout")]
+pub(crate) async fn race<F: Future>(
+    future: F,
+    delay: impl Future<Output = ()>,
+) -> Result<F::Output, Elapsed> {
+    let mut future = pin!(future);
+    let mut delay = pin!(delay);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        delay.as_mut().poll(cx).map(|()| Err(Elapsed))
+    })
+    .await
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm {
+    #[cfg(feature = "timeout")]
+    use std::future::Future;
+    use std::time::Duration;
+
+    use gloo_timers::future::TimeoutFuture;
+
+    #[cfg(feature = "timeout")]
+    use super::{race, Elapsed};
+
+    pub(crate) fn sleep(duration: Duration) -> TimeoutFuture {
+        // Browser timers take milliseconds as an u32
+        let millis = u32::try_from(duration.as_millis()).unwrap_or(u32::MAX);
+        TimeoutFuture::new(millis)
+    }
+
+    #[cfg(feature = "timeout")]
+    pub(crate) async fn timeout<F: Future>(
+        duration: Duration,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        race(future, sleep(duration)).await
+    }
+}
@@ -1,44 +1,175 @@
+use crate::{
+    classify::{Class, Classify},
+    clock::{Clock, TimeSource},
+    describe::{Describe, FindLayer, Stack},
+    error::{FromTimeout, IntoFlat},
+    events::{self, StackEvent},
+    layer::Layer,
+    Middleware, Service,
+};
 use core::error::Error;
-use std::{marker::PhantomData, time::Duration};
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc, time::Duration};
 use thiserror::Error;
-use tokio::time::timeout;
-
-use crate::{Middleware, Service};
 
 /// A service that returns an Error if the
 /// time of the request exceeds the given timeout duration
 pub struct Timeout<R, T: Service<R>> {
     inner: T,
-    timeout_duration: Duration,
-    phantom: PhantomData<R>,
+    timeout_duration: TimeoutDuration<R>,
+    time_source: TimeSource,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+enum TimeoutDuration<R> {
+    Fixed(Duration),
+    PerRequest(Arc<dyn Fn(&R) -> Duration + Send + Sync>),
 }
 
-#[derive(Debug, PartialEq, Error)]
+/// [`TimeoutError::TimeoutError`] carries no data and never allocates.
+#[derive(Debug, Clone, PartialEq, Error)]
 pub enum TimeoutError<E: Error> {
-    #[error("{0}")]
-    ServiceError(E),
+    #[error("inner service failed")]
+    ServiceError(#[source] E),
     #[error("request timed out")]
     TimeoutError,
 }
 
+impl<E: Error> TimeoutError<E> {
+    #[inline]
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, TimeoutError::TimeoutError)
+    }
+
+    #[inline]
+    pub fn into_service_error(self) -> Option<E> {
+        match self {
+            TimeoutError::ServiceError(e) => Some(e),
+            TimeoutError::TimeoutError => None,
+        }
+    }
+}
+
+impl<E: Error + IntoFlat<F>, F: FromTimeout> IntoFlat<F> for TimeoutError<E> {
+    fn into_flat(self) -> F {
+        match self {
+            TimeoutError::ServiceError(e) => e.into_flat(),
+            TimeoutError::TimeoutError => F::timed_out(),
+        }
+    }
+}
+
+impl<E: Error + Classify> Classify for TimeoutError<E> {
+    fn classify(&self) -> Class {
+        match self {
+            TimeoutError::ServiceError(e) => e.classify(),
+            TimeoutError::TimeoutError => Class::Transient,
+        }
+    }
+}
+
 impl<R, T: Service<R>> Timeout<R, T> {
     pub fn new(service: T, timeout_duration: Duration) -> Self {
         Timeout {
             inner: service,
+            timeout_duration: TimeoutDuration::Fixed(timeout_duration),
+            time_source: TimeSource::System,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Gives each request its own timeout, e.g. longer for bigger payloads,
+    /// so requests of different shapes can still share the same stack.
+    pub fn per_request(
+        service: T,
+        timeout_duration: impl Fn(&R) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        Timeout {
+            inner: service,
+            timeout_duration: TimeoutDuration::PerRequest(Arc::new(timeout_duration)),
+            time_source: TimeSource::System,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The timeout of `msg`, unless the current request overrides it, cut
+    /// short by the deadline of the current request if any.
+    fn budget(&self, msg: &R) -> Duration {
+        let meta = crate::meta::current();
+        let overridden = meta.as_ref().and_then(|meta| meta.overrides.timeout);
+        let timeout_duration = overridden.unwrap_or_else(|| match &self.timeout_duration {
+            TimeoutDuration::Fixed(duration) => *duration,
+            TimeoutDuration::PerRequest(duration) => duration(msg),
+        });
+
+        match crate::meta::remaining() {
+            Some(remaining) => remaining.min(timeout_duration),
+            None => timeout_duration,
+        }
+    }
+
+    /// Measures the timeout with the given clock instead of the system timers.
+    pub fn with_clock(service: T, timeout_duration: Duration, clock: Arc<dyn Clock>) -> Self {
+        Timeout {
+            inner: service,
+            timeout_duration: TimeoutDuration::Fixed(timeout_duration),
+            time_source: TimeSource::Clock(clock),
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// [`Layer`] making a [`Timeout`] with a fixed duration.
+pub struct TimeoutLayer<R> {
+    timeout_duration: Duration,
+    phantom: PhantomData<fn(R) -> R>,
+}
+
+impl<R> TimeoutLayer<R> {
+    pub fn new(timeout_duration: Duration) -> Self {
+        TimeoutLayer {
             timeout_duration,
             phantom: PhantomData,
         }
     }
 }
 
+impl<R, S: Service<R>> Layer<S> for TimeoutLayer<R> {
+    type Service = Timeout<R, S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Timeout::new(inner, self.timeout_duration)
+    }
+}
+
 impl<R, T: Service<R>> Service<R> for Timeout<R, T> {
     type Response = T::Response;
     type Error = TimeoutError<T::Error>;
     async fn request(&self, msg: R) -> Result<Self::Response, Self::Error> {
-        match timeout(self.timeout_duration, self.inner.request(msg)).await {
-            Ok(res) => res.map_err(|e| TimeoutError::ServiceError(e)),
-            Err(_) => Err(TimeoutError::TimeoutError),
-        }
+        #[cfg(feature = "metrics")]
+        let started = (!cfg!(target_arch = "wasm32")).then(std::time::Instant::now);
+
+        let budget = self.budget(&msg);
+        let inner = async {
+            #[cfg(feature = "metrics")]
+            let _phase = crate::metrics::Phase::start("timeout", "inner");
+            self.inner.request(msg).await
+        };
+
+        let res = match self.time_source.timeout(budget, inner).await {
+            Ok(res) => res.map_err(TimeoutError::ServiceError),
+            Err(_) => {
+                events::emit(StackEvent::TimedOut { after: budget });
+                Err(TimeoutError::TimeoutError)
+            }
+        };
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_request("timeout", started, &res, |e| match e {
+            TimeoutError::ServiceError(_) => "service",
+            TimeoutError::TimeoutError => "timeout",
+        });
+
+        res
     }
 }
 
@@ -48,14 +179,41 @@ impl<R, T: Service<R>> Middleware<R, T> for Timeout<R, T> {
     }
 }
 
+impl<R, T: Service<R> + Describe> Describe for Timeout<R, T> {
+    fn name(&self) -> &'static str {
+        "timeout"
+    }
+
+    fn params(&self) -> BTreeMap<&'static str, String> {
+        let duration_ms = match &self.timeout_duration {
+            TimeoutDuration::Fixed(duration) => duration.as_millis().to_string(),
+            TimeoutDuration::PerRequest(_) => "per_request".to_string(),
+        };
+        BTreeMap::from([("duration_ms", duration_ms)])
+    }
+
+    fn describe(&self) -> Stack {
+        Stack::on_top_of(self.layer(), self.inner.describe())
+    }
+}
+
+impl<R: 'static, T: Service<R> + FindLayer> FindLayer for Timeout<R, T> {
+    fn inner_layer(&self) -> Option<&dyn FindLayer> {
+        Some(&self.inner)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fmt::Debug;
     use std::ops::Mul;
 
-    use tokio::time::sleep;
+    use tokio::{join, time::sleep};
+
+    use crate::clock::ManualClock;
 
     use super::*;
+    use crate::meta::{Meta, MetaService, WithMeta};
 
     #[derive(Debug, PartialEq)]
     pub struct TestTimeoutService {}
@@ -110,5 +268,104 @@ mod tests {
             service_timeout.request(20).await.unwrap_err(),
             TimeoutError::TimeoutError
         );
+
+        assert!(service_timeout.request(20).await.unwrap_err().is_timeout());
+        assert_eq!(
+            service_timeout
+                .request(14)
+                .await
+                .unwrap_err()
+                .into_service_error(),
+            Some(FakeError::Error)
+        );
+    }
+
+    #[derive(Debug)]
+    pub struct TestClockTimeoutService {
+        clock: Arc<ManualClock>,
+    }
+
+    impl Service<u64> for TestClockTimeoutService {
+        type Response = u64;
+        type Error = FakeError;
+
+        async fn request(&self, msg: u64) -> Result<Self::Response, Self::Error> {
+            self.clock.sleep(Duration::from_millis(msg)).await;
+            Ok(msg)
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_clock_test() {
+        let clock = Arc::new(ManualClock::new());
+        let service = TestClockTimeoutService {
+            clock: clock.clone(),
+        };
+        let service_timeout =
+            Timeout::with_clock(service, Duration::from_millis(15), clock.clone());
+
+        let advance = || async {
+            for _ in 0..20 {
+                tokio::task::yield_now().await;
+                clock.advance(Duration::from_millis(1));
+            }
+        };
+
+        let (res, ()) = join!(service_timeout.request(10), advance());
+        assert_eq!(res, Ok(10));
+
+        let (res, ()) = join!(service_timeout.request(20), advance());
+        assert_eq!(res, Err(TimeoutError::TimeoutError));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_deadline_test() {
+        let service = MetaService::new(Timeout::new(
+            TestTimeoutService {},
+            Duration::from_millis(15),
+        ));
+        let deadline = |ms| {
+            let deadline = tokio::time::Instant::now() + Duration::from_millis(ms);
+            Meta::default().with_deadline(deadline.into_std())
+        };
+
+        // The deadline is closer than the timeout
+        assert_eq!(
+            service.request(WithMeta::new(10, deadline(5))).await,
+            Err(TimeoutError::TimeoutError)
+        );
+        // The timeout is closer than the deadline
+        assert_eq!(
+            service.request(WithMeta::new(20, deadline(100))).await,
+            Err(TimeoutError::TimeoutError)
+        );
+        assert_eq!(
+            service.request(WithMeta::new(10, deadline(100))).await,
+            Ok(20)
+        );
+
+        // Overridden for this request only
+        let meta = Meta::default().with_timeout(Duration::from_millis(30));
+        assert_eq!(service.request(WithMeta::new(20, meta)).await, Ok(40));
+        let meta = deadline(10).with_timeout(Duration::from_millis(30));
+        assert_eq!(
+            service.request(WithMeta::new(20, meta)).await,
+            Err(TimeoutError::TimeoutError)
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_per_request_test() {
+        // Requests asking for a longer wait get a longer timeout
+        let service_timeout = Timeout::per_request(TestTimeoutService {}, |msg: &u64| {
+            Duration::from_millis(msg + 5)
+        });
+
+        assert_eq!(service_timeout.request(10).await, Ok(20));
+        assert_eq!(service_timeout.request(20).await, Ok(40));
+        assert_eq!(
+            service_timeout.request(14).await,
+            Err(TimeoutError::ServiceError(FakeError::Error))
+        );
     }
 }